@@ -0,0 +1,72 @@
+#!/usr/bin/env bash
+
+# Compares the latencies of civkitd built from two revisions, measured by the
+# civkit-bench of the current tree, and appends them to doc/BENCH_RESULTS.md.
+#
+# Usage: ./bench_compare.sh <before-revision> [after-revision]
+
+set -o errexit
+
+if [ -z "$1" ]; then
+    echo "Usage: $0 <before-revision> [after-revision]"
+    exit 1
+fi
+
+BEFORE_REV=$1
+AFTER_REV=${2:-HEAD}
+ROUNDS=${ROUNDS:-50}
+PORT=${PORT:-50021}
+
+ROOT=$(git rev-parse --show-toplevel)
+WORK_DIR=$(mktemp -d)
+RESULTS="$ROOT/doc/BENCH_RESULTS.md"
+
+cargo build --release --bin civkit-bench --manifest-path "$ROOT/Cargo.toml"
+BENCH="$ROOT/target/release/civkit-bench"
+
+if [ ! -f "$RESULTS" ]; then
+    echo "Relay latency measures" > "$RESULTS"
+    echo "======================" >> "$RESULTS"
+fi
+
+{
+    echo
+    echo "## $(date -u +%Y-%m-%d) - $(uname -srm), $(grep -m1 'model name' /proc/cpuinfo | cut -d: -f2 | xargs)"
+    echo
+    echo "\`civkit-bench --rounds $ROUNDS\` built from $(git -C "$ROOT" rev-parse --short HEAD)."
+} >> "$RESULTS"
+
+run_revision() {
+    local name=$1
+    local rev=$2
+    local tree="$WORK_DIR/$name"
+    # Each relay runs on a fresh data directory, the older ones keep their
+    # database in the working directory.
+    local home="$WORK_DIR/home-$name"
+
+    git -C "$ROOT" worktree add --detach "$tree" "$rev" > /dev/null
+    cargo build --release --bin civkitd --manifest-path "$tree/Cargo.toml"
+    mkdir -p "$home"
+    (cd "$home" && HOME="$home" exec "$tree/target/release/civkitd" --nostr-port "$PORT" > civkitd.log 2>&1) &
+    local pid=$!
+    sleep 5
+
+    {
+        echo
+        echo "### $name: civkitd $(git -C "$ROOT" rev-parse --short "$rev")"
+        echo
+        echo '```'
+    } >> "$RESULTS"
+    "$BENCH" --port "$PORT" --rounds "$ROUNDS" | tee -a "$RESULTS"
+    echo '```' >> "$RESULTS"
+
+    kill "$pid"
+    wait "$pid" || true
+    git -C "$ROOT" worktree remove --force "$tree"
+}
+
+run_revision before "$BEFORE_REV"
+run_revision after "$AFTER_REV"
+
+rm -rf "$WORK_DIR"
+echo "Results appended to $RESULTS"
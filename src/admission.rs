@@ -0,0 +1,220 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! The admission policy of the published events, by kind.
+//!
+//! The policy merges the `[admission]` table with the proof-of-work and
+//! authentication settings of `[spam_protection]`, it is the only place they
+//! are enforced and advertised in the relay information document.
+//!
+//! The proof-of-work and the authentication are checked by the ClientHandler
+//! on reception, a proof-of-work is accepted in place of the credentials of
+//! the kinds paid with them. The events requiring credentials are staged by the
+//! NoteProcessor until a service deliverance referencing their id is
+//! validated by the CredentialGateway. The credentials are only spent once
+//! they pay for the event, in the same write as the event, and the payer is
+//! answered the outcome of this write.
+
+use crate::config::{AdmissionRequirement, Config};
+use crate::pow::check_difficulty;
+use crate::rejection::RejectionReason;
+use crate::util::is_credential;
+
+use nostr::Event;
+
+use serde_json::{json, Map, Value};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The kind of the market orders.
+const ORDER_KIND: u32 = 32500;
+
+/// The credentials to redeem for an event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CredentialRequirement {
+	pub service_id: u64,
+	pub credentials: u32,
+}
+
+/// What a client must provide to publish an event of a kind.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KindRequirement {
+	/// A NIP-42 authentication.
+	pub auth: bool,
+	/// The NIP-13 proof-of-work difficulty, 0 for none. If the kind is paid
+	/// with credentials, it is accepted in place of them.
+	pub pow_difficulty: u8,
+	/// The credentials paying for the event, none if the kind is free.
+	pub credentials: Option<CredentialRequirement>,
+}
+
+impl From<&AdmissionRequirement> for KindRequirement {
+	fn from(requirement: &AdmissionRequirement) -> Self {
+		match requirement {
+			AdmissionRequirement::Free => KindRequirement::default(),
+			AdmissionRequirement::Pow { difficulty } => KindRequirement { pow_difficulty: *difficulty, ..Default::default() },
+			AdmissionRequirement::Credentials { service_id, credentials } => {
+				KindRequirement { credentials: Some(CredentialRequirement { service_id: *service_id, credentials: *credentials }), ..Default::default() }
+			},
+			AdmissionRequirement::Auth => KindRequirement { auth: true, ..Default::default() },
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdmissionPolicy {
+	default: KindRequirement,
+	kinds: HashMap<u32, KindRequirement>,
+	/// A NIP-42 authentication is required to publish the credential events.
+	credential_auth: bool,
+}
+
+impl AdmissionPolicy {
+	pub fn new(config: &Config) -> Self {
+		let admission = &config.admission;
+		let spam_protection = &config.spam_protection;
+
+		let mut admission_kinds = HashMap::new();
+		for (kind, requirement) in admission.kinds.iter() {
+			match kind.parse::<u32>() {
+				Ok(kind) => { admission_kinds.insert(kind, KindRequirement::from(requirement)); },
+				Err(_) => println!("[CIVKITD] - NOSTR: Ignoring admission requirement of invalid kind {}", kind),
+			}
+		}
+		let mut pow_difficulty_per_kind = HashMap::new();
+		for (kind, difficulty) in spam_protection.pow_difficulty_per_kind.iter() {
+			match kind.parse::<u32>() {
+				Ok(kind) => { pow_difficulty_per_kind.insert(kind, *difficulty); },
+				Err(_) => println!("[CIVKITD] - NOSTR: Ignoring proof-of-work difficulty of invalid kind {}", kind),
+			}
+		}
+
+		let merge = |mut requirement: KindRequirement, kind: Option<u32>| {
+			let pow_difficulty = kind.and_then(|kind| pow_difficulty_per_kind.get(&kind)).cloned().unwrap_or(spam_protection.min_pow_difficulty);
+			requirement.pow_difficulty = requirement.pow_difficulty.max(pow_difficulty);
+			requirement.auth |= spam_protection.requireauthorders && kind == Some(ORDER_KIND);
+			// The credentials are only requested if enabled.
+			if !spam_protection.requestcredentials {
+				requirement.credentials = None;
+			}
+			requirement
+		};
+
+		let default = KindRequirement::from(&admission.default);
+		let mut kinds_set: HashSet<u32> = admission_kinds.keys().chain(pow_difficulty_per_kind.keys()).cloned().collect();
+		if spam_protection.requireauthorders {
+			kinds_set.insert(ORDER_KIND);
+		}
+		let kinds = kinds_set.into_iter().map(|kind| {
+			let requirement = admission_kinds.get(&kind).cloned().unwrap_or_else(|| default.clone());
+			(kind, merge(requirement, Some(kind)))
+		}).collect();
+
+		AdmissionPolicy {
+			default: merge(default, None),
+			kinds,
+			credential_auth: spam_protection.requireauthcredentials,
+		}
+	}
+
+	pub fn requirement(&self, kind: u32) -> &KindRequirement {
+		self.kinds.get(&kind).unwrap_or(&self.default)
+	}
+
+	/// Whether the event must be paid with credentials. The proof-of-work of a
+	/// kind paid with credentials is a cheaper alternative to them.
+	pub fn requires_credentials(&self, event: &Event) -> bool {
+		let requirement = self.requirement(event.kind.as_u32());
+		requirement.credentials.is_some() && !(requirement.pow_difficulty > 0 && check_difficulty(event, requirement.pow_difficulty).is_ok())
+	}
+
+	/// Checks the requirements met on reception of the event, the credentials
+	/// are checked when redeemed.
+	pub fn check_event(&self, event: &Event, authenticated: bool) -> Result<(), RejectionReason> {
+		if is_credential(event) {
+			if self.credential_auth && !authenticated {
+				return Err(RejectionReason::AuthRequired("authentication required to publish credentials".to_string()));
+			}
+			return Ok(());
+		}
+		let requirement = self.requirement(event.kind.as_u32());
+		if requirement.auth && !authenticated {
+			return Err(RejectionReason::AuthRequired(format!("authentication required to publish kind {}", event.kind.as_u32())));
+		}
+		// Without the proof-of-work, the event must be paid with credentials.
+		if requirement.credentials.is_some() {
+			return Ok(());
+		}
+		check_difficulty(event, requirement.pow_difficulty)
+	}
+
+	/// Checks the credentials redeemed for the event pay for its kind.
+	pub fn check_payment(&self, event: &Event, service_id: u64, credentials: u32) -> Result<(), RejectionReason> {
+		match &self.requirement(event.kind.as_u32()).credentials {
+			Some(required) => {
+				if service_id != required.service_id {
+					return Err(RejectionReason::Restricted(format!("credentials of service {} required, not {}", required.service_id, service_id)));
+				}
+				if credentials < required.credentials {
+					return Err(RejectionReason::Restricted(format!("{} credentials required, {} redeemed", required.credentials, credentials)));
+				}
+				Ok(())
+			},
+			None => Ok(()),
+		}
+	}
+
+	fn requirements(&self) -> impl Iterator<Item = &KindRequirement> {
+		self.kinds.values().chain(std::iter::once(&self.default))
+	}
+
+	/// Whether some kind requires credentials of the service, the other
+	/// service deliverances are refused.
+	pub fn accepts_service(&self, service_id: u64) -> bool {
+		self.requirements().any(|requirement| requirement.credentials.as_ref().map_or(false, |required| required.service_id == service_id))
+	}
+
+	/// Whether some kind requires credentials, announced as `payment_required`
+	/// in the relay information document.
+	pub fn requires_payment(&self) -> bool {
+		self.requirements().any(|requirement| requirement.credentials.is_some())
+	}
+
+	/// Adds the requirements to the `limitation` of the relay information
+	/// document. The kinds whose requirement differs from the default one are
+	/// listed by kind.
+	pub fn advertise(&self, limitation: &mut Map<String, Value>) {
+		let restricted_writes = self.credential_auth || self.requirements().any(|requirement| *requirement != KindRequirement::default());
+		limitation.insert("payment_required".to_string(), json!(self.requires_payment()));
+		limitation.insert("restricted_writes".to_string(), json!(restricted_writes));
+		limitation.insert("min_pow_difficulty".to_string(), json!(self.default.pow_difficulty));
+
+		let pow_difficulty_per_kind: BTreeMap<String, u8> = self.kinds.iter()
+			.filter(|(_, requirement)| requirement.pow_difficulty != self.default.pow_difficulty)
+			.map(|(kind, requirement)| (kind.to_string(), requirement.pow_difficulty))
+			.collect();
+		if !pow_difficulty_per_kind.is_empty() {
+			limitation.insert("pow_difficulty_per_kind".to_string(), json!(pow_difficulty_per_kind));
+		}
+
+		let auth_required_per_kind: BTreeMap<String, bool> = self.kinds.iter()
+			.filter(|(_, requirement)| requirement.auth != self.default.auth)
+			.map(|(kind, requirement)| (kind.to_string(), requirement.auth))
+			.collect();
+		if self.default.auth {
+			limitation.insert("publication_auth_required".to_string(), json!(true));
+		}
+		if !auth_required_per_kind.is_empty() {
+			limitation.insert("auth_required_per_kind".to_string(), json!(auth_required_per_kind));
+		}
+		if self.credential_auth {
+			limitation.insert("credential_auth_required".to_string(), json!(true));
+		}
+	}
+}
@@ -0,0 +1,84 @@
+use crate::admission::{AdmissionPolicy, CredentialRequirement, KindRequirement};
+use crate::config::{Admission, AdmissionRequirement, Config};
+use crate::rejection::RejectionReason;
+
+use nostr::{EventBuilder, Keys, Kind};
+
+fn admission_policy() -> AdmissionPolicy {
+	let mut config = Config::default();
+	config.admission.kinds.insert("32500".to_string(), AdmissionRequirement::Credentials { service_id: 100, credentials: 2 });
+	config.admission.kinds.insert("4".to_string(), AdmissionRequirement::Auth);
+	config.admission.kinds.insert("1".to_string(), AdmissionRequirement::Pow { difficulty: 255 });
+	config.admission.kinds.insert("orders".to_string(), AdmissionRequirement::Free);
+	AdmissionPolicy::new(&config)
+}
+
+#[test]
+fn test_admission_config() {
+	let config: Config = toml::from_str(&std::fs::read_to_string(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("example-config.toml")).unwrap()).unwrap();
+	assert_eq!(config.admission.default, AdmissionRequirement::Free);
+	assert_eq!(config.admission.kinds.get("32500"), Some(&AdmissionRequirement::Credentials { service_id: 0, credentials: 1 }));
+	assert_eq!(config.admission, Config::default().admission);
+}
+
+#[test]
+fn test_admission_requirements() {
+	let policy = admission_policy();
+	let keys = Keys::generate();
+
+	let metadata = EventBuilder::new(Kind::from(0), "{}", &[]).to_event(&keys).unwrap();
+	assert_eq!(policy.requirement(0), &KindRequirement::default());
+	assert_eq!(policy.check_event(&metadata, false), Ok(()));
+
+	let direct_message = EventBuilder::new(Kind::from(4), "hello", &[]).to_event(&keys).unwrap();
+	assert!(matches!(policy.check_event(&direct_message, false), Err(RejectionReason::AuthRequired(_))));
+	assert_eq!(policy.check_event(&direct_message, true), Ok(()));
+
+	let note = EventBuilder::new(Kind::from(1), "note", &[]).to_event(&keys).unwrap();
+	assert!(matches!(policy.check_event(&note, true), Err(RejectionReason::Pow(_))));
+
+	// The credentials are checked on redemption.
+	let order = EventBuilder::new(Kind::from(32500), "order", &[]).to_event(&keys).unwrap();
+	assert!(policy.requires_credentials(&order));
+	assert!(!policy.requires_credentials(&note));
+	assert_eq!(policy.check_event(&order, false), Ok(()));
+}
+
+#[test]
+fn test_admission_payments() {
+	let policy = admission_policy();
+	let order = EventBuilder::new(Kind::from(32500), "order", &[]).to_event(&Keys::generate()).unwrap();
+
+	assert_eq!(policy.check_payment(&order, 100, 2), Ok(()));
+	assert_eq!(policy.check_payment(&order, 100, 3), Ok(()));
+	assert!(matches!(policy.check_payment(&order, 100, 1), Err(RejectionReason::Restricted(_))));
+	assert!(matches!(policy.check_payment(&order, 0, 2), Err(RejectionReason::Restricted(_))));
+
+	assert!(policy.accepts_service(100));
+	assert!(!policy.accepts_service(0));
+	assert!(policy.requires_payment());
+	let mut config = Config::default();
+	config.admission = Admission { default: AdmissionRequirement::Free, kinds: Default::default() };
+	assert!(!AdmissionPolicy::new(&config).requires_payment());
+}
+
+#[test]
+fn test_spam_protection_folded_in_admission() {
+	let mut config = Config::default();
+	config.admission.kinds.insert("1".to_string(), AdmissionRequirement::Pow { difficulty: 4 });
+	config.spam_protection.min_pow_difficulty = 2;
+	config.spam_protection.pow_difficulty_per_kind.insert("1".to_string(), 8);
+	config.spam_protection.requireauthorders = true;
+	let policy = AdmissionPolicy::new(&config);
+
+	// The strongest difficulty applies.
+	assert_eq!(policy.requirement(1).pow_difficulty, 8);
+	assert_eq!(policy.requirement(0).pow_difficulty, 2);
+	assert_eq!(policy.requirement(32500), &KindRequirement { auth: true, pow_difficulty: 2, credentials: Some(CredentialRequirement { service_id: 0, credentials: 1 }) });
+
+	// Without credentials requested, no kind is paid.
+	config.spam_protection.requestcredentials = false;
+	let policy = AdmissionPolicy::new(&config);
+	assert_eq!(policy.requirement(32500).credentials, None);
+	assert!(!policy.requires_payment());
+}
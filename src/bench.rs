@@ -0,0 +1,170 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! A latency benchmark against a running civkitd.
+//!
+//! It measures the websocket connection setup, the round trip of a REQ until
+//! its EOSE, and the delay between an EVENT published by one client and its
+//! reception by a subscribed client.
+//!
+//! The first two only rely on behaviors of every civkitd revision, so they
+//! can compare a relay before and after a change. The relays before the
+//! admission policy withhold the text notes until a credential is validated,
+//! the publication benchmark then times out against them.
+
+use clap::Parser;
+
+use futures_util::{SinkExt, StreamExt};
+
+use nostr::{ClientMessage, EventBuilder, Filter, Keys, Kind, RelayMessage, SubscriptionId, Tag};
+
+use serde_json::json;
+
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration, Instant};
+
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Parser, Debug)]
+#[clap(name = "civkit-bench")]
+#[clap(about = "Measure civkitd end-to-end latencies", long_about = None)]
+struct Cli {
+	/// The nostr port of the civkitd node
+	#[clap(short, long, default_value = "50021")]
+	port: String,
+
+	/// Number of measures for each benchmark
+	#[clap(short, long, default_value = "50")]
+	rounds: u32,
+
+	/// Max wait in milliseconds for an expected message
+	#[clap(short, long, default_value = "10000")]
+	timeout_ms: u64,
+
+	/// Min delay in milliseconds between two REQs of a client, the default
+	/// stays under the default limit of 60 REQs per minute
+	#[clap(short, long, default_value = "1100")]
+	interval_ms: u64,
+}
+
+async fn try_connect(port: &str) -> Option<WsStream> {
+	let url = url::Url::parse(&format!("ws://[::1]:{}", port)).unwrap();
+	connect_async(url).await.ok().map(|(ws_stream, _)| ws_stream)
+}
+
+async fn connect(port: &str) -> WsStream {
+	try_connect(port).await.expect("WebSocket connection failed !")
+}
+
+async fn send_client_message(ws_stream: &mut WsStream, client_message: ClientMessage) {
+	ws_stream.send(Message::Text(client_message.as_json())).await.expect("Failed to send client message");
+}
+
+/// Waits for the first relay message accepted by `is_expected`.
+async fn wait_for<F: Fn(&RelayMessage) -> bool>(ws_stream: &mut WsStream, is_expected: F) {
+	while let Some(Ok(message)) = ws_stream.next().await {
+		let msg_json = match message {
+			Message::Text(msg) => msg,
+			Message::Binary(msg) => String::from_utf8(msg).unwrap_or_default(),
+			_ => continue,
+		};
+		if let Ok(relay_message) = RelayMessage::from_json(msg_json) {
+			if is_expected(&relay_message) { return; }
+		}
+	}
+}
+
+fn build_filter(value: serde_json::Value) -> Filter {
+	serde_json::from_value(value).unwrap()
+}
+
+fn print_summary(name: &str, mut latencies: Vec<Duration>, rounds: u32) {
+	if latencies.is_empty() {
+		println!("{}: no measure in {} rounds (timeout)", name, rounds);
+		return;
+	}
+	latencies.sort();
+	let median = latencies[latencies.len() / 2];
+	let total: Duration = latencies.iter().sum();
+	println!("{}: {} / {} rounds - min {:?} median {:?} mean {:?} max {:?}", name, latencies.len(), rounds, latencies[0], median, total / latencies.len() as u32, latencies[latencies.len() - 1]);
+}
+
+async fn bench_connect(cli: &Cli) {
+	let mut latencies = Vec::new();
+	for _ in 0..cli.rounds {
+		let start = Instant::now();
+		// A connection refused, e.g by the per address limit, is not counted.
+		if let Some(mut client) = try_connect(&cli.port).await {
+			latencies.push(start.elapsed());
+			let _ = client.close(None).await;
+		}
+	}
+	print_summary("WebSocket connection", latencies, cli.rounds);
+}
+
+async fn bench_req_to_eose(cli: &Cli) {
+	let mut client = connect(&cli.port).await;
+	let mut latencies = Vec::new();
+
+	for round in 0..cli.rounds {
+		let sub_id = SubscriptionId::new(format!("bench-eose-{}", round));
+		let filter = build_filter(json!({ "kinds": [1], "limit": 1 }));
+		let start = Instant::now();
+		send_client_message(&mut client, ClientMessage::new_req(sub_id.clone(), vec![filter])).await;
+		// A single REQ is pending, the relays before the subscription id echo
+		// answer it with a random one.
+		let wait = wait_for(&mut client, |msg| matches!(msg, RelayMessage::EndOfStoredEvents(_)));
+		if timeout(Duration::from_millis(cli.timeout_ms), wait).await.is_ok() {
+			latencies.push(start.elapsed());
+		}
+		send_client_message(&mut client, ClientMessage::close(sub_id)).await;
+		// Stay under the REQ rate limit, the pause is not measured.
+		tokio::time::sleep_until(start + Duration::from_millis(cli.interval_ms)).await;
+	}
+	print_summary("REQ to EOSE", latencies, cli.rounds);
+}
+
+async fn bench_publish_to_subscriber(cli: &Cli) {
+	let mut publisher = connect(&cli.port).await;
+	let mut subscriber = connect(&cli.port).await;
+	let keys = Keys::generate();
+	let topic = format!("civkit-bench-{}", hex::encode(&keys.public_key().serialize()[..4]));
+
+	let sub_id = SubscriptionId::new("bench-publish");
+	let filter = build_filter(json!({ "kinds": [1], "#t": [topic] }));
+	send_client_message(&mut subscriber, ClientMessage::new_req(sub_id.clone(), vec![filter])).await;
+	let wait = wait_for(&mut subscriber, |msg| matches!(msg, RelayMessage::EndOfStoredEvents(id) if *id == sub_id));
+	let _ = timeout(Duration::from_millis(cli.timeout_ms), wait).await;
+
+	let mut latencies = Vec::new();
+	for round in 0..cli.rounds {
+		let tags = vec![Tag::parse(vec!["t".to_string(), topic.clone()]).unwrap()];
+		let event = EventBuilder::new(Kind::from(1), format!("bench {}", round), &tags).to_event(&keys).unwrap();
+		let event_id = event.id;
+		let start = Instant::now();
+		send_client_message(&mut publisher, ClientMessage::new_event(event)).await;
+		let wait = wait_for(&mut subscriber, |msg| matches!(msg, RelayMessage::Event { event, .. } if event.id == event_id));
+		if timeout(Duration::from_millis(cli.timeout_ms), wait).await.is_ok() {
+			latencies.push(start.elapsed());
+		}
+	}
+	print_summary("EVENT publish to subscriber", latencies, cli.rounds);
+}
+
+#[tokio::main]
+async fn main() {
+	let cli = Cli::parse();
+
+	bench_connect(&cli).await;
+	bench_req_to_eose(&cli).await;
+	bench_publish_to_subscriber(&cli).await;
+}
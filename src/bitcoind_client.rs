@@ -18,12 +18,12 @@ use bitcoin_hashes::hex::{ToHex, FromHex};
 
 use staking_credentials::common::utils::Proof;
 
-use tokio::sync::{mpsc, oneshot};
-use tokio::sync::Mutex as TokioMutex;
+use tokio::sync::oneshot;
 
-use tokio::time::{sleep, Duration};
+use crate::bus::{BusReceiver, BusSender};
 
 use crate::inclusionproof::InclusionProof;
+use crate::shutdown::ShutdownSignal;
 use crate::verifycommitment::{verify_merkle_root_inclusion};
 
 #[derive(Debug)]
@@ -69,11 +69,11 @@ impl BitcoindClient {
 
 pub struct BitcoindHandler {
 
-	receive_bitcoind_request: TokioMutex<mpsc::UnboundedReceiver<BitcoindRequest>>,
+	receive_bitcoind_request: BusReceiver<BitcoindRequest>,
 
-	receive_bitcoind_request_gateway: TokioMutex<mpsc::UnboundedReceiver<BitcoindRequest>>,
+	receive_bitcoind_request_gateway: BusReceiver<BitcoindRequest>,
 
-	send_bitcoind_result_handler: TokioMutex<mpsc::UnboundedSender<BitcoindResult>>,
+	send_bitcoind_result_handler: BusSender<BitcoindResult>,
 
 	bitcoind_client: BitcoindClient,
 
@@ -83,7 +83,7 @@ pub struct BitcoindHandler {
 }
 
 impl BitcoindHandler {
-	pub fn new(config: Config, receive_bitcoind_requests: mpsc::UnboundedReceiver<BitcoindRequest>, receive_bitcoind_request_gateway: mpsc::UnboundedReceiver<BitcoindRequest>, send_bitcoind_result_handler: mpsc::UnboundedSender<BitcoindResult>) -> BitcoindHandler {
+	pub fn new(config: Config, receive_bitcoind_requests: BusReceiver<BitcoindRequest>, receive_bitcoind_request_gateway: BusReceiver<BitcoindRequest>, send_bitcoind_result_handler: BusSender<BitcoindResult>) -> BitcoindHandler {
 
 		let bitcoind_client = BitcoindClient {
 			host: config.bitcoind_params.host.clone(),
@@ -100,98 +100,89 @@ impl BitcoindHandler {
 		let rpc_client = Client::new(&url, user_pass).unwrap();
 
 		BitcoindHandler {
-			receive_bitcoind_request: TokioMutex::new(receive_bitcoind_requests),
-			receive_bitcoind_request_gateway: TokioMutex::new(receive_bitcoind_request_gateway),
-			send_bitcoind_result_handler: TokioMutex::new(send_bitcoind_result_handler),
+			receive_bitcoind_request: receive_bitcoind_requests,
+			receive_bitcoind_request_gateway,
+			send_bitcoind_result_handler,
 			bitcoind_client,
 			rpc_client,
 			config,
 		}
 	}
 
-	pub async fn run(&mut self) {
+	pub async fn run(&mut self, mut shutdown: ShutdownSignal) {
 		loop {
-			sleep(Duration::from_millis(1000)).await;
-
-			{
-				let mut receive_bitcoind_request_lock = self.receive_bitcoind_request.lock();
-				if let Ok(bitcoind_request) = receive_bitcoind_request_lock.await.try_recv() {
-					match bitcoind_request {
-						BitcoindRequest::CheckRpcCall => {
-							println!("[CIVKITD] - BITCOIND CLIENT: Received rpc call - Test bitcoind");
- 
-							self.rpc_client.call("getblockchaininfo", &vec![]);
-						},
-						BitcoindRequest::GenerateTxInclusionProof { txid, respond_to } => {
-							println!("[CIVKITD] - BITCOIND CLIENT: Received rpc call - Generate merkle block");
-
-							let txid_json_value = serde_json::to_value(txid).unwrap();
-							let txid_json = serde_json::Value::Array(vec![txid_json_value]);
-
-							if let Ok(response) = self.rpc_client.call("gettxoutproof", &[txid_json]) {
-								if let Some(raw_value) = response.result {
-									let mut mb_string = raw_value.get().to_string();
-									let index = mb_string.find('\"').unwrap();
-									mb_string.remove(index);
-									let index = mb_string.find('\"').unwrap();
-									mb_string.remove(index);
-									//let mb_bytes = Vec::from_hex(&mb_string).unwrap();
-									//let mb: MerkleBlock = bitcoin::consensus::deserialize(&mb_bytes).unwrap();
-									respond_to.send(Some(mb_string));
-								}
-							} else { respond_to.send(None); }
-						},
-						_ => {},
-					}
-				}
+			tokio::select! {
+				_ = shutdown.recv() => {
+					println!("[CIVKITD] - CONTROL: BitcoindHandler shutting down");
+					break;
+				},
+				Some(bitcoind_request) = self.receive_bitcoind_request.recv() => {
+					self.handle_request(bitcoind_request).await;
+				},
+				Some(bitcoind_request) = self.receive_bitcoind_request_gateway.recv() => {
+					self.handle_request(bitcoind_request).await;
+				},
+				else => { break; }
 			}
+		}
+	}
 
-			let mut validation_result = Vec::new();
-			{
-				let mut receive_bitcoind_request_gateway = self.receive_bitcoind_request_gateway.lock();
-				if let Ok(bitcoind_request) = receive_bitcoind_request_gateway.await.try_recv() {
-					match bitcoind_request {
-						BitcoindRequest::CheckMerkleProof { request_id, proof } => {
-							println!("[CIVKITD] - BITCOIND CLIENT: Received rpc call - Check merkle proof");
-
-							match proof {
-								Proof::MerkleBlock(merkle_block) => {
-									let hex_string = serialize(&merkle_block).to_hex();
-									let proof_json = serde_json::Value::String(hex_string);
-
-									//TODO: verify transaction paid the correct amount to the correct scrippubkey and deliver credential in function ?
-									if let Ok(response) = self.rpc_client.call("verifytxoutproof", &[proof_json]) {
-										println!("got an answer {:?}", response);
-										if let Some(raw_value) = response.result {
-											println!("raw value {}", raw_value);
-											let txid_array = raw_value.get();
-											if txid_array.len() > 0 {
-												println!("[CIVKITD] - BITCOIND CLIENT: Check - Valid proof");
-												validation_result.push(BitcoindResult::ProofValid { request_id, valid: true });
-											}
-										}
-									} else { println!("[CIVKITD] - No reply from bitcoind"); }
-								},
-								_ => { validation_result.push(BitcoindResult::ProofValid { request_id, valid: false }); }
-							}
-						},
-						BitcoindRequest::VerifyInclusionProof { inclusion_proof, respond_to } => {
-							println!("[CIVKITD] - BITCOIND CLIENT: Received rpc call - Verify inclusion proof");
-	
-							let res = BitcoindClient::verifytxoutproof(inclusion_proof).await;
-							respond_to.send(Some(res.to_string()));
-						}
-						_ => {},
+	async fn handle_request(&mut self, bitcoind_request: BitcoindRequest) {
+		match bitcoind_request {
+			BitcoindRequest::CheckRpcCall => {
+				println!("[CIVKITD] - BITCOIND CLIENT: Received rpc call - Test bitcoind");
+
+				self.rpc_client.call("getblockchaininfo", &vec![]);
+			},
+			BitcoindRequest::GenerateTxInclusionProof { txid, respond_to } => {
+				println!("[CIVKITD] - BITCOIND CLIENT: Received rpc call - Generate merkle block");
+
+				let txid_json_value = serde_json::to_value(txid).unwrap();
+				let txid_json = serde_json::Value::Array(vec![txid_json_value]);
+
+				if let Ok(response) = self.rpc_client.call("gettxoutproof", &[txid_json]) {
+					if let Some(raw_value) = response.result {
+						let mut mb_string = raw_value.get().to_string();
+						let index = mb_string.find('\"').unwrap();
+						mb_string.remove(index);
+						let index = mb_string.find('\"').unwrap();
+						mb_string.remove(index);
+						//let mb_bytes = Vec::from_hex(&mb_string).unwrap();
+						//let mb: MerkleBlock = bitcoin::consensus::deserialize(&mb_bytes).unwrap();
+						respond_to.send(Some(mb_string));
 					}
+				} else { respond_to.send(None); }
+			},
+			BitcoindRequest::CheckMerkleProof { request_id, proof } => {
+				println!("[CIVKITD] - BITCOIND CLIENT: Received rpc call - Check merkle proof");
+
+				match proof {
+					Proof::MerkleBlock(merkle_block) => {
+						let hex_string = serialize(&merkle_block).to_hex();
+						let proof_json = serde_json::Value::String(hex_string);
+
+						//TODO: verify transaction paid the correct amount to the correct scrippubkey and deliver credential in function ?
+						if let Ok(response) = self.rpc_client.call("verifytxoutproof", &[proof_json]) {
+							println!("got an answer {:?}", response);
+							if let Some(raw_value) = response.result {
+								println!("raw value {}", raw_value);
+								let txid_array = raw_value.get();
+								if txid_array.len() > 0 {
+									println!("[CIVKITD] - BITCOIND CLIENT: Check - Valid proof");
+									let _ = self.send_bitcoind_result_handler.send(BitcoindResult::ProofValid { request_id, valid: true }).await;
+								}
+							}
+						} else { println!("[CIVKITD] - No reply from bitcoind"); }
+					},
+					_ => { let _ = self.send_bitcoind_result_handler.send(BitcoindResult::ProofValid { request_id, valid: false }).await; }
 				}
-			}
+			},
+			BitcoindRequest::VerifyInclusionProof { inclusion_proof, respond_to } => {
+				println!("[CIVKITD] - BITCOIND CLIENT: Received rpc call - Verify inclusion proof");
 
-			{
-				for result in validation_result {
-					let mut send_bitcoind_result_handler_lock = self.send_bitcoind_result_handler.lock();
-					send_bitcoind_result_handler_lock.await.send(result);
-				}
-			}
+				let res = BitcoindClient::verifytxoutproof(inclusion_proof).await;
+				respond_to.send(Some(res.to_string()));
+			},
 		}
 	}
 }
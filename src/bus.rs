@@ -0,0 +1,277 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! The bounded channels between civkitd components.
+//!
+//! Each channel applies an overflow policy, chosen per message type, when its
+//! queue is full and counts its depth, drops and rejections for the admin.
+//!
+//! To avoid deadlocks the components blocking on a full queue are never
+//! awaited on by its consumer: ClientHandler only drops or rejects, the workers
+//! behind it (NoteProcessor, CredentialGateway, BitcoindHandler) may block.
+
+use crate::bitcoind_client::{BitcoindRequest, BitcoindResult};
+use crate::events::ClientEvents;
+use crate::nostr_db::DbRequest;
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+	/// The message is discarded, the sender is not told.
+	Drop,
+	/// The message is handed back to the sender to refuse the work upstream.
+	Reject,
+	/// The sender waits for room in the queue.
+	Block,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BusError<T> {
+	/// The queue is full and the message policy is to reject it.
+	Full(T),
+	/// The receiving component is gone.
+	Closed(T),
+}
+
+impl<T> fmt::Display for BusError<T> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			BusError::Full(_) => write!(f, "queue full"),
+			BusError::Closed(_) => write!(f, "queue closed"),
+		}
+	}
+}
+
+/// The counters of one queue.
+#[derive(Debug)]
+struct QueueStats {
+	name: String,
+	capacity: u64,
+	depth: AtomicU64,
+	high_water: AtomicU64,
+	sent: AtomicU64,
+	dropped: AtomicU64,
+	rejected: AtomicU64,
+}
+
+impl QueueStats {
+	/// Counted before the message is queued, the receiver may get it first.
+	fn on_enqueue(&self) {
+		let depth = self.depth.fetch_add(1, Ordering::AcqRel) + 1;
+		self.high_water.fetch_max(depth, Ordering::AcqRel);
+		self.sent.fetch_add(1, Ordering::Relaxed);
+	}
+
+	fn on_enqueue_failure(&self) {
+		self.depth.fetch_sub(1, Ordering::AcqRel);
+		self.sent.fetch_sub(1, Ordering::Relaxed);
+	}
+
+	fn on_dequeued(&self) {
+		self.depth.fetch_sub(1, Ordering::AcqRel);
+	}
+}
+
+/// A point-in-time copy of the counters of a queue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueSnapshot {
+	pub name: String,
+	pub capacity: u64,
+	pub depth: u64,
+	pub high_water: u64,
+	pub sent: u64,
+	pub dropped: u64,
+	pub rejected: u64,
+}
+
+/// The registry of all the queues, shared with the admin interface.
+#[derive(Clone, Debug, Default)]
+pub struct BusMetrics {
+	queues: Arc<Mutex<Vec<Arc<QueueStats>>>>,
+}
+
+impl BusMetrics {
+	pub fn new() -> Self {
+		BusMetrics::default()
+	}
+
+	/// Creates a queue of `capacity` messages, `policy` tells what to do with a
+	/// message when the queue is full.
+	pub fn channel<T>(&self, name: &str, capacity: usize, policy: fn(&T) -> OverflowPolicy) -> (BusSender<T>, BusReceiver<T>) {
+		let capacity = capacity.max(1);
+		let (send, receive) = mpsc::channel(capacity);
+		let stats = Arc::new(QueueStats {
+			name: name.to_string(),
+			capacity: capacity as u64,
+			depth: AtomicU64::new(0),
+			high_water: AtomicU64::new(0),
+			sent: AtomicU64::new(0),
+			dropped: AtomicU64::new(0),
+			rejected: AtomicU64::new(0),
+		});
+		self.queues.lock().unwrap().push(stats.clone());
+
+		(BusSender { send, policy, stats: stats.clone() }, BusReceiver { receive, stats })
+	}
+
+	pub fn snapshot(&self) -> Vec<QueueSnapshot> {
+		self.queues.lock().unwrap().iter().map(|stats| {
+			QueueSnapshot {
+				name: stats.name.clone(),
+				capacity: stats.capacity,
+				depth: stats.depth.load(Ordering::Acquire),
+				high_water: stats.high_water.load(Ordering::Acquire),
+				sent: stats.sent.load(Ordering::Relaxed),
+				dropped: stats.dropped.load(Ordering::Relaxed),
+				rejected: stats.rejected.load(Ordering::Relaxed),
+			}
+		}).collect()
+	}
+}
+
+pub struct BusSender<T> {
+	send: mpsc::Sender<T>,
+	policy: fn(&T) -> OverflowPolicy,
+	stats: Arc<QueueStats>,
+}
+
+impl<T> Clone for BusSender<T> {
+	fn clone(&self) -> Self {
+		BusSender {
+			send: self.send.clone(),
+			policy: self.policy,
+			stats: self.stats.clone(),
+		}
+	}
+}
+
+impl<T> fmt::Debug for BusSender<T> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "BusSender({})", self.stats.name)
+	}
+}
+
+impl<T> BusSender<T> {
+	/// Sends the message, waiting for room only if its policy is to block.
+	/// A dropped message is reported as sent.
+	pub async fn send(&self, msg: T) -> Result<(), BusError<T>> {
+		if (self.policy)(&msg) != OverflowPolicy::Block {
+			return self.try_send(msg);
+		}
+		// Waiting on a permit keeps the counters right if the caller gives up.
+		match self.send.reserve().await {
+			Ok(permit) => {
+				self.stats.on_enqueue();
+				permit.send(msg);
+				Ok(())
+			},
+			Err(_) => Err(BusError::Closed(msg)),
+		}
+	}
+
+	/// Sends the message without waiting, for the callers which cannot block.
+	/// A message whose policy is to block is rejected on a full queue.
+	pub fn try_send(&self, msg: T) -> Result<(), BusError<T>> {
+		self.stats.on_enqueue();
+		let result = self.send.try_send(msg);
+		if result.is_err() { self.stats.on_enqueue_failure(); }
+		match result {
+			Ok(()) => Ok(()),
+			Err(TrySendError::Full(msg)) => {
+				if (self.policy)(&msg) == OverflowPolicy::Drop {
+					self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+					println!("[CIVKITD] - BUS: queue {} full, dropping message", self.stats.name);
+					Ok(())
+				} else {
+					self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+					println!("[CIVKITD] - BUS: queue {} full, rejecting message", self.stats.name);
+					Err(BusError::Full(msg))
+				}
+			},
+			Err(TrySendError::Closed(msg)) => Err(BusError::Closed(msg)),
+		}
+	}
+}
+
+pub struct BusReceiver<T> {
+	receive: mpsc::Receiver<T>,
+	stats: Arc<QueueStats>,
+}
+
+impl<T> BusReceiver<T> {
+	/// Receives the next message, it is cancel safe.
+	pub async fn recv(&mut self) -> Option<T> {
+		let msg = self.receive.recv().await;
+		if msg.is_some() { self.stats.on_dequeued(); }
+		msg
+	}
+
+	pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+		let msg = self.receive.try_recv();
+		if msg.is_ok() { self.stats.on_dequeued(); }
+		msg
+	}
+}
+
+/// The overflow policies of the messages, by message type and by direction.
+pub mod policies {
+	use super::*;
+
+	pub fn always_block<T>(_: &T) -> OverflowPolicy { OverflowPolicy::Block }
+
+	pub fn always_reject<T>(_: &T) -> OverflowPolicy { OverflowPolicy::Reject }
+
+	/// The requests of the ClientHandler to the NoteProcessor. The clients are
+	/// told when their event or subscription is refused, the bookkeeping writes
+	/// are best effort. The disconnections are retried by the ClientHandler, as
+	/// they release the client state.
+	pub fn client_db_requests(request: &DbRequest) -> OverflowPolicy {
+		match request {
+			DbRequest::WriteClient(_) => OverflowPolicy::Drop,
+			_ => OverflowPolicy::Reject,
+		}
+	}
+
+	/// The events of the ServiceManager to the ClientHandler, the admin is
+	/// answered an error when refused.
+	pub fn service_events(_: &ClientEvents) -> OverflowPolicy {
+		OverflowPolicy::Reject
+	}
+
+	/// The results sent back to the ClientHandler by the workers: the clients
+	/// are waiting for them, the service announcements can be redone.
+	pub fn client_results(event: &ClientEvents) -> OverflowPolicy {
+		match event {
+			ClientEvents::ServiceAnnouncement { .. } => OverflowPolicy::Drop,
+			_ => OverflowPolicy::Block,
+		}
+	}
+
+	/// The credentials and service registrations submitted to the
+	/// CredentialGateway. The disconnections are retried by the ClientHandler.
+	pub fn credential_requests(_: &ClientEvents) -> OverflowPolicy {
+		OverflowPolicy::Reject
+	}
+
+	/// The requests to bitcoind are refused rather than queued behind a slow
+	/// RPC, the results are awaited by the CredentialGateway.
+	pub fn bitcoind_requests(_: &BitcoindRequest) -> OverflowPolicy {
+		OverflowPolicy::Reject
+	}
+
+	pub fn bitcoind_results(_: &BitcoindResult) -> OverflowPolicy {
+		OverflowPolicy::Block
+	}
+}
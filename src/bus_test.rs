@@ -0,0 +1,47 @@
+use crate::bus::{BusError, BusMetrics, OverflowPolicy};
+
+use tokio::time::{timeout, Duration};
+
+fn by_value(msg: &u64) -> OverflowPolicy {
+	match msg {
+		0 => OverflowPolicy::Drop,
+		1 => OverflowPolicy::Reject,
+		_ => OverflowPolicy::Block,
+	}
+}
+
+#[tokio::test]
+async fn test_overflow_policies() {
+	let metrics = BusMetrics::new();
+	let (send, mut receive) = metrics.channel::<u64>("test_queue", 1, by_value);
+
+	assert_eq!(send.send(2).await, Ok(()));
+
+	// The queue is full.
+	assert_eq!(send.send(0).await, Ok(()));
+	assert_eq!(send.send(1).await, Err(BusError::Full(1)));
+	assert!(timeout(Duration::from_millis(50), send.send(2)).await.is_err());
+	assert_eq!(send.try_send(2), Err(BusError::Full(2)));
+
+	let snapshot = &metrics.snapshot()[0];
+	assert_eq!(snapshot.name, "test_queue");
+	assert_eq!(snapshot.capacity, 1);
+	assert_eq!(snapshot.depth, 1);
+	assert_eq!(snapshot.sent, 1);
+	assert_eq!(snapshot.dropped, 1);
+	assert_eq!(snapshot.rejected, 2);
+
+	// A blocked sender proceeds once there is room.
+	let blocked_send = send.clone();
+	let blocked = tokio::spawn(async move { blocked_send.send(3).await });
+	assert_eq!(receive.recv().await, Some(2));
+	assert_eq!(blocked.await.unwrap(), Ok(()));
+	assert_eq!(receive.recv().await, Some(3));
+
+	let snapshot = &metrics.snapshot()[0];
+	assert_eq!(snapshot.depth, 0);
+	assert_eq!(snapshot.high_water, 1);
+
+	drop(receive);
+	assert_eq!(send.send(2).await, Err(BusError::Closed(2)));
+}
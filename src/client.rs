@@ -9,7 +9,7 @@
 
 use adminctrl::admin_ctrl_client::AdminCtrlClient;
 //TODO: simplify by using prefix
-use adminctrl::{PingRequest, PongRequest, ShutdownRequest, ShutdownReply, SendNote, ReceivedNote, ListClientRequest, ListSubscriptionRequest, PeerConnectionRequest, DisconnectClientRequest, SendNotice, SendOffer, SendInvoice, ListDbEventsRequest, ListDbClientsRequest, ListDbClientsReply, CheckChainStateRequest, CheckChainStateReply, GenerateTxInclusionProofRequest, GenerateTxInclusionProofReply};
+use adminctrl::{PingRequest, PongRequest, ShutdownRequest, ShutdownReply, SendNote, ReceivedNote, ListClientRequest, ConnectionUsageRequest, QueueMetricsRequest, ListSubscriptionRequest, PeerConnectionRequest, DisconnectClientRequest, SendNotice, SendOffer, SendInvoice, ListDbEventsRequest, ListDbClientsRequest, ListDbClientsReply, CheckChainStateRequest, CheckChainStateReply, GenerateTxInclusionProofRequest, GenerateTxInclusionProofReply};
 
 use std::env;
 use std::process;
@@ -55,6 +55,10 @@ enum Command {
 	Publishtextnote,
 	/// List information about connected clients
 	Listclients,
+	/// Report the client connections usage against the configured limits
+	Connectionusage,
+	/// Report the depth, drops and rejections of the queues between components
+	Queuemetrics,
 	/// List information about subscriptions [TODO]
 	Listsubscriptions,
 	/// Connect to a BOLT8 peer on local port
@@ -124,6 +128,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 			println!("[CIVKIT-CLI] clients {:#?}", response.into_inner().clients);
 		}
+		Command::Connectionusage => {
+			let request = tonic::Request::new(ConnectionUsageRequest {});
+
+			let response = client.connection_usage(request).await?;
+
+			println!("[CIVKIT-CLI] connection usage {:#?}", response.into_inner());
+		}
+		Command::Queuemetrics => {
+			let request = tonic::Request::new(QueueMetricsRequest {});
+
+			let response = client.queue_metrics(request).await?;
+
+			for queue in response.into_inner().queues {
+				println!("[CIVKIT-CLI] queue {} depth {} / {} (high water {}) sent {} dropped {} rejected {}", queue.name, queue.depth, queue.capacity, queue.high_water, queue.sent, queue.dropped, queue.rejected);
+			}
+		}
 		Command::Listsubscriptions => {
 			let request = tonic::Request::new(ListSubscriptionRequest {});
 
@@ -0,0 +1,94 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! NIP-42 authentication of clients to the relay.
+//!
+//! Each connection receives a random challenge, the client proves ownership
+//! of a key by signing a kind 22242 event carrying this challenge.
+
+use bitcoin::secp256k1::rand;
+
+use nostr::{Event, Kind, Timestamp};
+use nostr::key::XOnlyPublicKey;
+
+use crate::eventvalidation::validate_event;
+
+use std::fmt;
+
+/// The kind of the NIP-42 authentication events.
+pub const AUTH_EVENT_KIND: u64 = 22242;
+
+/// Max drift in seconds between the authentication event and our clock.
+const MAX_AUTH_TIME_DRIFT: i64 = 600;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+	WrongKind,
+	InvalidEvent,
+	WrongChallenge,
+	WrongRelay,
+	Expired,
+}
+
+impl fmt::Display for AuthError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			AuthError::WrongKind => write!(f, "authentication event must be of kind {}", AUTH_EVENT_KIND),
+			AuthError::InvalidEvent => write!(f, "authentication event id or signature is invalid"),
+			AuthError::WrongChallenge => write!(f, "authentication challenge does not match"),
+			AuthError::WrongRelay => write!(f, "authentication relay does not match"),
+			AuthError::Expired => write!(f, "authentication event is too far from current time"),
+		}
+	}
+}
+
+/// Returns a fresh random challenge for a new connection.
+pub fn generate_challenge() -> String {
+	hex::encode(rand::random::<[u8; 16]>())
+}
+
+/// Verifies a NIP-42 authentication event against the challenge sent to the
+/// client and returns the authenticated key.
+///
+/// If `relay_url` is empty, any `relay` tag is accepted.
+pub fn verify_auth_event(event: &Event, challenge: &str, relay_url: &str, now: Timestamp) -> Result<XOnlyPublicKey, AuthError> {
+	if event.kind != Kind::from(AUTH_EVENT_KIND) {
+		return Err(AuthError::WrongKind);
+	}
+	validate_event(event).map_err(|_| AuthError::InvalidEvent)?;
+
+	if (event.created_at.as_i64() - now.as_i64()).abs() > MAX_AUTH_TIME_DRIFT {
+		return Err(AuthError::Expired);
+	}
+
+	if tag_value(event, "challenge").as_deref() != Some(challenge) {
+		return Err(AuthError::WrongChallenge);
+	}
+
+	match tag_value(event, "relay") {
+		Some(relay) if relay_url.is_empty() || same_relay_url(&relay, relay_url) => {},
+		_ => { return Err(AuthError::WrongRelay); }
+	}
+
+	Ok(event.pubkey)
+}
+
+fn tag_value(event: &Event, name: &str) -> Option<String> {
+	for tag in event.tags.iter() {
+		let mut tag = tag.as_vec();
+		if tag.len() >= 2 && tag[0] == name {
+			return Some(tag.swap_remove(1));
+		}
+	}
+	None
+}
+
+fn same_relay_url(a: &str, b: &str) -> bool {
+	a.trim_end_matches('/').eq_ignore_ascii_case(b.trim_end_matches('/'))
+}
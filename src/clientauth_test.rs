@@ -0,0 +1,58 @@
+use crate::clientauth::{generate_challenge, verify_auth_event, AuthError, AUTH_EVENT_KIND};
+
+use nostr::{Event, EventBuilder, Keys, Kind, Tag, Timestamp};
+
+use serde_json::json;
+
+const RELAY_URL: &str = "wss://relay.civkit.org";
+
+fn build_auth_event(keys: &Keys, kind: u64, challenge: &str, relay: &str) -> Event {
+	let tags = vec![
+		Tag::parse(vec!["relay".to_string(), relay.to_string()]).unwrap(),
+		Tag::parse(vec!["challenge".to_string(), challenge.to_string()]).unwrap(),
+	];
+	EventBuilder::new(Kind::from(kind), "", &tags).to_event(keys).unwrap()
+}
+
+#[test]
+fn test_challenges_are_random() {
+	assert_ne!(generate_challenge(), generate_challenge());
+	assert_eq!(generate_challenge().len(), 32);
+}
+
+#[test]
+fn test_valid_auth_event() {
+	let keys = Keys::generate();
+	let challenge = generate_challenge();
+	let event = build_auth_event(&keys, AUTH_EVENT_KIND, &challenge, RELAY_URL);
+
+	assert_eq!(verify_auth_event(&event, &challenge, RELAY_URL, Timestamp::now()), Ok(keys.public_key()));
+	assert_eq!(verify_auth_event(&event, &challenge, "wss://relay.civkit.org/", Timestamp::now()), Ok(keys.public_key()));
+	// Without a configured relay url any relay tag is accepted.
+	assert_eq!(verify_auth_event(&event, &challenge, "", Timestamp::now()), Ok(keys.public_key()));
+}
+
+#[test]
+fn test_invalid_auth_events() {
+	let keys = Keys::generate();
+	let challenge = generate_challenge();
+
+	let wrong_kind = build_auth_event(&keys, 1, &challenge, RELAY_URL);
+	assert_eq!(verify_auth_event(&wrong_kind, &challenge, RELAY_URL, Timestamp::now()), Err(AuthError::WrongKind));
+
+	let wrong_challenge = build_auth_event(&keys, AUTH_EVENT_KIND, &generate_challenge(), RELAY_URL);
+	assert_eq!(verify_auth_event(&wrong_challenge, &challenge, RELAY_URL, Timestamp::now()), Err(AuthError::WrongChallenge));
+
+	let wrong_relay = build_auth_event(&keys, AUTH_EVENT_KIND, &challenge, "wss://other.relay");
+	assert_eq!(verify_auth_event(&wrong_relay, &challenge, RELAY_URL, Timestamp::now()), Err(AuthError::WrongRelay));
+
+	let event = build_auth_event(&keys, AUTH_EVENT_KIND, &challenge, RELAY_URL);
+	let later = Timestamp::from(event.created_at.as_u64() + 3600);
+	assert_eq!(verify_auth_event(&event, &challenge, RELAY_URL, later), Err(AuthError::Expired));
+
+	// Claiming another key with a signature of our own.
+	let mut raw: serde_json::Value = serde_json::from_str(&event.as_json()).unwrap();
+	raw["pubkey"] = json!(Keys::generate().public_key().to_string());
+	let forged = Event::from_json(raw.to_string()).unwrap();
+	assert_eq!(verify_auth_event(&forged, &challenge, RELAY_URL, Timestamp::now()), Err(AuthError::InvalidEvent));
+}
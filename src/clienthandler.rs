@@ -9,128 +9,188 @@
 
 //! The ClientHandler responsible of nostr clients and subscriptions.
 
-use bitcoin::secp256k1;
-use bitcoin::secp256k1::SecretKey;
-use bitcoin::secp256k1::Secp256k1;
-
-use nostr::{RelayMessage, Event, EventId, ClientMessage, SubscriptionId, Filter};
-use nostr::key::XOnlyPublicKey;
+use nostr::{RelayMessage, Event, EventId, ClientMessage, Filter, SubscriptionId, Timestamp};
 
 use crate::config::Config;
 
-use crate::{events, NostrSub, NostrClient};
-use crate::events::{ClientEvents, EventsProvider, ServerCmd};
+use crate::bus::{BusError, BusReceiver, BusSender};
+
+use crate::{NostrSub, NostrClient};
+use crate::eventcount::{count_message, parse_count_message};
+use crate::eventfilter::match_filters;
+use crate::eventvalidation::validate_event;
+use crate::clientauth::verify_auth_event;
+use crate::connectionlimits::{ConnectionLimits, ConnectionRefusal};
+use crate::kindprocessor::MAX_PENDING_DB_REQUEST_PER_CLIENT;
+use crate::admission::AdmissionPolicy;
+use crate::ratelimit::RateLimiter;
+use crate::rejection::{ok_message, RejectionReason};
+use crate::retention::is_expired;
+use crate::search::has_search;
+use crate::events::{ClientEvents, ServerCmd};
 use crate::nostr_db::DbRequest;
+use crate::shutdown::ShutdownSignal;
 use crate::util::{is_ephemeral, is_credential};
 
-use staking_credentials::common::msgs::CredentialPolicy;
+use futures_util::{StreamExt, SinkExt};
 
-use futures_util::{future, pin_mut, TryStreamExt, StreamExt, SinkExt};
+use serde_json::Value;
 
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::{thread, time};
+use std::time::Instant;
 
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::time::{sleep, Duration};
 
 use tokio_tungstenite::tungstenite::Message;
 
 /// Max number of subscriptions by connected clients.
-const MAX_SUBSCRIPTIONS: u64 = 100;
-
-//pub(crate) struct NostrSub {
-//	our_side_id: u64,
-//	id: SubscriptionId,
-//	filters: Vec<Filter>,
-//}
+pub const MAX_SUBSCRIPTIONS: u64 = 100;
 
+/// The subscription id the credential results are sent on, whatever the
+/// subscriptions opened by the client.
+pub const CREDENTIAL_SUBSCRIPTION_ID: &str = "civkit-credentials";
 
 const MAGIC_SERVER_PAYLOAD: [u8; 4] = [0x27, 0x27, 0x27, 0x27];
 
-//TODO: rework the mutex model
+/// Max wait for the client connections to flush their last messages on shutdown.
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The period of the retries of the disconnections refused by a full queue.
+const DISCONNECTION_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 pub struct ClientHandler {
 	clients: HashMap<u64, NostrClient>,
-	subscriptions: HashMap<u64, NostrSub>,
+	/// The subscriptions by client and by the id the client supplied.
+	subscriptions: HashMap<(u64, SubscriptionId), NostrSub>,
 
 	clients_counter: u64,
 	subscriptions_counter: u64,
 
-	map_send: Mutex<HashMap<u64, mpsc::UnboundedSender<Vec<u8>>>>,
-	map_receive: Mutex<HashMap<u64, mpsc::UnboundedReceiver<Vec<u8>>>>,
+	map_send: HashMap<u64, mpsc::UnboundedSender<Vec<u8>>>,
+	/// The connection tasks, each one sends its client id on `send_client_disconnect`
+	/// once its websocket is closed.
+	connection_tasks: HashMap<u64, JoinHandle<()>>,
+	connection_limits: ConnectionLimits,
+
+	/// The messages received on all the client connections, with the client id.
+	send_client_msg: BusSender<(u64, Vec<u8>)>,
+	receive_client_msg: BusReceiver<(u64, Vec<u8>)>,
 
-	handler_receive: Mutex<mpsc::UnboundedReceiver<ClientEvents>>,
-	connection_receive: Mutex<mpsc::UnboundedReceiver<(TcpStream, SocketAddr)>>,
+	send_client_disconnect: BusSender<u64>,
+	receive_client_disconnect: BusReceiver<u64>,
 
-	send_db_requests: Mutex<mpsc::UnboundedSender<DbRequest>>,
-	handler_receive_db_result: Mutex<mpsc::UnboundedReceiver<ClientEvents>>,
+	handler_receive: BusReceiver<ClientEvents>,
+	connection_receive: BusReceiver<(TcpStream, SocketAddr)>,
 
-	send_credential_events_handler: Mutex<mpsc::UnboundedSender<ClientEvents>>,
-	receive_credential_events_handler: Mutex<mpsc::UnboundedReceiver<ClientEvents>>,
+	send_db_requests: BusSender<DbRequest>,
+	handler_receive_db_result: BusReceiver<ClientEvents>,
 
-	filtered_events: HashMap<SubscriptionId, Event>,
+	send_credential_events_handler: BusSender<ClientEvents>,
+	receive_credential_events_handler: BusReceiver<ClientEvents>,
 
-	//TODO: put a max limit per client on the incoming event buffer.
-	pending_validation_events: Mutex<HashMap<EventId, ClientEvents>>,
+	/// The subscribed events withheld until their validation, with the id of
+	/// the publishing client.
+	pending_validation_events: HashMap<EventId, (u64, Vec<ClientEvents>)>,
+	/// The number of withheld events per publishing client.
+	pending_validation_counts: HashMap<u64, u64>,
 
-	pending_events: Mutex<Vec<ClientEvents>>,
+	/// The disconnections refused by a full queue, retried in order as they
+	/// release the client state of the other components.
+	retry_db_disconnections: VecDeque<DbRequest>,
+	retry_credential_disconnections: VecDeque<ClientEvents>,
 
-	deliverance_counter: u64,
+	rate_limiter: RateLimiter,
+	admission: AdmissionPolicy,
 
 	config: Config
 }
 
-async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr, outgoing_receive: mpsc::UnboundedSender<Vec<u8>>, mut incoming_send: mpsc::UnboundedReceiver<Vec<u8>>) {
+async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr, client_id: u64, outgoing_receive: BusSender<(u64, Vec<u8>)>, mut incoming_send: mpsc::UnboundedReceiver<Vec<u8>>) {
 	println!("[CIVKITD] - NET: incoming tcp Connection from :{}", addr);
 
-	let mut ws_stream = tokio_tungstenite::accept_async(raw_stream).await.expect("Error during the websocket handshake occured");
+	let ws_stream = match tokio_tungstenite::accept_async(raw_stream).await {
+		Ok(ws_stream) => ws_stream,
+		Err(err) => {
+			println!("[CIVKITD] - NET: websocket handshake failure with {}: {}", addr, err);
+			return;
+		},
+	};
 	println!("[CIVKITD] - NET: websocket established: {}", addr);
 
 	let (mut outgoing, mut incoming) = ws_stream.split();
 
-	tokio::spawn(async move {
+	let mut incoming_task = tokio::spawn(async move {
+		// We stop reading the socket while the ClientHandler is busy.
 		while let Some(message) = incoming.next().await {
-			match message {
-				Ok(Message::Text(msg)) => { outgoing_receive.send(msg.into()); },
-				Ok(Message::Binary(msg)) => { outgoing_receive.send(msg); },
-				Ok(Message::Close(None)) => { break; },
+			let forwarded = match message {
+				Ok(Message::Text(msg)) => outgoing_receive.send((client_id, msg.into())).await,
+				Ok(Message::Binary(msg)) => outgoing_receive.send((client_id, msg)).await,
+				Ok(Message::Close(_)) => { break; },
+				Err(err) => {
+					println!("[CIVKITD] - NET: websocket error with {}: {}", addr, err);
+					break;
+				},
 				other => {
-					//TODO: if failure client state cleanly
 					println!("[CIVKITD] unknow message: {:?}", Some(other));
+					Ok(())
 				},
-			}
+			};
+			if forwarded.is_err() { break; }
 		}
 		println!("[CIVKITD] websocket connection closing: {}", addr);
-		//TODO: if closing clean client and thread state
 	});
 
-	tokio::spawn(async move {
-		while let Some(message) = incoming_send.recv().await {
-			if message == MAGIC_SERVER_PAYLOAD {
-				match outgoing.close().await {
-					Ok(_) => {},
-					Err(_) => { println!("[CIVKITD] - NOSTR: sample disconnect !"); },
-				}
-			} else {
-				match outgoing.send(Message::Binary(message)).await {
-					Ok(_) => {},
-					Err(_) => { println!("[CIVKITD] - NOSTR: error sample sending !"); },
-				}
+	// The connection lasts until the client closes it or the ClientHandler drops
+	// the sending side.
+	loop {
+		let message = tokio::select! {
+			_ = &mut incoming_task => { break; },
+			message = incoming_send.recv() => {
+				if let Some(message) = message { message } else { break; }
+			},
+		};
+		if message == MAGIC_SERVER_PAYLOAD {
+			match outgoing.close().await {
+				Ok(_) => {},
+				Err(_) => { println!("[CIVKITD] - NOSTR: sample disconnect !"); },
+			}
+		} else {
+			match outgoing.send(Message::Binary(message)).await {
+				Ok(_) => {},
+				Err(_) => { println!("[CIVKITD] - NOSTR: error sample sending !"); },
 			}
 		}
-	});
+	}
+	incoming_task.abort();
 }
 
-impl ClientHandler {
-	pub fn new(handler_receive: mpsc::UnboundedReceiver<ClientEvents>, connection_receive: mpsc::UnboundedReceiver<(TcpStream, SocketAddr)>, send_db_requests: mpsc::UnboundedSender<DbRequest>, handler_receive_db_result: mpsc::UnboundedReceiver<ClientEvents>, send_credential_events_handler: mpsc::UnboundedSender<ClientEvents>, receive_credential_events_handler: mpsc::UnboundedReceiver<ClientEvents>, our_config: Config) -> Self {
+/// The reason sent to a client whose message is refused by a full queue.
+fn bus_refusal<T>(err: &BusError<T>) -> RejectionReason {
+	match err {
+		BusError::Full(_) => RejectionReason::RateLimited("relay overloaded, retry later".to_string()),
+		BusError::Closed(_) => RejectionReason::Error("relay shutting down".to_string()),
+	}
+}
 
-		let (outgoing_receive, incoming_receive) = mpsc::unbounded_channel::<Vec<u8>>();
+/// Completes the websocket handshake to tell the client why it is refused, then
+/// closes the connection.
+async fn refuse_connection(raw_stream: TcpStream, addr: SocketAddr, refusal: ConnectionRefusal) {
+	let mut ws_stream = if let Ok(ws_stream) = tokio_tungstenite::accept_async(raw_stream).await { ws_stream } else {
+		println!("[CIVKITD] - NET: websocket handshake failure with refused {}", addr);
+		return;
+	};
+	let notice = RelayMessage::new_notice(refusal.to_string());
+	let _ = ws_stream.send(Message::Text(notice.as_json())).await;
+	let _ = ws_stream.close(None).await;
+}
 
+impl ClientHandler {
+	pub fn new(handler_receive: BusReceiver<ClientEvents>, connection_receive: BusReceiver<(TcpStream, SocketAddr)>, send_client_msg: BusSender<(u64, Vec<u8>)>, receive_client_msg: BusReceiver<(u64, Vec<u8>)>, send_client_disconnect: BusSender<u64>, receive_client_disconnect: BusReceiver<u64>, send_db_requests: BusSender<DbRequest>, handler_receive_db_result: BusReceiver<ClientEvents>, send_credential_events_handler: BusSender<ClientEvents>, receive_credential_events_handler: BusReceiver<ClientEvents>, our_config: Config) -> Self {
 		ClientHandler {
 			clients: HashMap::new(),
 			subscriptions: HashMap::new(),
@@ -138,405 +198,683 @@ impl ClientHandler {
 			clients_counter: 0,
 			subscriptions_counter: 0,
 
-			map_send: Mutex::new(HashMap::new()),
-			map_receive: Mutex::new(HashMap::new()),
+			map_send: HashMap::new(),
+			connection_tasks: HashMap::new(),
+			connection_limits: ConnectionLimits::new(&our_config.connections).expect("Invalid CIDR block in the connections config"),
 
-			handler_receive: Mutex::new(handler_receive),
-			connection_receive: Mutex::new(connection_receive),
+			send_client_msg,
+			receive_client_msg,
 
-			send_db_requests: Mutex::new(send_db_requests),
-			handler_receive_db_result: Mutex::new(handler_receive_db_result),
+			send_client_disconnect,
+			receive_client_disconnect,
 
-			send_credential_events_handler: Mutex::new(send_credential_events_handler),
-			receive_credential_events_handler: Mutex::new(receive_credential_events_handler),
+			handler_receive,
+			connection_receive,
 
-			filtered_events: HashMap::new(),
+			send_db_requests,
+			handler_receive_db_result,
 
-			pending_validation_events: Mutex::new(HashMap::new()),
+			send_credential_events_handler,
+			receive_credential_events_handler,
 
-			pending_events: Mutex::new(vec![]),
+			pending_validation_events: HashMap::new(),
+			pending_validation_counts: HashMap::new(),
 
-			deliverance_counter: 0,
+			retry_db_disconnections: VecDeque::new(),
+			retry_credential_disconnections: VecDeque::new(),
+
+			rate_limiter: RateLimiter::new(our_config.rate_limits.clone()),
+			admission: AdmissionPolicy::new(&our_config),
 
 			config: our_config
 		}
 	}
 
-	pub async fn run(&mut self) {
+	pub async fn run(&mut self, mut shutdown: ShutdownSignal) {
+		let mut disconnection_retry = tokio::time::interval(DISCONNECTION_RETRY_INTERVAL);
 		loop {
-			sleep(Duration::from_millis(1000)).await;
-
-			let mut client_events = Vec::new();
-			{
+			tokio::select! {
+				// We stop serving, after notifying the clients.
+				_ = shutdown.recv() => {
+					println!("[CIVKITD] - CONTROL: ClientHandler shutting down");
+					self.close_clients().await;
+					break;
+				},
 				// We receive an offer processed by the relay management utility, or any other
 				// service-side Nostr event.
-				let handler_receive_lock = self.handler_receive.lock();
-
-				if let Ok(event) = handler_receive_lock.await.try_recv() {
+				Some(event) = self.handler_receive.recv() => {
 					println!("[CIVKITD] - PROCESSING: received an event from service manager");
-					// Handle server requests
-					if let ClientEvents::Server{ cmd } = event {
-						match cmd {
-							ServerCmd::GetClients { respond_to } => {
-								let all_clients = self.clients.values().cloned().collect::<Vec<NostrClient>>();
-								let _ = respond_to.send(all_clients);
-							},
-							ServerCmd::DisconnectClient { client_id } => {
-								let map_send_lock = self.map_send.lock();
-								if let Some(outgoing_send) = map_send_lock.await.get(&client_id) {
-									match outgoing_send.send(MAGIC_SERVER_PAYLOAD.clone().to_vec()) {
-										Ok(_) => {},
-										Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending disconnect"); }
-									}
-								}
-							},
-						}
+					if let ClientEvents::Server { cmd } = event {
+						self.handle_server_cmd(cmd);
 					} else {
-						client_events.push(event);
+						self.broadcast_client_event(event);
 					}
-				}
-			}
-
-			{
+				},
 				// We receive a result of a db query from the DB manager.
-				let mut handler_receive_db_result_lock = self.handler_receive_db_result.lock();
-				if let Ok(event) = handler_receive_db_result_lock.await.try_recv() {
-					client_events.push(event);
-				}
-			}
-
-			let mut dispatch_events = Vec::new();
-			{
-				//We receive a result of a credential validation request or service registration from the credential gateway.
-				let mut receive_credential_events_handler_lock = self.receive_credential_events_handler.lock();
-				if let Ok(event) = receive_credential_events_handler_lock.await.try_recv() {
-					dispatch_events.push(event);
-				}
+				Some(event) = self.handler_receive_db_result.recv() => {
+					self.broadcast_client_event(event);
+				},
+				// We receive a result of a credential validation request or service registration from the credential gateway.
+				Some(event) = self.receive_credential_events_handler.recv() => {
+					self.dispatch_events(vec![event]);
+				},
+				// We receive a new Nostr client connection.
+				Some((stream, addr)) = self.connection_receive.recv() => {
+					self.accept_client(stream, addr);
+				},
+				// We receive a new message from a Nostr client.
+				Some((client_id, msg)) = self.receive_client_msg.recv() => {
+					self.handle_client_message(client_id, msg);
+				},
+				// A Nostr client connection is closed.
+				Some(client_id) = self.receive_client_disconnect.recv() => {
+					self.disconnect_client(client_id);
+				},
+				_ = disconnection_retry.tick(), if self.has_pending_disconnections() => {
+					self.send_disconnections();
+				},
+				else => { break; }
 			}
+		}
+	}
 
-			let mut mark_as_validated = Vec::new();
-			for event in client_events {
-				let mut map_send_lock = self.map_send.lock();
-
-				for (id, outgoing_send) in map_send_lock.await.iter() {
-					println!("[CIVKITD] - NOSTR: sending event for client {}", id);
-					match event {
-						ClientEvents::TextNote { ref event } => {
-							let random_id = SubscriptionId::generate();
-							let relay_message = RelayMessage::new_event(random_id, event.clone());
-							let serialized_message = relay_message.as_json();
-							match outgoing_send.send(serialized_message.into_bytes()) {
-								Ok(_) => {},
-								Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending note"); }
-							}
-						},
-						ClientEvents::RelayNotice { ref client_id, ref message } => {
-							if id != client_id { continue }
-
-							//TODO: implement `requestcredential` announcement
-							let relay_message = RelayMessage::new_notice(message);
-							let serialized_message = relay_message.as_json();
-							match outgoing_send.send(serialized_message.into_bytes()) {
-								Ok(_) => {},
-								Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending notice"); },
-							}
-						},
-						ClientEvents::OrderNote { ref order } => {
-							let random_id = SubscriptionId::generate();
-							let relay_message = RelayMessage::new_event(random_id, order.clone());
-							let serialized_message = relay_message.as_json();
-							match outgoing_send.send(serialized_message.into_bytes()) {
-								Ok(_) => {},
-								Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending order"); }
-							}
-						},
-						ClientEvents::StoredEvent { ref client_id,  ref events } => {
-							if id != client_id { continue }
-
-							//TODO: capture the subscription id
-							let random_id = SubscriptionId::generate();
-							for ev in events {
-								let relay_message = RelayMessage::new_event(random_id.clone(), ev.clone());
-								let serialized_message = relay_message.as_json();
-								match outgoing_send.send(serialized_message.into_bytes()) {
-									Ok(_) => {},
-									Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending stored events"); },
-								}
-							}
-							let relay_message = RelayMessage::new_eose(random_id.clone());
-							let serialized_message = relay_message.as_json();
-							match outgoing_send.send(serialized_message.into_bytes()) {
-								Ok(_) => {},
-								Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending end of stored events"); },
-							}
-						},
-						ClientEvents::OkEvent { ref client_id, ref event_id, ref ret, ref msg } => {
-							if id != client_id { continue }
-
-							let msg_str = if msg.is_none() { String::new() } else { msg.clone().unwrap() };
-							let event_id_2 = event_id.clone();
-							let relay_message = RelayMessage::new_ok(*event_id, *ret, msg_str);
-							let serialized_message = relay_message.as_json();
-							mark_as_validated.push(event_id_2);
-							match outgoing_send.send(serialized_message.into_bytes()) {
-								Ok(_) => {},
-								Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending ok event"); },
-							}
-						},
-						ClientEvents::ServiceRegistration { ref pubkey, ref credential_policy, ref service_policy } => {
-							//TODO: design new nostr events or just use a tag or use a NOTICE ?
-						},
-						_ => {}
+	fn handle_server_cmd(&mut self, cmd: ServerCmd) {
+		match cmd {
+			ServerCmd::GetClients { respond_to } => {
+				let all_clients = self.clients.values().cloned().collect::<Vec<NostrClient>>();
+				let _ = respond_to.send(all_clients);
+			},
+			ServerCmd::DisconnectClient { client_id } => {
+				if let Some(outgoing_send) = self.map_send.get(&client_id) {
+					match outgoing_send.send(MAGIC_SERVER_PAYLOAD.clone().to_vec()) {
+						Ok(_) => {},
+						Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending disconnect"); }
 					}
 				}
-			}
-
-			{
+			},
+			ServerCmd::GetConnectionUsage { respond_to } => {
+				let _ = respond_to.send(self.connection_limits.usage());
+			},
+		}
+	}
 
-				let mut pending_validation_events_lock = self.pending_validation_events.lock().await;
-				if pending_validation_events_lock.len() != 0 { println!("[CIVKITD] NOSTR: - Pending validation events {} and validated events {}", pending_validation_events_lock.len(), mark_as_validated.len()); }
-				for validated in mark_as_validated {
-					println!("[CIVKITD] NOSTR: Validated Event Id {}", validated.to_string());
-					if let Some(event) = pending_validation_events_lock.remove(&validated) {
-						println!("[CIVKITD] - NOSTR: Dispatching validated event");
-						dispatch_events.push(event);
+	/// Sends a service-side event or a storage result to the concerned clients.
+	fn broadcast_client_event(&mut self, event: ClientEvents) {
+		// The withheld events are released even if their publisher is gone.
+		let mut mark_as_validated = Vec::new();
+		if let ClientEvents::OkEvent { ref event_id, ref reason, .. } = event {
+			// Only the newly stored events are released to the subscribers.
+			mark_as_validated.push((*event_id, reason.is_none()));
+		}
+		for (id, outgoing_send) in self.map_send.iter() {
+			println!("[CIVKITD] - NOSTR: sending event for client {}", id);
+			match event {
+				ClientEvents::TextNote { ref event } => {
+					for sub_id in self.matching_subscriptions(*id, event) {
+						let relay_message = RelayMessage::new_event(sub_id, event.clone());
+						let serialized_message = relay_message.as_json();
+						match outgoing_send.send(serialized_message.into_bytes()) {
+							Ok(_) => {},
+							Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending note"); }
+						}
 					}
-				}
-			}
-
-			// Dispatch pending client events
-			{
-				let mut map_send_lock = self.map_send.lock();
-				for (map_client_id, outgoing_send) in map_send_lock.await.iter() {
-					for event in dispatch_events.iter() {
-						match event {
-							ClientEvents::EndOfStoredEvents { client_id, sub_id } => {
-								if client_id == map_client_id {
-									let relay_message = RelayMessage::new_eose(sub_id.clone());
-									let serialized_message = relay_message.as_json();
-									match outgoing_send.send(serialized_message.into_bytes()) {
-										Ok(_) => {},
-										Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending end of stored events"); },
-									}
-								}
-							},
-							ClientEvents::SubscribedEvent { client_id, sub_id, event } => {
-								if client_id == map_client_id {
-									let relay_message = RelayMessage::new_event(sub_id.clone(), event.clone());
-									let serialized_message = relay_message.as_json();
-									match outgoing_send.send(serialized_message.into_bytes()) {
-										Ok(_) => {},
-										Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending subcribed event"); },
-									}
-								}
-							},
-							ClientEvents::Credential { client_id, deliverance_id, event } => {
-								if client_id == map_client_id {
-									let random_sub_id = SubscriptionId::generate();
-									let relay_message = RelayMessage::new_event(random_sub_id, event.clone());
-									let serialized_message = relay_message.as_json();
-									match outgoing_send.send(serialized_message.into_bytes()) {
-										Ok(_) => {},
-										Err(_) => { println!("[CIVKITD - NOSTR: Error inter thread sending subscribed event"); },
-									}
-								}
-							},
-							_ => {},
+				},
+				ClientEvents::RelayNotice { ref client_id, ref message } => {
+					if id != client_id { continue }
+
+					//TODO: implement `requestcredential` announcement
+					let relay_message = RelayMessage::new_notice(message);
+					let serialized_message = relay_message.as_json();
+					match outgoing_send.send(serialized_message.into_bytes()) {
+						Ok(_) => {},
+						Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending notice"); },
+					}
+				},
+				ClientEvents::OrderNote { ref order } => {
+					for sub_id in self.matching_subscriptions(*id, order) {
+						let relay_message = RelayMessage::new_event(sub_id, order.clone());
+						let serialized_message = relay_message.as_json();
+						match outgoing_send.send(serialized_message.into_bytes()) {
+							Ok(_) => {},
+							Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending order"); }
 						}
 					}
-				}
+				},
+				ClientEvents::StoredEvent { ref client_id, ref sub_id, ref events } => {
+					if id != client_id { continue }
+
+					for ev in events {
+						let relay_message = RelayMessage::new_event(sub_id.clone(), ev.clone());
+						let serialized_message = relay_message.as_json();
+						match outgoing_send.send(serialized_message.into_bytes()) {
+							Ok(_) => {},
+							Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending stored events"); },
+						}
+					}
+					let relay_message = RelayMessage::new_eose(sub_id.clone());
+					let serialized_message = relay_message.as_json();
+					match outgoing_send.send(serialized_message.into_bytes()) {
+						Ok(_) => {},
+						Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending end of stored events"); },
+					}
+				},
+				ClientEvents::OkEvent { ref client_id, ref event_id, ref reason } => {
+					if id != client_id { continue }
+
+					let relay_message = ok_message(*event_id, reason.as_ref());
+					let serialized_message = relay_message.as_json();
+					match outgoing_send.send(serialized_message.into_bytes()) {
+						Ok(_) => {},
+						Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending ok event"); },
+					}
+				},
+				ClientEvents::EventCount { ref client_id, ref sub_id, ref count } => {
+					if id != client_id { continue }
+
+					let serialized_message = match count {
+						Ok(count) => count_message(sub_id, *count),
+						Err(reason) => reason.closed_message(sub_id),
+					};
+					match outgoing_send.send(serialized_message.into_bytes()) {
+						Ok(_) => {},
+						Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending event count"); },
+					}
+				},
+				ClientEvents::ServiceRegistration { ref pubkey, ref credential_policy, ref service_policy } => {
+					//TODO: design new nostr events or just use a tag or use a NOTICE ?
+				},
+				_ => {}
 			}
+		}
 
-			let mut socket_and_sender = None;
-			{
-				// We receive a new Nostr client connection.
-				let mut nostr_client_request_lock = self.connection_receive.lock();
-
-				if let Ok((stream, addr)) = nostr_client_request_lock.await.try_recv() {
-					let (outgoing_send, incoming_send) = mpsc::unbounded_channel::<Vec<u8>>();
-					let (outgoing_receive, incoming_receive) = mpsc::unbounded_channel::<Vec<u8>>();
-					socket_and_sender = Some((addr.clone(), outgoing_send, incoming_receive));
-					tokio::spawn(async move {
-						handle_connection(stream, addr, outgoing_receive, incoming_send).await;
-					});
+		if self.pending_validation_events.len() != 0 { println!("[CIVKITD] NOSTR: - Pending validation events {} and validated events {}", self.pending_validation_events.len(), mark_as_validated.len()); }
+		let mut dispatch_events = Vec::new();
+		for (validated, accepted) in mark_as_validated {
+			println!("[CIVKITD] NOSTR: Validated Event Id {}", validated.to_string());
+			if let Some(mut events) = self.take_pending_validation(&validated) {
+				// Rejected events are dropped without reaching the subscribers.
+				if accepted {
+					println!("[CIVKITD] - NOSTR: Dispatching validated event");
+					dispatch_events.append(&mut events);
 				}
 			}
+		}
+		self.dispatch_events(dispatch_events);
+	}
+
+	fn take_pending_validation(&mut self, event_id: &EventId) -> Option<Vec<ClientEvents>> {
+		let (publisher, events) = self.pending_validation_events.remove(event_id)?;
+		if let Some(count) = self.pending_validation_counts.get_mut(&publisher) {
+			*count -= 1;
+			if *count == 0 {
+				self.pending_validation_counts.remove(&publisher);
+			}
+		}
+		Some(events)
+	}
 
-			let mut write_db = Vec::new();
-			let mut query_credential_gateway = Vec::new();
+	/// Sends the events addressed to a single client, e.g the subscribed events
+	/// released after validation.
+	fn dispatch_events(&self, dispatch_events: Vec<ClientEvents>) {
+		for event in dispatch_events {
+			let (client_id, relay_message) = match event {
+				ClientEvents::EndOfStoredEvents { client_id, sub_id } => {
+					(client_id, RelayMessage::new_eose(sub_id))
+				},
+				ClientEvents::SubscribedEvent { client_id, sub_id, event } => {
+					(client_id, RelayMessage::new_event(sub_id, event))
+				},
+				ClientEvents::Credential { client_id, event } => {
+					// The credential results are sent to the requesting client only.
+					(client_id, RelayMessage::new_event(SubscriptionId::new(CREDENTIAL_SUBSCRIPTION_ID), event))
+				},
+				ClientEvents::OkEvent { client_id, event_id, reason } => {
+					(client_id, ok_message(event_id, reason.as_ref()))
+				},
+				_ => { continue; },
+			};
+			self.send_relay_message(client_id, relay_message);
+		}
+	}
 
-			let mut new_pending_events = Vec::new();
+	/// Sends an OK false, or true for a duplicate, for a refused event.
+	fn send_rejection(&self, client_id: u64, event_id: EventId, reason: RejectionReason) {
+		println!("[CIVKITD] - NOSTR: Rejecting event {} from {}: {}", event_id.to_hex(), client_id, reason);
+		self.send_relay_message(client_id, reason.ok_message(event_id));
+	}
 
-			if let Some((addr, outgoing_send, incoming_receive)) = socket_and_sender {
-				self.clients_counter += 1;
-				let client_id = self.clients_counter;
-				let new_nostr_client = NostrClient::new(client_id as u64, addr);
-				let client_2 = new_nostr_client.clone();
-				self.clients.insert(client_id, new_nostr_client);
-				{
-					let mut map_send_lock = self.map_send.lock();
-					map_send_lock.await.insert(client_id, outgoing_send);
-				}
-				{
-					let mut map_receive_lock = self.map_receive.lock();
-					map_receive_lock.await.insert(client_id, incoming_receive);
-				}
-				let db_request = DbRequest::WriteClient(client_2);
-				write_db.push(db_request);
-				//TODO: serialize CredentialPolicy as message notice
-				let message = String::new();
-				let relay_message = RelayMessage::new_notice(message);
-				let serialized_message = relay_message.as_json();
-				let notice_event = ClientEvents::RelayNotice { client_id: client_id,  message: serialized_message };
-				new_pending_events.push(notice_event);
+	fn send_relay_message(&self, client_id: u64, relay_message: RelayMessage) {
+		if let Some(outgoing_send) = self.map_send.get(&client_id) {
+			match outgoing_send.send(relay_message.as_json().into_bytes()) {
+				Ok(_) => {},
+				Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending relay message"); },
 			}
+		}
+	}
 
-			let mut msg_queue = Vec::new();
-			{
-				// We check if a Nostr client has sent a new event
-				let mut map_receive_lock = self.map_receive.lock();
-				for (id, mut incoming_receive) in map_receive_lock.await.iter_mut() {
-					if let Ok(msg) = incoming_receive.try_recv() {
-						msg_queue.push((id.clone(), msg.clone()));
-					}
-				}
+	/// Sends a CLOSED for a subscription refused or terminated on the relay side.
+	fn send_closed(&self, client_id: u64, sub_id: &SubscriptionId, reason: &RejectionReason) {
+		if let Some(outgoing_send) = self.map_send.get(&client_id) {
+			match outgoing_send.send(reason.closed_message(sub_id).into_bytes()) {
+				Ok(_) => {},
+				Err(_) => { println!("[CIVKITD] - NOSTR: Error inter thread sending closed"); },
 			}
+		}
+	}
 
-			{
-				// If we have a new event, we'll fan out according to its types (event, subscription, close)
-				for (id, msg) in msg_queue {
-					let msg_json = String::from_utf8(msg).unwrap();
-					println!("[CIVKITD] - NOSTR: Message received from {}!", id);
-					if let Ok(client_msg) = ClientMessage::from_json(msg_json) {
-						match client_msg {
-							ClientMessage::Event(msg) => {
-								if let Some(nostr_client) = self.clients.get_mut(&id) {
-									if !nostr_client.has_pubkey() {
-										nostr_client.add_pubkey(msg.pubkey.clone());
-									}
-								}
-								let msg_2 = msg.clone();
-								if is_credential(&msg_2) {
-									self.deliverance_counter += 1;
-									println!("[CIVKITD] - NOSTR: credential msg received");
-									let credential = ClientEvents::Credential { client_id: id, deliverance_id: self.deliverance_counter, event: *msg_2.clone() };
-									query_credential_gateway.push(credential);
-								} else {
-									//TODO: move filtering logic in its own thread - beware out of sync with credential validation due to event timing.
-									self.filter_events(*msg).await;
-									//TODO: move is_ephemeral check when receive result from CredentialGateway is in NoteProcessor ?
-									if !is_ephemeral(&msg_2) {
-										//TODO: we reuse the self.deliverance_counter
-										let db_request = DbRequest::WriteEvent { client_id: id, deliverance_id: self.deliverance_counter, ev: *msg_2 };
-										write_db.push(db_request);
-									}
-								}
-							},
-							ClientMessage::Req { subscription_id, filters } => {
-								self.subscriptions_counter += 1;
-								let our_side_id = self.subscriptions_counter;
-								// Check this client number of subscriptions
-								if let Some(nostr_client) = self.clients.get_mut(&id) {
-									//TODO: NIP 01 : "Clients should not open more than one websocket to each relay. One channel can support an unlimited number of subscriptions, so clients should do that."
-									// Sanitize with keys ?
-									if !nostr_client.add_sub(our_side_id, MAX_SUBSCRIPTIONS) {
-										println!("[CIVKITD] - NOSTR: subscription register failure");
-									}
-								}
-
-								#[cfg(debug_assertions)] {
-									for filter in &filters {
-										if let Some(kinds) = &filter.kinds {
-											for kind in kinds {
-												 println!("[CIVKITD - NOSTR: Registering subscription for kind {}", kind.as_u32());
-											}
-										}
-									}
-								}
-								let nostr_sub = NostrSub::new(our_side_id, subscription_id.clone(), filters.clone());
-								let nostr_sub2 = nostr_sub.clone();
-								self.subscriptions.insert(our_side_id, nostr_sub);
-								let db_request = DbRequest::ReplayEvents { client_id: id, filters: filters };
-								write_db.push(db_request);
-							},
-							ClientMessage::Close(subscription_id) => {
-								//TODO: replace our_side_id by Sha256 of SubscriptionId
-								let mut our_side_id = 0;
-								for (registered_id, nostr_sub) in self.subscriptions.iter() {
-									if nostr_sub.is_our_id(&subscription_id) {
-										our_side_id = *registered_id;
-									}
-								}
-								if our_side_id != 0 {
-									self.subscriptions.remove(&our_side_id);
-									println!("[CIVKITD] - NOSTR: Remove subscription id {}", our_side_id);
-								}
-							},
-							_ => { println!("[CIVKITD] - NOSTR: Unknown client message"); }
-						}
-					} else { println!("[CIVKITD] - NOSTR: ClientMessage deserialization failure"); }
-				}
+	/// Terminates all the subscriptions and connections, then waits for the
+	/// connections to flush their last messages.
+	async fn close_clients(&mut self) {
+		let reason = RejectionReason::Error("relay shutting down".to_string());
+		for sub in self.subscriptions.values() {
+			self.send_closed(sub.get_client_id(), sub.get_id(), &reason);
+		}
+		self.subscriptions.clear();
+
+		for (client_id, outgoing_send) in self.map_send.iter() {
+			let _ = outgoing_send.send(RelayMessage::new_notice("relay shutting down").as_json().into_bytes());
+			if outgoing_send.send(MAGIC_SERVER_PAYLOAD.to_vec()).is_err() {
+				println!("[CIVKITD] - NOSTR: Error inter thread sending disconnect to {}", client_id);
+			}
+		}
+		// Dropping the senders lets the connection tasks complete once drained.
+		self.map_send.clear();
+
+		let connection_tasks: Vec<JoinHandle<()>> = self.connection_tasks.drain().map(|(_, task)| task).collect();
+		let all_closed = futures_util::future::join_all(connection_tasks);
+		// The closing connections are not blocked on their disconnect event.
+		let receive_client_disconnect = &mut self.receive_client_disconnect;
+		let drain_disconnections = async move { while receive_client_disconnect.recv().await.is_some() {} };
+		let closing = async move {
+			tokio::select! {
+				_ = all_closed => {},
+				_ = drain_disconnections => {},
 			}
+		};
+		if timeout(SHUTDOWN_FLUSH_TIMEOUT, closing).await.is_err() {
+			println!("[CIVKITD] - NOSTR: Some client connections did not close in time");
+		}
+	}
 
-			{
-				for ev in write_db {
-					let mut send_db_requests_lock = self.send_db_requests.lock();
-					send_db_requests_lock.await.send(ev);
-				}
+	/// Releases all the state of a client whose websocket has been closed and
+	/// records the disconnection.
+	fn disconnect_client(&mut self, client_id: u64) {
+		let nostr_client = if let Some(nostr_client) = self.clients.remove(&client_id) { nostr_client } else { return; };
+		println!("[CIVKITD] - NOSTR: Client {} disconnected", client_id);
+
+		self.connection_tasks.remove(&client_id);
+		self.map_send.remove(&client_id);
+		self.subscriptions.retain(|_, sub| sub.get_client_id() != client_id);
+		self.rate_limiter.remove_client(client_id);
+		self.connection_limits.release(nostr_client.associated_socket.ip());
+		self.remove_pending_validation(client_id);
+
+		self.retry_credential_disconnections.push_back(ClientEvents::ClientDisconnected { client_id });
+		self.retry_db_disconnections.push_back(DbRequest::WriteClientDisconnect(nostr_client));
+		self.send_disconnections();
+	}
+
+	fn has_pending_disconnections(&self) -> bool {
+		!self.retry_db_disconnections.is_empty() || !self.retry_credential_disconnections.is_empty()
+	}
+
+	/// Sends the disconnections not yet delivered, the ones refused by a full
+	/// queue are kept for the next retry.
+	fn send_disconnections(&mut self) {
+		while let Some(db_request) = self.retry_db_disconnections.pop_front() {
+			match self.send_db_requests.try_send(db_request) {
+				Ok(()) => {},
+				Err(BusError::Full(db_request)) => {
+					self.retry_db_disconnections.push_front(db_request);
+					break;
+				},
+				Err(BusError::Closed(_)) => { self.retry_db_disconnections.clear(); },
+			}
+		}
+		while let Some(event) = self.retry_credential_disconnections.pop_front() {
+			match self.send_credential_events_handler.try_send(event) {
+				Ok(()) => {},
+				Err(BusError::Full(event)) => {
+					self.retry_credential_disconnections.push_front(event);
+					break;
+				},
+				Err(BusError::Closed(_)) => { self.retry_credential_disconnections.clear(); },
 			}
+		}
+	}
 
-			{
-				for ev in query_credential_gateway {
-					let mut send_credential_events_handler = self.send_credential_events_handler.lock();
-					send_credential_events_handler.await.send(ev);
+	/// Drops the events withheld for a disconnected subscriber. The events it
+	/// published are still released to the other subscribers once validated,
+	/// the NoteProcessor answers for the ones it drops with the publisher.
+	fn remove_pending_validation(&mut self, client_id: u64) {
+		self.pending_validation_counts.remove(&client_id);
+		let pending_validation_counts = &mut self.pending_validation_counts;
+		self.pending_validation_events.retain(|_, (publisher, events)| {
+			events.retain(|event| !matches!(event, ClientEvents::SubscribedEvent { client_id: subscriber, .. } if *subscriber == client_id));
+			if !events.is_empty() { return true; }
+			if let Some(count) = pending_validation_counts.get_mut(publisher) {
+				*count -= 1;
+				if *count == 0 {
+					pending_validation_counts.remove(publisher);
 				}
 			}
+			false
+		});
+	}
 
+	fn accept_client(&mut self, stream: TcpStream, addr: SocketAddr) {
+		self.rate_limiter.prune_idle_pubkeys(Instant::now());
+		if let Err(refusal) = self.connection_limits.try_admit(addr.ip()) {
+			println!("[CIVKITD] - NET: Refusing connection from {}: {:?}", addr, refusal);
+			tokio::spawn(async move {
+				refuse_connection(stream, addr, refusal).await;
+			});
+			return;
+		}
 
-			{
-				let mut pending_events_lock = self.pending_events.lock();
-				pending_events_lock.await.append(&mut new_pending_events);
+		self.clients_counter += 1;
+		let client_id = self.clients_counter;
+
+		let (outgoing_send, incoming_send) = mpsc::unbounded_channel::<Vec<u8>>();
+		let outgoing_receive = self.send_client_msg.clone();
+		let send_client_disconnect = self.send_client_disconnect.clone();
+		let connection_task = tokio::spawn(async move {
+			handle_connection(stream, addr, client_id, outgoing_receive, incoming_send).await;
+			let _ = send_client_disconnect.send(client_id).await;
+		});
+		self.connection_tasks.insert(client_id, connection_task);
+
+		let new_nostr_client = NostrClient::new(client_id as u64, addr);
+		let client_2 = new_nostr_client.clone();
+		let auth_challenge = new_nostr_client.auth_challenge.clone();
+		self.clients.insert(client_id, new_nostr_client);
+		self.map_send.insert(client_id, outgoing_send);
+
+		// We challenge the client to authenticate (NIP-42).
+		self.send_relay_message(client_id, RelayMessage::new_auth(auth_challenge));
+
+		let db_request = DbRequest::WriteClient(client_2);
+		let _ = self.send_db_requests.try_send(db_request);
+	}
+
+	/// Fans out a client message according to its types (event, subscription, close).
+	fn handle_client_message(&mut self, id: u64, msg: Vec<u8>) {
+		println!("[CIVKITD] - NOSTR: Message received from {}!", id);
+		let max_message_size = self.rate_limiter.max_message_size();
+		if max_message_size > 0 && msg.len() as u64 > max_message_size {
+			println!("[CIVKITD] - NOSTR: Dropping {} bytes message from {}", msg.len(), id);
+			let reason = RejectionReason::Invalid(format!("message larger than {} bytes", max_message_size));
+			self.send_relay_message(id, RelayMessage::new_notice(reason.to_string()));
+			return;
+		}
+		let msg_len = msg.len() as u64;
+		let msg_json = if let Ok(msg_json) = String::from_utf8(msg) { msg_json } else {
+			println!("[CIVKITD] - NOSTR: ClientMessage is not utf8");
+			self.send_relay_message(id, RelayMessage::new_notice(RejectionReason::Invalid("message is not utf8".to_string()).to_string()));
+			return;
+		};
+		if let Some((subscription_id, filters)) = parse_count_message(&msg_json) {
+			self.handle_count(id, msg_len, subscription_id, filters);
+			return;
+		}
+		let client_msg = match ClientMessage::from_json(&msg_json) {
+			Ok(client_msg) => client_msg,
+			Err(err) => {
+				println!("[CIVKITD] - NOSTR: ClientMessage deserialization failure: {}", err);
+				self.reject_malformed_message(id, &msg_json);
+				return;
+			},
+		};
+
+		let now = Instant::now();
+		let auth_pubkey = self.clients.get(&id).and_then(|client| client.pubkey);
+		if let Err(limited) = self.rate_limiter.check_bytes(id, auth_pubkey.as_ref(), msg_len, now) {
+			println!("[CIVKITD] - NOSTR: Rate limiting client {}: {}", id, limited);
+			let reason = RejectionReason::from(&limited);
+			match client_msg {
+				ClientMessage::Event(msg) => self.send_rejection(id, msg.id, reason),
+				ClientMessage::Req { subscription_id, .. } => self.send_closed(id, &subscription_id, &reason),
+				_ => self.send_relay_message(id, RelayMessage::new_notice(reason.to_string())),
 			}
+			return;
 		}
-	}
 
-	async fn filter_events(&mut self, event: Event) -> bool {
+		match client_msg {
+			ClientMessage::Event(msg) => {
+				// The event is charged before its id and signature are checked, the
+				// author is refunded if the event is forged.
+				if let Err(limited) = self.rate_limiter.check_event(id, &msg.pubkey, now) {
+					self.send_rejection(id, msg.id, RejectionReason::from(&limited));
+					return;
+				}
+				// We reject forged or corrupted events before any filtering or storage.
+				if let Err(err) = validate_event(&msg) {
+					self.rate_limiter.refund_author_event(&msg.pubkey);
+					self.send_rejection(id, msg.id, RejectionReason::Invalid(err.to_string()));
+					return;
+				}
+				let authenticated = self.clients.get(&id).map_or(false, |client| client.is_authenticated());
+				if let Err(reason) = self.admission.check_event(&msg, authenticated) {
+					self.send_rejection(id, msg.id, reason);
+					return;
+				}
+				let max_event_tags = self.rate_limiter.max_event_tags();
+				if max_event_tags > 0 && msg.tags.len() as u64 > max_event_tags {
+					self.send_rejection(id, msg.id, RejectionReason::Invalid(format!("more than {} tags", max_event_tags)));
+					return;
+				}
+				let msg_2 = msg.clone();
+				if is_credential(&msg_2) {
+					println!("[CIVKITD] - NOSTR: credential msg received");
+					let credential = ClientEvents::Credential { client_id: id, event: *msg_2.clone() };
+					if let Err(err) = self.send_credential_events_handler.try_send(credential) {
+						self.send_rejection(id, msg.id, bus_refusal(&err));
+					}
+					return;
+				}
+				if is_ephemeral(&msg_2) {
+					// Ephemeral events are not stored, they don't wait on any validation.
+					if self.admission.requires_credentials(&msg_2) {
+						self.send_rejection(id, msg.id, RejectionReason::Restricted("ephemeral events can't be paid with credentials".to_string()));
+						return;
+					}
+					if is_expired(&msg_2, Timestamp::now().as_u64()) {
+						self.send_rejection(id, msg.id, RejectionReason::Invalid("event has expired".to_string()));
+						return;
+					}
+					self.send_relay_message(id, ok_message(msg.id, None));
+					self.dispatch_ephemeral_event(*msg);
+				} else {
+					// We bound the events withheld for validation, and queued for storage, per client.
+					if self.pending_validation_counts.get(&id).cloned().unwrap_or(0) >= MAX_PENDING_DB_REQUEST_PER_CLIENT {
+						self.send_rejection(id, msg.id, RejectionReason::RateLimited("too many events pending validation".to_string()));
+						return;
+					}
+					// The events requiring credentials are staged until a service deliverance
+					// referencing their id is validated.
+					let db_request = DbRequest::WriteEvent { client_id: id, ev: *msg_2 };
+					if let Err(err) = self.send_db_requests.try_send(db_request) {
+						self.send_rejection(id, msg.id, bus_refusal(&err));
+						return;
+					}
+					//TODO: move filtering logic in its own thread - beware out of sync with credential validation due to event timing.
+					self.filter_events(id, *msg);
+				}
+			},
+			ClientMessage::Req { subscription_id, filters } => {
+				if let Err(limited) = self.rate_limiter.check_req(id, auth_pubkey.as_ref(), now) {
+					println!("[CIVKITD] - NOSTR: Rate limiting subscription from {}: {}", id, limited);
+					self.send_closed(id, &subscription_id, &RejectionReason::from(&limited));
+					return;
+				}
+				// A REQ reusing a subscription id replaces the subscription (NIP-01).
+				let (is_replacement, subscriptions_count) = if let Some(nostr_client) = self.clients.get(&id) {
+					(nostr_client.has_sub(&subscription_id), nostr_client.subscriptions.len() as u64)
+				} else { return; };
+				if !is_replacement && subscriptions_count >= MAX_SUBSCRIPTIONS {
+					println!("[CIVKITD] - NOSTR: subscription register failure for {}", id);
+					self.send_closed(id, &subscription_id, &RejectionReason::Blocked(format!("too many subscriptions, max {}", MAX_SUBSCRIPTIONS)));
+					return;
+				}
+				if let Some(reason) = self.search_refusal(&filters) {
+					self.send_closed(id, &subscription_id, &reason);
+					return;
+				}
+				let db_request = DbRequest::ReplayEvents { client_id: id, sub_id: subscription_id.clone(), filters: filters.clone() };
+				if let Err(err) = self.send_db_requests.try_send(db_request) {
+					self.send_closed(id, &subscription_id, &bus_refusal(&err));
+					return;
+				}
+				self.subscriptions_counter += 1;
+				let our_side_id = self.subscriptions_counter;
+				if let Some(nostr_client) = self.clients.get_mut(&id) {
+					nostr_client.add_sub(subscription_id.clone(), our_side_id, MAX_SUBSCRIPTIONS);
+				}
+				if is_replacement {
+					println!("[CIVKITD] - NOSTR: Replacing subscription {:?} of {}", subscription_id, id);
+				}
 
-		println!("[CIVKITD] - NOSTR: Apply filtering of the event on {} subscriptions with event kind {}", self.subscriptions.len(), event.kind.as_u32());
-		let mut match_result = false;
-		for (our_side_id, sub) in self.subscriptions.iter() {
-			let filters = sub.get_filters();
-			for filter in filters {
-				if let Some(ref kinds) = filter.kinds {
-					for kind in kinds.iter() {
-						if kind.as_u32() == event.kind.as_u32() {
-							match_result = true;
+				#[cfg(debug_assertions)] {
+					for filter in &filters {
+						if let Some(kinds) = &filter.kinds {
+							for kind in kinds {
+								 println!("[CIVKITD - NOSTR: Registering subscription for kind {}", kind.as_u32());
+							}
 						}
 					}
 				}
-			}
-			println!("[CIVKITD] - NOSTR: Matching result {}", match_result);
-			let mut clients_to_dispatch = Vec::new();
-			if match_result {
-				for (client_id, nostr_client) in self.clients.iter() {
-					if nostr_client.has_sub(*our_side_id) {
-						//TODO: fulfill with match subscription
-						let event_id = event.id.clone();
-						let associated_event = ClientEvents::SubscribedEvent { client_id: client_id.clone(), sub_id: SubscriptionId::generate(), event: event.clone() };
-						clients_to_dispatch.push((event_id, associated_event));
+				let nostr_sub = NostrSub::new(our_side_id, id, subscription_id.clone(), filters);
+				self.subscriptions.insert((id, subscription_id), nostr_sub);
+			},
+			ClientMessage::Auth(auth_event) => {
+				if let Some(nostr_client) = self.clients.get_mut(&id) {
+					let relay_url = &self.config.relay_info.url;
+					let relay_message = match verify_auth_event(&auth_event, &nostr_client.auth_challenge, relay_url, Timestamp::now()) {
+						Ok(pubkey) => {
+							println!("[CIVKITD] - NOSTR: Client {} authenticated as {}", id, pubkey);
+							nostr_client.authenticate(pubkey);
+							RelayMessage::new_ok(auth_event.id, true, String::new())
+						},
+						Err(err) => {
+							println!("[CIVKITD] - NOSTR: Client {} authentication failure: {}", id, err);
+							RejectionReason::Invalid(err.to_string()).ok_message(auth_event.id)
+						},
+					};
+					self.send_relay_message(id, relay_message);
+				}
+			},
+			ClientMessage::Close(subscription_id) => {
+				// A client can only close its own subscriptions.
+				if let Some(nostr_client) = self.clients.get_mut(&id) {
+					if let Some(our_side_id) = nostr_client.remove_sub(&subscription_id) {
+						println!("[CIVKITD] - NOSTR: Remove subscription id {}", our_side_id);
 					}
 				}
+				self.subscriptions.remove(&(id, subscription_id));
+			},
+			_ => { println!("[CIVKITD] - NOSTR: Unknown client message"); }
+		}
+	}
+
+	/// Requests the count of the stored events matching the filters (NIP-45),
+	/// rate limited as a subscription.
+	fn handle_count(&mut self, id: u64, msg_len: u64, subscription_id: SubscriptionId, filters: Vec<Filter>) {
+		let now = Instant::now();
+		let auth_pubkey = self.clients.get(&id).and_then(|client| client.pubkey);
+		let rate_limit = self.rate_limiter.check_bytes(id, auth_pubkey.as_ref(), msg_len, now)
+			.and_then(|_| self.rate_limiter.check_req(id, auth_pubkey.as_ref(), now));
+		if let Err(limited) = rate_limit {
+			println!("[CIVKITD] - NOSTR: Rate limiting count from {}: {}", id, limited);
+			self.send_closed(id, &subscription_id, &RejectionReason::from(&limited));
+			return;
+		}
+		if let Some(reason) = self.search_refusal(&filters) {
+			self.send_closed(id, &subscription_id, &reason);
+			return;
+		}
+		let db_request = DbRequest::CountEvents { client_id: id, sub_id: subscription_id.clone(), filters };
+		if let Err(err) = self.send_db_requests.try_send(db_request) {
+			self.send_closed(id, &subscription_id, &bus_refusal(&err));
+		}
+	}
+
+	fn filter_events(&mut self, publisher: u64, event: Event) -> bool {
+		let mut subscribed_events = self.subscribed_events(&event);
+		let match_result = !subscribed_events.is_empty();
+		if match_result {
+			println!("[CIVKITD] - NOSTR: Witholding {} events {} pending validation", subscribed_events.len(), event.id.to_hex());
+			let pending_events = self.pending_validation_events.entry(event.id).or_insert_with(|| {
+				*self.pending_validation_counts.entry(publisher).or_insert(0) += 1;
+				(publisher, Vec::new())
+			});
+			pending_events.1.append(&mut subscribed_events);
+		}
+		println!("[CIVKITD] - NOSTR: Matching result {}", match_result);
+
+		match_result
+	}
+
+	/// Sends an ephemeral event to the matching subscriptions right away, it is
+	/// neither stored nor validated.
+	fn dispatch_ephemeral_event(&self, event: Event) {
+		let subscribed_events = self.subscribed_events(&event);
+		println!("[CIVKITD] - NOSTR: Dispatching ephemeral event {} to {} subscriptions", event.id.to_hex(), subscribed_events.len());
+		self.dispatch_events(subscribed_events);
+	}
+
+	/// Returns the event addressed to each subscription matching it.
+	fn subscribed_events(&self, event: &Event) -> Vec<ClientEvents> {
+		println!("[CIVKITD] - NOSTR: Apply filtering of the event on {} subscriptions with event kind {}", self.subscriptions.len(), event.kind.as_u32());
+		let mut subscribed_events = Vec::new();
+		for ((client_id, sub_id), sub) in self.subscriptions.iter() {
+			if !match_filters(sub.get_compiled_filters(), event) { continue }
+			if self.clients.get(client_id).map_or(false, |nostr_client| nostr_client.has_sub(sub_id)) {
+				subscribed_events.push(ClientEvents::SubscribedEvent { client_id: *client_id, sub_id: sub.get_id().clone(), event: event.clone() });
 			}
+		}
+		subscribed_events
+	}
+
+	/// Returns the reason to refuse the filters if they search the events and
+	/// the search is not enabled.
+	fn search_refusal(&self, filters: &[Filter]) -> Option<RejectionReason> {
+		if !self.config.search.enabled && has_search(filters) {
+			return Some(RejectionReason::Restricted("search is not enabled on this relay".to_string()));
+		}
+		None
+	}
 
-			{
-				let mut pending_validation_events_lock = self.pending_validation_events.lock().await;
-				for ev in clients_to_dispatch {
-					println!("[CIVKITD] - NOSTR: Witholding one event {} pending validation", event.id.to_hex());
-					pending_validation_events_lock.insert(ev.0, ev.1);
+	/// Answers a message which is not a valid client message: an OK for an
+	/// event and a CLOSED for a subscription, when their id can be found, or
+	/// a NOTICE.
+	fn reject_malformed_message(&self, client_id: u64, msg_json: &str) {
+		let message: Option<Vec<Value>> = serde_json::from_str(msg_json).ok();
+		let message = message.unwrap_or_default();
+		let reason = RejectionReason::Invalid("malformed message".to_string());
+		match (message.get(0).and_then(|v| v.as_str()), message.get(1)) {
+			(Some("EVENT"), Some(event)) => {
+				if let Some(event_id) = event.get("id").and_then(|v| v.as_str()).and_then(|id| EventId::from_hex(id).ok()) {
+					self.send_relay_message(client_id, reason.ok_message(event_id));
+					return;
 				}
-			}
+			},
+			(Some("REQ") | Some("COUNT"), Some(Value::String(sub_id))) => {
+				self.send_closed(client_id, &SubscriptionId::new(sub_id), &reason);
+				return;
+			},
+			_ => {},
 		}
+		self.send_relay_message(client_id, RelayMessage::new_notice(reason.to_string()));
+	}
 
-		match_result
+	/// Returns the ids of the client subscriptions matching the event.
+	fn matching_subscriptions(&self, client_id: u64, event: &Event) -> Vec<SubscriptionId> {
+		let mut sub_ids = Vec::new();
+		for sub in self.subscriptions.values() {
+			if sub.get_client_id() == client_id && match_filters(sub.get_compiled_filters(), event) {
+				sub_ids.push(sub.get_id().clone());
+			}
+		}
+		sub_ids
 	}
 }
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs;
 use bitcoin::{Block, BlockHeader, Network};
 use serde::Serializer;
@@ -14,22 +15,71 @@ pub struct Config {
     pub logging: Logging,
     pub mainstay: Mainstay,
     pub bitcoind_params: BitcoindParams,
+    #[serde(default)]
+    pub relay_info: RelayInfo,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    #[serde(default)]
+    pub channels: Channels,
+    #[serde(default)]
+    pub search: Search,
+    #[serde(default)]
+    pub admission: Admission,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct Performance {
     pub max_db_size: i32,
+    /// Max age in seconds of the events served, 0 for no limit.
     pub max_event_age: i32,
+    /// Max number of events served, the oldest are pruned beyond. 0 for no limit.
+    #[serde(default)]
+    pub max_events: u64,
+    /// Max ages overriding `max_event_age`, by event kind.
+    #[serde(default)]
+    pub max_event_age_per_kind: BTreeMap<String, i32>,
+    /// Seconds between two prunings of the expired and old events.
+    #[serde(default = "default_retention_interval")]
+    pub retention_interval: u64,
 }
 
+fn default_retention_interval() -> u64 {
+    300
+}
+
+/// The anti-spam settings, merged with the `Admission` table in a single
+/// admission policy by kind.
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct SpamProtection {
+    /// Request the credentials priced in the `Admission` table, every kind is
+    /// free of credentials otherwise.
     pub requestcredentials: bool,
+    /// Require NIP-42 authentication before publishing orders (kind 32500).
+    #[serde(default)]
+    pub requireauthorders: bool,
+    /// Require NIP-42 authentication before publishing credential events.
+    #[serde(default)]
+    pub requireauthcredentials: bool,
+    /// Min NIP-13 proof-of-work difficulty of the events, 0 for none.
+    #[serde(default)]
+    pub min_pow_difficulty: u8,
+    /// Difficulties overriding `min_pow_difficulty`, by event kind.
+    #[serde(default)]
+    pub pow_difficulty_per_kind: BTreeMap<String, u8>,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct Connections {
     pub maxclientconnections: i32,
+    /// Max simultaneous client connections from one IP address, 0 for no limit.
+    #[serde(default)]
+    pub maxconnectionsperip: i32,
+    /// CIDR blocks clients must connect from, any address when empty.
+    #[serde(default)]
+    pub allowlist: Vec<String>,
+    /// CIDR blocks refused, even when matched by the allow list.
+    #[serde(default)]
+    pub denylist: Vec<String>,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
@@ -63,19 +113,163 @@ pub struct BitcoindParams {
     pub chain: bitcoin::Network,
 }
 
+/// The relay identity announced in the NIP-11 information document.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RelayInfo {
+    pub name: String,
+    pub description: String,
+    /// The hex-encoded public key of the relay administrator.
+    pub pubkey: String,
+    pub contact: String,
+    /// The public websocket URL of the relay, checked in NIP-42 authentication.
+    pub url: String,
+}
+
+impl Default for RelayInfo {
+    fn default() -> Self {
+        RelayInfo {
+            name: "civkitd".to_string(),
+            description: "A CivKit market relay".to_string(),
+            pubkey: String::new(),
+            contact: String::new(),
+            url: String::new(),
+        }
+    }
+}
+
+/// The token-bucket limits on client messages, each amount can be spent in a
+/// burst and refills over a minute. Zero disables a limit.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub events_per_minute: u64,
+    pub reqs_per_minute: u64,
+    pub bytes_per_minute: u64,
+    /// Limits for an event author, or the NIP-42 authenticated key of the client.
+    pub pubkey_events_per_minute: u64,
+    pub pubkey_reqs_per_minute: u64,
+    pub pubkey_bytes_per_minute: u64,
+    /// Max size in bytes of a client message.
+    pub max_message_size: u64,
+    /// Max number of tags of an event.
+    pub max_event_tags: u64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            events_per_minute: 120,
+            reqs_per_minute: 60,
+            bytes_per_minute: 1024 * 1024,
+            pubkey_events_per_minute: 120,
+            pubkey_reqs_per_minute: 60,
+            pubkey_bytes_per_minute: 1024 * 1024,
+            max_message_size: 128 * 1024,
+            max_event_tags: 2000,
+        }
+    }
+}
+
+/// The capacities of the queues between civkitd components.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Channels {
+    pub default_capacity: usize,
+    /// Capacities overriding the default, by queue name as reported by
+    /// `civkit-cli queuemetrics`.
+    pub capacities: BTreeMap<String, usize>,
+}
+
+impl Channels {
+    pub fn capacity(&self, name: &str) -> usize {
+        self.capacities.get(name).cloned().unwrap_or(self.default_capacity)
+    }
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Channels {
+            default_capacity: 1024,
+            capacities: BTreeMap::new(),
+        }
+    }
+}
+
+/// The NIP-50 full-text search of the stored events, disabled by default.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Search {
+    pub enabled: bool,
+    /// Max number of events returned for a search filter.
+    pub max_results: u64,
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Search {
+            enabled: false,
+            max_results: 100,
+        }
+    }
+}
+
+/// What a client must provide to publish an event of a kind.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(tag = "requirement", rename_all = "lowercase")]
+pub enum AdmissionRequirement {
+    Free,
+    /// A NIP-13 proof-of-work of the difficulty.
+    Pow { difficulty: u8 },
+    /// A number of staking credentials redeemed for the service.
+    Credentials { service_id: u64, credentials: u32 },
+    /// A NIP-42 authentication.
+    Auth,
+}
+
+/// The requirements to publish the events, by kind.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Admission {
+    /// The requirement of the kinds without an entry.
+    pub default: AdmissionRequirement,
+    pub kinds: BTreeMap<String, AdmissionRequirement>,
+}
+
+impl Default for Admission {
+    fn default() -> Self {
+        let mut kinds = BTreeMap::new();
+        kinds.insert("32500".to_string(), AdmissionRequirement::Credentials { service_id: 0, credentials: 1 });
+        Admission {
+            default: AdmissionRequirement::Free,
+            kinds,
+        }
+    }
+}
+
 // default config to fallback
 impl Default for Config {
     fn default() -> Self {
         Config {
             performance: Performance {
                 max_db_size: 10000,
-                max_event_age: 3600,
+                max_event_age: 0,
+                max_events: 0,
+                max_event_age_per_kind: BTreeMap::new(),
+                retention_interval: default_retention_interval(),
             },
             spam_protection: SpamProtection {
                 requestcredentials: true,
+                requireauthorders: false,
+                requireauthcredentials: false,
+                min_pow_difficulty: 0,
+                pow_difficulty_per_kind: BTreeMap::new(),
             },
             connections: Connections {
                 maxclientconnections: 100,
+                maxconnectionsperip: 10,
+                allowlist: Vec::new(),
+                denylist: Vec::new(),
             },
             civkitd: Civkitd {
                 network: "testnet".to_string(),
@@ -99,7 +293,12 @@ impl Default for Config {
 		rpc_user: "civkitd_client".to_string(),
 		rpc_password: "hello_world".to_string(),
         chain: bitcoin::Network::Testnet,
-	    }
+	    },
+            relay_info: RelayInfo::default(),
+            rate_limits: RateLimits::default(),
+            channels: Channels::default(),
+            search: Search::default(),
+            admission: Admission::default(),
         }
     }
 }
@@ -0,0 +1,209 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Admission control of the Nostr client connections: a global cap, a per-IP
+//! cap and allow/deny lists of CIDR blocks.
+
+use crate::config::Connections;
+use crate::rejection::RejectionReason;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of IP addresses in CIDR notation, e.g `10.0.0.0/8` or `::1/128`.
+/// A bare address is a block of this single address.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CidrBlock {
+	network: IpAddr,
+	prefix_len: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CidrParseError(pub String);
+
+impl fmt::Display for CidrParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "invalid CIDR block {}", self.0)
+	}
+}
+
+impl FromStr for CidrBlock {
+	type Err = CidrParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let err = || CidrParseError(s.to_string());
+		let (addr, prefix_len) = match s.split_once('/') {
+			Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+			None => (s, None),
+		};
+		let network = canonical_ip(addr.trim().parse::<IpAddr>().map_err(|_| err())?);
+		let max_len = if network.is_ipv4() { 32 } else { 128 };
+		let prefix_len = match prefix_len {
+			Some(prefix_len) => prefix_len.trim().parse::<u8>().map_err(|_| err())?,
+			None => max_len,
+		};
+		if prefix_len > max_len {
+			return Err(err());
+		}
+		Ok(CidrBlock { network, prefix_len })
+	}
+}
+
+impl CidrBlock {
+	pub fn contains(&self, ip: &IpAddr) -> bool {
+		match (self.network, canonical_ip(*ip)) {
+			(IpAddr::V4(network), IpAddr::V4(ip)) => {
+				prefix_matches(u32::from(network) as u128, u32::from(ip) as u128, 32, self.prefix_len)
+			},
+			(IpAddr::V6(network), IpAddr::V6(ip)) => {
+				prefix_matches(u128::from(network), u128::from(ip), 128, self.prefix_len)
+			},
+			_ => false,
+		}
+	}
+}
+
+fn prefix_matches(network: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+	if prefix_len == 0 { return true; }
+	let shift = bits - prefix_len;
+	(network >> shift) == (ip >> shift)
+}
+
+/// The IPv4 clients reach the dual-stack listener with IPv4-mapped IPv6
+/// addresses, we match them against the IPv4 blocks.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+	match ip {
+		IpAddr::V6(ipv6) => ipv6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+		_ => ip,
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConnectionRefusal {
+	/// The address matches the deny list.
+	Denied,
+	/// The allow list is set and the address does not match it.
+	NotAllowed,
+	/// The relay serves `maxclientconnections` clients already.
+	TooManyConnections,
+	/// The address has `maxconnectionsperip` connections already.
+	TooManyConnectionsFromIp,
+}
+
+impl From<&ConnectionRefusal> for RejectionReason {
+	fn from(refusal: &ConnectionRefusal) -> Self {
+		match refusal {
+			ConnectionRefusal::Denied => RejectionReason::Blocked("address denied by relay policy".to_string()),
+			ConnectionRefusal::NotAllowed => RejectionReason::Restricted("address not allowed by relay policy".to_string()),
+			ConnectionRefusal::TooManyConnections => RejectionReason::RateLimited("relay at max client connections".to_string()),
+			ConnectionRefusal::TooManyConnectionsFromIp => RejectionReason::RateLimited("too many connections from your address".to_string()),
+		}
+	}
+}
+
+impl fmt::Display for ConnectionRefusal {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", RejectionReason::from(self))
+	}
+}
+
+/// The current usage of the client connections, reported to the admin.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionUsage {
+	pub connections: u64,
+	pub max_connections: u64,
+	pub max_connections_per_ip: u64,
+	pub connections_per_ip: Vec<(IpAddr, u64)>,
+	pub refused_connections: u64,
+}
+
+pub struct ConnectionLimits {
+	/// Zero for no limit.
+	max_connections: u64,
+	/// Zero for no limit.
+	max_connections_per_ip: u64,
+	allowlist: Vec<CidrBlock>,
+	denylist: Vec<CidrBlock>,
+
+	connections_per_ip: HashMap<IpAddr, u64>,
+	connections: u64,
+	refused_connections: u64,
+}
+
+impl ConnectionLimits {
+	pub fn new(config: &Connections) -> Result<Self, CidrParseError> {
+		let parse_blocks = |blocks: &Vec<String>| blocks.iter().map(|block| block.parse::<CidrBlock>()).collect::<Result<Vec<CidrBlock>, CidrParseError>>();
+
+		Ok(ConnectionLimits {
+			max_connections: config.maxclientconnections.max(0) as u64,
+			max_connections_per_ip: config.maxconnectionsperip.max(0) as u64,
+			allowlist: parse_blocks(&config.allowlist)?,
+			denylist: parse_blocks(&config.denylist)?,
+
+			connections_per_ip: HashMap::new(),
+			connections: 0,
+			refused_connections: 0,
+		})
+	}
+
+	/// Counts a new connection from `ip` if the policy admits it.
+	pub fn try_admit(&mut self, ip: IpAddr) -> Result<(), ConnectionRefusal> {
+		let refusal = self.check(&canonical_ip(ip));
+		if let Err(refusal) = refusal {
+			self.refused_connections += 1;
+			return Err(refusal);
+		}
+		self.connections += 1;
+		*self.connections_per_ip.entry(canonical_ip(ip)).or_insert(0) += 1;
+		Ok(())
+	}
+
+	fn check(&self, ip: &IpAddr) -> Result<(), ConnectionRefusal> {
+		if self.denylist.iter().any(|block| block.contains(ip)) {
+			return Err(ConnectionRefusal::Denied);
+		}
+		if !self.allowlist.is_empty() && !self.allowlist.iter().any(|block| block.contains(ip)) {
+			return Err(ConnectionRefusal::NotAllowed);
+		}
+		if self.max_connections > 0 && self.connections >= self.max_connections {
+			return Err(ConnectionRefusal::TooManyConnections);
+		}
+		let ip_connections = self.connections_per_ip.get(ip).cloned().unwrap_or(0);
+		if self.max_connections_per_ip > 0 && ip_connections >= self.max_connections_per_ip {
+			return Err(ConnectionRefusal::TooManyConnectionsFromIp);
+		}
+		Ok(())
+	}
+
+	/// Releases a connection previously admitted from `ip`.
+	pub fn release(&mut self, ip: IpAddr) {
+		let ip = canonical_ip(ip);
+		if let Some(ip_connections) = self.connections_per_ip.get_mut(&ip) {
+			*ip_connections -= 1;
+			if *ip_connections == 0 {
+				self.connections_per_ip.remove(&ip);
+			}
+			self.connections -= 1;
+		}
+	}
+
+	pub fn usage(&self) -> ConnectionUsage {
+		let mut connections_per_ip: Vec<(IpAddr, u64)> = self.connections_per_ip.iter().map(|(ip, count)| (*ip, *count)).collect();
+		connections_per_ip.sort();
+		ConnectionUsage {
+			connections: self.connections,
+			max_connections: self.max_connections,
+			max_connections_per_ip: self.max_connections_per_ip,
+			connections_per_ip,
+			refused_connections: self.refused_connections,
+		}
+	}
+}
@@ -0,0 +1,75 @@
+use crate::config::Connections;
+use crate::connectionlimits::{CidrBlock, ConnectionLimits, ConnectionRefusal};
+
+use std::net::IpAddr;
+
+fn ip(s: &str) -> IpAddr {
+	s.parse().unwrap()
+}
+
+fn connections(max: i32, max_per_ip: i32, allowlist: &[&str], denylist: &[&str]) -> Connections {
+	Connections {
+		maxclientconnections: max,
+		maxconnectionsperip: max_per_ip,
+		allowlist: allowlist.iter().map(|block| block.to_string()).collect(),
+		denylist: denylist.iter().map(|block| block.to_string()).collect(),
+	}
+}
+
+#[test]
+fn test_cidr_block_parsing_and_matching() {
+	let block: CidrBlock = "10.1.0.0/16".parse().unwrap();
+	assert!(block.contains(&ip("10.1.200.3")));
+	assert!(!block.contains(&ip("10.2.0.1")));
+	assert!(!block.contains(&ip("::1")));
+
+	// IPv4-mapped addresses from the dual-stack listener match IPv4 blocks.
+	assert!(block.contains(&ip("::ffff:10.1.0.7")));
+
+	let block: CidrBlock = "2001:db8::/32".parse().unwrap();
+	assert!(block.contains(&ip("2001:db8:ffff::1")));
+	assert!(!block.contains(&ip("2001:db9::1")));
+
+	let single: CidrBlock = "::1".parse().unwrap();
+	assert!(single.contains(&ip("::1")));
+	assert!(!single.contains(&ip("::2")));
+
+	let any: CidrBlock = "0.0.0.0/0".parse().unwrap();
+	assert!(any.contains(&ip("192.0.2.1")));
+
+	assert!("10.0.0.0/33".parse::<CidrBlock>().is_err());
+	assert!("::/129".parse::<CidrBlock>().is_err());
+	assert!("not-an-ip/8".parse::<CidrBlock>().is_err());
+	assert!(ConnectionLimits::new(&connections(10, 0, &["10.0.0.0/x"], &[])).is_err());
+}
+
+#[test]
+fn test_global_and_per_ip_caps() {
+	let mut limits = ConnectionLimits::new(&connections(3, 2, &[], &[])).unwrap();
+
+	assert_eq!(limits.try_admit(ip("::1")), Ok(()));
+	assert_eq!(limits.try_admit(ip("::1")), Ok(()));
+	assert_eq!(limits.try_admit(ip("::1")), Err(ConnectionRefusal::TooManyConnectionsFromIp));
+	assert_eq!(limits.try_admit(ip("::2")), Ok(()));
+	assert_eq!(limits.try_admit(ip("::3")), Err(ConnectionRefusal::TooManyConnections));
+
+	let usage = limits.usage();
+	assert_eq!(usage.connections, 3);
+	assert_eq!(usage.connections_per_ip, vec![(ip("::1"), 2), (ip("::2"), 1)]);
+	assert_eq!(usage.refused_connections, 2);
+
+	// Released slots are available again.
+	limits.release(ip("::1"));
+	assert_eq!(limits.try_admit(ip("::3")), Ok(()));
+	assert_eq!(limits.usage().connections, 3);
+}
+
+#[test]
+fn test_allow_and_deny_lists() {
+	let mut limits = ConnectionLimits::new(&connections(0, 0, &["10.0.0.0/8", "::1"], &["10.6.6.0/24"])).unwrap();
+
+	assert_eq!(limits.try_admit(ip("10.1.2.3")), Ok(()));
+	assert_eq!(limits.try_admit(ip("::1")), Ok(()));
+	assert_eq!(limits.try_admit(ip("10.6.6.6")), Err(ConnectionRefusal::Denied));
+	assert_eq!(limits.try_admit(ip("192.0.2.1")), Err(ConnectionRefusal::NotAllowed));
+}
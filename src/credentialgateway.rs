@@ -19,7 +19,7 @@ use bitcoin::secp256k1::{PublicKey, SecretKey, Secp256k1};
 use bitcoin::secp256k1::rand::thread_rng;
 use bitcoin::secp256k1;
 
-use nostr::{Event, EventBuilder, Keys, Kind, Tag, TagKind};
+use nostr::{Event, EventBuilder, EventId, Keys, Kind, Tag, TagKind};
 
 use staking_credentials::common::msgs::{AssetProofFeatures, CredentialsFeatures, CredentialPolicy, Encodable, ServicePolicy};
 use staking_credentials::common::utils::Proof;
@@ -30,12 +30,14 @@ use staking_credentials::redemption::redemption::RedemptionEngine;
 use staking_credentials::common::msgs::{CredentialAuthenticationResult, CredentialAuthenticationPayload, Decodable, ServiceDeliveranceRequest, ServiceDeliveranceResult, FromHex, ToHex};
 use staking_credentials::common::utils::Credentials;
 
+use crate::admission::AdmissionPolicy;
+use crate::config::Config;
 use crate::events::ClientEvents;
+use crate::rejection::RejectionReason;
 use crate::bitcoind_client::{BitcoindClient, BitcoindRequest, BitcoindResult};
+use crate::shutdown::ShutdownSignal;
 
-use tokio::time::{sleep, Duration};
-use tokio::sync::mpsc;
-use tokio::sync::Mutex;
+use crate::bus::{BusReceiver, BusSender};
 
 use std::collections::HashMap;
 use std::ops::Deref;
@@ -49,7 +51,8 @@ struct GatewayConfig {
 
 	//supported_credentials_features: CredentialsFeatures
 
-	/// The number of elements of the credentials cache - Default data struct Merkle Tree
+	/// The number of spent credentials remembered to refuse their redemption
+	/// again, the oldest are forgotten first.
 	credentials_consumed_cache_size: u32,
 }
 
@@ -92,6 +95,17 @@ enum IssuanceError {
 	SignatureError,
 }
 
+impl From<IssuanceError> for RejectionReason {
+	fn from(err: IssuanceError) -> Self {
+		match err {
+			IssuanceError::InvalidDataCarrier => RejectionReason::Invalid("credential event must carry one credential tag".to_string()),
+			IssuanceError::Parse => RejectionReason::Invalid("undecodable credential message".to_string()),
+			IssuanceError::Policy => RejectionReason::Restricted(format!("more than {} credentials requested", MAX_CREDENTIALS_PER_REQUEST)),
+			IssuanceError::SignatureError => RejectionReason::Error("credentials signing failure".to_string()),
+		}
+	}
+}
+
 const MAX_CREDENTIALS_PER_REQUEST: usize = 100;
 
 //TODO: protect denial-of-service from client id requests congestion rate
@@ -159,6 +173,12 @@ impl IssuanceManager {
 		}
 		Err(IssuanceError::SignatureError)
 	}
+	/// Drops the requests of a disconnected client, there is no one to
+	/// deliver the signed credentials to.
+	fn remove_client_requests(&mut self, client_id: u64) {
+		self.table_signing_requests.retain(|_, request| request.client_id != client_id);
+	}
+
 	fn get_client_id(&self, request_id: u64) -> u64 {
 		if let Some(issuance_request) = self.table_signing_requests.get(&request_id) {
 			issuance_request.client_id
@@ -173,12 +193,24 @@ enum RedemptionError {
 	EventGenerationError,
 }
 
+impl From<RedemptionError> for RejectionReason {
+	fn from(err: RedemptionError) -> Self {
+		match err {
+			RedemptionError::Parse => RejectionReason::Invalid("undecodable service deliverance request".to_string()),
+			RedemptionError::BadLength => RejectionReason::Invalid("credentials and signatures count mismatch".to_string()),
+			RedemptionError::EventGenerationError => RejectionReason::Error("service deliverance result failure".to_string()),
+		}
+	}
+}
+
 struct RedemptionManager {
 	redemption_engine: RedemptionEngine,
 }
 
 impl RedemptionManager {
-	fn validate_service_deliverance(&mut self, client_id: u64, deliverance_id: u64, credential_msg_bytes: Vec<u8>, secret_key: &SecretKey) -> Result<(bool, u64, Event), RedemptionError> {
+	/// Verifies the credentials redeemed are signed by us. Returns the result
+	/// and the decoded service deliverance.
+	fn validate_service_deliverance(&mut self, credential_msg_bytes: Vec<u8>, secret_key: &SecretKey) -> Result<(bool, ServiceDeliveranceRequest), RedemptionError> {
 
 		let secp_ctx = Secp256k1::new();
 
@@ -192,20 +224,32 @@ impl RedemptionManager {
 		if service_deliverance.credentials.len() != service_deliverance.signatures.len() { return Err(RedemptionError::BadLength) }
 
 
-		let mut ret = false;
+		// Every credential redeemed must be signed.
+		let mut ret = !service_deliverance.credentials.is_empty();
 		for signed_credentials in service_deliverance.credentials.iter().zip(service_deliverance.signatures.iter()) {
 			let credential_bytes = signed_credentials.0.serialize();
 
 			if let Ok(msg) = secp256k1::Message::from_slice(&credential_bytes[..]) {
 				//TODO: verify where the signatures are breaking at generation, client reception or constitution of deliverance message
-				ret = secp_ctx.verify_ecdsa(&msg, &signed_credentials.1, &pubkey).is_ok();
-				println!("[CIVKITD] - CREDENTIAL: ecdsa verification {}", ret);
-			} // TODO: return an error here
+				let verified = secp_ctx.verify_ecdsa(&msg, &signed_credentials.1, &pubkey).is_ok();
+				println!("[CIVKITD] - CREDENTIAL: ecdsa verification {}", verified);
+				ret &= verified;
+			} else { ret = false; }
 		}
 
-		let service_id = service_deliverance.service_id;
+		Ok((ret, service_deliverance))
+	}
 
-		let mut service_deliverance_result = ServiceDeliveranceResult::new(service_id, ret);
+	/// The serialized credentials of the service deliverance, the keys of the
+	/// spent credentials.
+	fn spent_credentials(service_deliverance: &ServiceDeliveranceRequest) -> Vec<Vec<u8>> {
+		service_deliverance.credentials.iter().map(|credential| credential.serialize()).collect()
+	}
+
+	/// The event carrying the result of the service deliverance back to the
+	/// client.
+	fn service_deliverance_result(service_deliverance: &ServiceDeliveranceRequest, result: bool) -> Result<Event, RedemptionError> {
+		let mut service_deliverance_result = ServiceDeliveranceResult::new(service_deliverance.service_id, result);
 
 		let mut buffer = vec![];
 		service_deliverance_result.encode(&mut buffer);
@@ -217,12 +261,23 @@ impl RedemptionManager {
 		let server_event_keys = Keys::generate();
 
 		if let Ok(credential_carrier) = EventBuilder::new_text_note("", tags).to_event(&server_event_keys) {
-			return Ok((ret, deliverance_id, credential_carrier));
+			return Ok(credential_carrier);
 		}
 		Err(RedemptionError::EventGenerationError)
 	}
 }
 
+/// The id of the event paid by a service deliverance, referenced by its single
+/// `e` tag.
+fn paid_event_id(ev: &Event) -> Option<EventId> {
+	let referenced_ids: Vec<EventId> = ev.tags.iter()
+		.map(|tag| tag.as_vec())
+		.filter(|tag| tag.len() >= 2 && tag[0] == "e")
+		.filter_map(|tag| EventId::from_hex(&tag[1]).ok())
+		.collect();
+	if referenced_ids.len() == 1 { Some(referenced_ids[0]) } else { None }
+}
+
 #[derive(Clone)]
 struct Service {
 	credential_policy: CredentialPolicy,
@@ -239,14 +294,14 @@ pub struct CredentialGateway {
 
 	secp_ctx: Secp256k1<secp256k1::All>,
 
-	receive_credential_event_gateway: Mutex<mpsc::UnboundedReceiver<ClientEvents>>,
-	send_credential_events_gateway: Mutex<mpsc::UnboundedSender<ClientEvents>>,
+	receive_credential_event_gateway: BusReceiver<ClientEvents>,
+	send_credential_events_gateway: BusSender<ClientEvents>,
 
-	send_bitcoind_request_gateway: Mutex<mpsc::UnboundedSender<BitcoindRequest>>,
-	receive_bitcoind_result_handler: Mutex<mpsc::UnboundedReceiver<BitcoindResult>>,
+	send_bitcoind_request_gateway: BusSender<BitcoindRequest>,
+	receive_bitcoind_result_handler: BusReceiver<BitcoindResult>,
 
-	receive_events_gateway: Mutex<mpsc::UnboundedReceiver<ClientEvents>>,
-	send_validation_result_gateway: Mutex<mpsc::UnboundedSender<ClientEvents>>,
+	receive_events_gateway: BusReceiver<ClientEvents>,
+	send_validation_result_gateway: BusSender<ClientEvents>,
 
 	issuance_manager: IssuanceManager,
 	redemption_manager: RedemptionManager,
@@ -256,10 +311,18 @@ pub struct CredentialGateway {
 	hosted_services: HashMap<PublicKey, Service>,
 
 	chain_height: u64,
+
+	admission: AdmissionPolicy,
+
+	/// The outcomes of the redemptions, by the NoteProcessor.
+	receive_redemption_results: BusReceiver<ClientEvents>,
+	/// The service deliverances waiting for the outcome of their redemption,
+	/// by deliverance event id.
+	pending_deliverances: HashMap<EventId, (u64, ServiceDeliveranceRequest)>,
 }
 
 impl CredentialGateway {
-	pub fn new(receive_credential_event_gateway: mpsc::UnboundedReceiver<ClientEvents>, send_credential_events_gateway: mpsc::UnboundedSender<ClientEvents>, send_bitcoind_request_gateway: mpsc::UnboundedSender<BitcoindRequest>, receive_bitcoind_result_gateway: mpsc::UnboundedReceiver<BitcoindResult>, receive_events_gateway: mpsc::UnboundedReceiver<ClientEvents>, send_validation_result_gateway: mpsc::UnboundedSender<ClientEvents>) -> Self {
+	pub fn new(receive_credential_event_gateway: BusReceiver<ClientEvents>, send_credential_events_gateway: BusSender<ClientEvents>, send_bitcoind_request_gateway: BusSender<BitcoindRequest>, receive_bitcoind_result_gateway: BusReceiver<BitcoindResult>, receive_events_gateway: BusReceiver<ClientEvents>, send_validation_result_gateway: BusSender<ClientEvents>, receive_redemption_results: BusReceiver<ClientEvents>, our_config: Config) -> Self {
 		let bitcoind_client = BitcoindClient::new(String::new(), "0".to_string(), String::new(), String::new());
 		let secp_ctx = Secp256k1::new();
 
@@ -303,30 +366,35 @@ impl CredentialGateway {
 			genesis_hash: genesis_block(Network::Testnet).header.block_hash(),
 			default_config: GatewayConfig::default(),
 			secp_ctx,
-			receive_credential_event_gateway: Mutex::new(receive_credential_event_gateway),
-			send_credential_events_gateway: Mutex::new(send_credential_events_gateway),
-			send_bitcoind_request_gateway: Mutex::new(send_bitcoind_request_gateway),
-			receive_bitcoind_result_handler: Mutex::new(receive_bitcoind_result_gateway),
-			receive_events_gateway: Mutex::new(receive_events_gateway),
-			send_validation_result_gateway: Mutex::new(send_validation_result_gateway),
+			receive_credential_event_gateway,
+			send_credential_events_gateway,
+			send_bitcoind_request_gateway,
+			receive_bitcoind_result_handler: receive_bitcoind_result_gateway,
+			receive_events_gateway,
+			send_validation_result_gateway,
 			issuance_manager: issuance_manager,
 			redemption_manager: redemption_manager,
 			sec_key: secret_key,
 			hosted_services: hosted_services,
 			chain_height: 0,
+			admission: AdmissionPolicy::new(&our_config),
+			receive_redemption_results,
+			pending_deliverances: HashMap::new(),
 		}
 	}
 
-	fn get_credential_bytes_and_type(&self, ev: Event) -> Result<(u8, Vec<u8>), IssuanceError> {
-		if ev.tags.len() != 1 {
+	fn get_credential_bytes_and_type(&self, ev: &Event) -> Result<(u8, Vec<u8>), IssuanceError> {
+		let credential_tags: Vec<&String> = ev.tags.iter().filter_map(|tag| match tag {
+			Tag::Credential(credential) => Some(credential),
+			_ => None,
+		}).collect();
+		if credential_tags.len() != 1 {
 			return Err(IssuanceError::InvalidDataCarrier);
 		}
-		let credential_hex = match &ev.tags[0] {
-			Tag::Credential(credential) => { credential },
-			_ => { return Err(IssuanceError::InvalidDataCarrier); },
-		};
-		let credential_msg_bytes = Vec::from_hex(&credential_hex).unwrap();
-		Ok((credential_msg_bytes[0], credential_msg_bytes))
+		let credential_hex = credential_tags[0];
+		let credential_msg_bytes: Vec<u8> = Vec::from_hex(&credential_hex).map_err(|_| IssuanceError::Parse)?;
+		let credential_type = *credential_msg_bytes.first().ok_or(IssuanceError::Parse)?;
+		Ok((credential_type, credential_msg_bytes))
 	}
 
 	fn get_new_service_announcement(&self, since: u64) -> Vec<Service> {
@@ -340,132 +408,189 @@ impl CredentialGateway {
 		to_be_announced_services
 	}
 
-	pub async fn run(&mut self) {
+	pub async fn run(&mut self, mut shutdown: ShutdownSignal) {
 		loop {
-			sleep(Duration::from_millis(1000)).await;
-
-			let mut credential_queue = Vec::new();
-			{
-				let mut receive_credential_event_gateway_lock = self.receive_credential_event_gateway.lock();
-				if let Ok(credential_event) = receive_credential_event_gateway_lock.await.try_recv() {
+			tokio::select! {
+				_ = shutdown.recv() => {
+					println!("[CIVKITD] - CONTROL: CredentialGateway shutting down");
+					break;
+				},
+				Some(credential_event) = self.receive_credential_event_gateway.recv() => {
 					println!("[CIVKITD] - CREDENTIAL: credential received for processing");
-					credential_queue.push(credential_event);
-				}
+					self.handle_credential_event(credential_event).await;
+				},
+				Some(bitcoind_result) = self.receive_bitcoind_result_handler.recv() => {
+					self.handle_bitcoind_result(bitcoind_result).await;
+				},
+				Some(service_registration) = self.receive_events_gateway.recv() => {
+					println!("[CIVKITD] - CREDENTIAL: service registration received for processing");
+					self.handle_service_registration(service_registration).await;
+				},
+				Some(redemption_result) = self.receive_redemption_results.recv() => {
+					self.handle_redemption_result(redemption_result).await;
+				},
+				else => { break; }
 			}
+		}
+	}
 
-			let mut proofs_to_verify = Vec::new();
-			let mut redemption_result = Vec::new();
-			//TODO: change serialization of credential message from bytes payload to encompass ServiceDelivereRequest.
-			//let mut deliverance_result_queue = Vec::new();
-			for event in credential_queue {
-				match event {
-					ClientEvents::Credential { client_id, deliverance_id, event } => {
-						if let Ok((credential_type, credential_msg_bytes)) = self.get_credential_bytes_and_type(event) {
-							match credential_type {
-								//TODO: decode and check the exact credential requested from client
-								0 => {
-									match self.issuance_manager.register_authentication_request(client_id, credential_msg_bytes) {
-										Ok(proof) => {
-											println!("[CIVKITD] - CREDENTIAL: adding a merkle block proof to verify");
-											proofs_to_verify.push(proof);
-										},
-										Err(error) => {
-											println!("[CIVKITD] - CREDENTIAL: authentication request error {:?}", error);
-										}
-									}
-								},
-								1 => { println!("[CIVKITD] - CREDENTIAL event error: gateway should not receive CredentialAuthenticationResult"); },
-								2 => {
-									match self.redemption_manager.validate_service_deliverance(client_id, deliverance_id, credential_msg_bytes, &self.sec_key) {
-										Ok(result) => {
-											println!("[CIVKITD] - CREDENTIAL: service deliverance validation result");
-											//TODO: return ServiceDeliveranceResult to original client.
-											if result.0 {
-												redemption_result.push((client_id, (result.1, result.2)));
-											}
-										},
-										Err(error) => {
-											println!("[CIVKITD - CREDENTIAL: authentication request error {:?}", error);
-										}
-									}
-								},
-								3 => { println!("[CIVKITD] - CREDENTIAL event error: gateway should not receive ServiceDeliveranceResult"); },
-								_ => { println!("[CIVKITD] - CREDENTIAL: credential event error: unknown type"); }
+	async fn handle_credential_event(&mut self, event: ClientEvents) {
+		//TODO: change serialization of credential message from bytes payload to encompass ServiceDelivereRequest.
+		match event {
+			ClientEvents::Credential { client_id, event } => {
+				let event_id = event.id;
+				let (credential_type, credential_msg_bytes) = match self.get_credential_bytes_and_type(&event) {
+					Ok(credential) => credential,
+					Err(error) => {
+						println!("[CIVKITD] - CREDENTIAL event error: invalid data carrier");
+						self.reject_credential(client_id, event_id, error.into()).await;
+						return;
+					},
+				};
+				match credential_type {
+					//TODO: decode and check the exact credential requested from client
+					0 => {
+						match self.issuance_manager.register_authentication_request(client_id, credential_msg_bytes) {
+							Ok((request_id, proof)) => {
+								println!("[CIVKITD] - CREDENTIAL: adding a merkle block proof to verify");
+								println!("[CIVKITD] - CREDENTIAL: credential check merkle proof");
+								if let Err(err) = self.send_bitcoind_request_gateway.send(BitcoindRequest::CheckMerkleProof { request_id, proof }).await {
+									println!("[CIVKITD] - CREDENTIAL: merkle proof check refused: {}", err);
+									self.reject_credential(client_id, event_id, RejectionReason::RateLimited("credential gateway overloaded, retry later".to_string())).await;
+								}
+							},
+							Err(error) => {
+								println!("[CIVKITD] - CREDENTIAL: authentication request error {:?}", error);
+								self.reject_credential(client_id, event_id, error.into()).await;
 							}
-						} else { println!("[CIVKITD] - CREDENTIAL event error: invalid data carrier"); }
+						}
 					},
-					_ => {},
-				}
-			}
-
-			for (request_id, proof) in proofs_to_verify {
-				let mut send_bitcoind_request_lock = self.send_bitcoind_request_gateway.lock();
-				println!("[CIVKITD] - CREDENTIAL: credential check merkle proof");
-				send_bitcoind_request_lock.await.send(BitcoindRequest::CheckMerkleProof { request_id, proof });
-			}
-
-			let mut validated_requests = Vec::new();
-			{
-				let mut receive_bitcoind_result_handler_lock = self.receive_bitcoind_result_handler.lock();
-				if let Ok(bitcoind_result) = receive_bitcoind_result_handler_lock.await.try_recv() {
-					match bitcoind_result {
-						BitcoindResult::ProofValid { request_id, valid } => {
-							validated_requests.push((request_id, valid));
-						},
-						_ => { println!("[CIVKITD] - CREDENTIAL: uncorrect Bitcoin backend result"); },
+					1 => {
+						println!("[CIVKITD] - CREDENTIAL event error: gateway should not receive CredentialAuthenticationResult");
+						self.reject_credential(client_id, event_id, RejectionReason::Invalid("credential authentication result is relay-issued".to_string())).await;
+					},
+					2 => {
+						// The service deliverance pays for the publication of the event it references.
+						let paid_event_id = if let Some(paid_event_id) = paid_event_id(&event) { paid_event_id } else {
+							self.reject_credential(client_id, event_id, RejectionReason::Invalid("service deliverance must reference one paid event".to_string())).await;
+							return;
+						};
+						let service_deliverance = match self.redemption_manager.validate_service_deliverance(credential_msg_bytes, &self.sec_key) {
+							Ok((true, service_deliverance)) => service_deliverance,
+							Ok((false, _)) => {
+								self.reject_credential(client_id, event_id, RejectionReason::Invalid("credentials signatures do not verify".to_string())).await;
+								return;
+							},
+							Err(error) => {
+								println!("[CIVKITD - CREDENTIAL: authentication request error {:?}", error);
+								self.reject_credential(client_id, event_id, error.into()).await;
+								return;
+							}
+						};
+						println!("[CIVKITD] - CREDENTIAL: service deliverance validation result");
+						let service_id = service_deliverance.service_id as u64;
+						if !self.admission.accepts_service(service_id) {
+							self.reject_credential(client_id, event_id, RejectionReason::Restricted(format!("no event requires credentials of service {}", service_id))).await;
+							return;
+						}
+						if self.pending_deliverances.contains_key(&event_id) {
+							self.reject_credential(client_id, event_id, RejectionReason::Duplicate("service deliverance already pending".to_string())).await;
+							return;
+						}
+						// The credentials are spent by the NoteProcessor with the paid event, once the
+						// payment is accepted. The client is answered with the outcome.
+						println!("[CIVKITD] - CREDENTIAL: forward validation result for DB write");
+						let credentials = RedemptionManager::spent_credentials(&service_deliverance);
+						let max_spent = self.default_config.credentials_consumed_cache_size as u64;
+						let validation_result = ClientEvents::ValidationResult { client_id, payment_id: event_id, event_id: paid_event_id, service_id, credentials, max_spent };
+						if let Err(err) = self.send_validation_result_gateway.send(validation_result).await {
+							println!("[CIVKITD] - CREDENTIAL: validation result refused: {}", err);
+							self.reject_credential(client_id, event_id, RejectionReason::RateLimited("credential gateway overloaded, retry later".to_string())).await;
+							return;
+						}
+						self.pending_deliverances.insert(event_id, (client_id, service_deliverance));
+					},
+					3 => {
+						println!("[CIVKITD] - CREDENTIAL event error: gateway should not receive ServiceDeliveranceResult");
+						self.reject_credential(client_id, event_id, RejectionReason::Invalid("service deliverance result is relay-issued".to_string())).await;
+					},
+					_ => {
+						println!("[CIVKITD] - CREDENTIAL: credential event error: unknown type");
+						self.reject_credential(client_id, event_id, RejectionReason::Invalid(format!("unknown credential message type {}", credential_type))).await;
 					}
 				}
-			}
+			},
+			ClientEvents::ClientDisconnected { client_id } => {
+				self.issuance_manager.remove_client_requests(client_id);
+				self.pending_deliverances.retain(|_, (payer, _)| *payer != client_id);
+			},
+			_ => {},
+		}
+	}
 
-			let mut authentication_result_queue = Vec::new();
-			for (request_id, validation_result) in validated_requests {
-				if let Ok(result) = self.issuance_manager.validate_authentication_request(request_id, validation_result, self.sec_key) {
-					let client_id = self.issuance_manager.get_client_id(request_id);
-					authentication_result_queue.push((client_id, result));
-				}
-			}
+	/// Answers the service deliverance with the outcome of its redemption.
+	async fn handle_redemption_result(&mut self, redemption_result: ClientEvents) {
+		let (payment_id, reason) = match redemption_result {
+			ClientEvents::RedemptionResult { payment_id, reason, .. } => (payment_id, reason),
+			_ => { return; }
+		};
+		// The payer may be gone.
+		let (client_id, service_deliverance) = if let Some(pending) = self.pending_deliverances.remove(&payment_id) { pending } else { return; };
+		let redeemed = reason.is_none();
+		println!("[CIVKITD] - CREDENTIAL: service deliverance {} redeemed {}", payment_id.to_hex(), redeemed);
+		let ok_event = ClientEvents::OkEvent { client_id, event_id: payment_id, reason };
+		if let Err(err) = self.send_credential_events_gateway.send(ok_event).await {
+			println!("[CIVKITD] - CREDENTIAL: redemption outcome lost: {}", err);
+		}
+		self.send_deliverance_result(client_id, &service_deliverance, redeemed).await;
+	}
 
-			{
-				for (client_id, event) in authentication_result_queue {
-					let mut send_credential_lock = self.send_credential_events_gateway.lock();
-					send_credential_lock.await.send(ClientEvents::Credential { client_id, deliverance_id: 0, event: event });
-				}
-			}
+	/// Tells the client its credential event is refused.
+	async fn reject_credential(&mut self, client_id: u64, event_id: EventId, reason: RejectionReason) {
+		let rejected_event = ClientEvents::OkEvent { client_id, event_id, reason: Some(reason) };
+		if let Err(err) = self.send_credential_events_gateway.send(rejected_event).await {
+			println!("[CIVKITD] - CREDENTIAL: credential rejection lost: {}", err);
+		}
+	}
 
-			{
-				for (client_id, result) in redemption_result {
-					println!("[CIVKITD] - CREDENTIAL: forward validation result for DB write");
-					let mut send_validation_result_gateway_lock = self.send_validation_result_gateway.lock();
-					send_validation_result_gateway_lock.await.send(ClientEvents::Credential { client_id, deliverance_id: result.0, event: result.1 });
+	/// Sends the result of the service deliverance to the client.
+	async fn send_deliverance_result(&mut self, client_id: u64, service_deliverance: &ServiceDeliveranceRequest, result: bool) {
+		match RedemptionManager::service_deliverance_result(service_deliverance, result) {
+			Ok(result_event) => {
+				if let Err(err) = self.send_credential_events_gateway.send(ClientEvents::Credential { client_id, event: result_event }).await {
+					println!("[CIVKITD] - CREDENTIAL: service deliverance result lost: {}", err);
 				}
-			}
+			},
+			Err(error) => println!("[CIVKITD] - CREDENTIAL: service deliverance result error {:?}", error),
+		}
+	}
 
-			let mut service_registration_request = Vec::new();
-			{
-				let mut receive_events_gateway_lock = self.receive_events_gateway.lock();
-				if let Ok(service_registration) = receive_events_gateway_lock.await.try_recv() {
-					println!("[CIVKITD] - CREDENTIAL: service registration received for processing");
-					service_registration_request.push(service_registration);
-				}
-			}
+	async fn handle_bitcoind_result(&mut self, bitcoind_result: BitcoindResult) {
+		let (request_id, validation_result) = match bitcoind_result {
+			BitcoindResult::ProofValid { request_id, valid } => (request_id, valid),
+			_ => { println!("[CIVKITD] - CREDENTIAL: uncorrect Bitcoin backend result"); return; },
+		};
 
-			// We register civkit services hosted by this credential gateway
-			for service in service_registration_request {
-				match service {
-					ClientEvents::ServiceRegistration { pubkey, credential_policy, service_policy } => {
-						self.hosted_services.insert(pubkey, Service { credential_policy, service_policy, registration_height: self.chain_height });
-					},
-					_ => { }
-				}
+		if let Ok(result) = self.issuance_manager.validate_authentication_request(request_id, validation_result, self.sec_key) {
+			let client_id = self.issuance_manager.get_client_id(request_id);
+			if let Err(err) = self.send_credential_events_gateway.send(ClientEvents::Credential { client_id, event: result }).await {
+				println!("[CIVKITD] - CREDENTIAL: credential lost: {}", err);
 			}
+		}
+	}
 
-			let services_to_be_announced = self.get_new_service_announcement(0); //TODO: put this on a timer and filter what is already announced ?
-			{
-				for service in services_to_be_announced {
-					let mut send_credential_lock = self.send_credential_events_gateway.lock();
-					send_credential_lock.await.send(ClientEvents::ServiceAnnouncement { credential_policy: service.credential_policy, service_policy: service.service_policy });
-				}
-			}
+	async fn handle_service_registration(&mut self, service: ClientEvents) {
+		// We register civkit services hosted by this credential gateway
+		match service {
+			ClientEvents::ServiceRegistration { pubkey, credential_policy, service_policy } => {
+				self.hosted_services.insert(pubkey, Service { credential_policy, service_policy, registration_height: self.chain_height });
+			},
+			_ => { return; }
+		}
+
+		let services_to_be_announced = self.get_new_service_announcement(0); //TODO: filter what is already announced ?
+		for service in services_to_be_announced {
+			let _ = self.send_credential_events_gateway.send(ClientEvents::ServiceAnnouncement { credential_policy: service.credential_policy, service_policy: service.service_policy }).await;
 		}
 	}
 }
@@ -0,0 +1,37 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! The NIP-45 COUNT messages, parsed and serialized by hand as they are not
+//! supported by our nostr version.
+
+use nostr::{Filter, SubscriptionId};
+
+use serde_json::{json, Value};
+
+/// Returns the subscription id and the filters of a COUNT client message,
+/// `None` if the message is not a well-formed COUNT.
+pub fn parse_count_message(msg_json: &str) -> Option<(SubscriptionId, Vec<Filter>)> {
+	let message: Vec<Value> = serde_json::from_str(msg_json).ok()?;
+	if message.get(0)?.as_str()? != "COUNT" {
+		return None;
+	}
+	let sub_id = SubscriptionId::new(message.get(1)?.as_str()?);
+	let filters = message[2..].iter()
+		.map(|filter| serde_json::from_value::<Filter>(filter.clone()).ok())
+		.collect::<Option<Vec<Filter>>>()?;
+	if filters.is_empty() {
+		return None;
+	}
+	Some((sub_id, filters))
+}
+
+/// The COUNT relay message answering a COUNT of the client.
+pub fn count_message(sub_id: &SubscriptionId, count: u64) -> String {
+	json!(["COUNT", sub_id, { "count": count }]).to_string()
+}
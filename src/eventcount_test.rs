@@ -0,0 +1,25 @@
+use crate::eventcount::{count_message, parse_count_message};
+
+use nostr::SubscriptionId;
+
+use serde_json::{json, Value};
+
+#[test]
+fn test_parse_count_message() {
+	let (sub_id, filters) = parse_count_message(r#"["COUNT", "orders", {"kinds": [32500], "#t": ["board"]}, {"authors": ["abcd"]}]"#).unwrap();
+	assert_eq!(sub_id, SubscriptionId::new("orders"));
+	assert_eq!(filters.len(), 2);
+
+	// Other messages and malformed counts are left to the NIP-01 parsing.
+	assert!(parse_count_message(r#"["REQ", "orders", {"kinds": [32500]}]"#).is_none());
+	assert!(parse_count_message(r#"["COUNT", "orders"]"#).is_none());
+	assert!(parse_count_message(r#"["COUNT", 42, {}]"#).is_none());
+	assert!(parse_count_message(r#"["COUNT", "orders", "kinds"]"#).is_none());
+	assert!(parse_count_message("COUNT").is_none());
+}
+
+#[test]
+fn test_count_message() {
+	let count: Value = serde_json::from_str(&count_message(&SubscriptionId::new("orders"), 12)).unwrap();
+	assert_eq!(count, json!(["COUNT", "orders", { "count": 12 }]));
+}
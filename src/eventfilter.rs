@@ -0,0 +1,131 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! NIP-01 filter evaluation of live events against client subscriptions.
+//!
+//! A subscription matches an event if any of its filters matches, and a
+//! filter matches if all the fields it sets are satisfied by the event.
+
+use crate::search::match_search;
+
+use nostr::{Event, Filter};
+
+use serde_json::Value;
+
+/// A filter compiled once, at REQ time for a subscription, in the conditions
+/// evaluated against every live event.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CompiledFilter {
+	conditions: Vec<Condition>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Condition {
+	Ids(Vec<String>),
+	Authors(Vec<String>),
+	Kinds(Vec<u64>),
+	Since(i64),
+	Until(i64),
+	Search(String),
+	Tag(String, Vec<String>),
+	/// A field set with a value of the wrong type, nothing can match it.
+	Never,
+}
+
+impl CompiledFilter {
+	pub fn new(filter: &Filter) -> Self {
+		// We compile the NIP-01 wire form of the filter, generic tag queries
+		// (`#e`, `#p`, `#d`, ...) are then handled the same way.
+		let filter_fields = match serde_json::to_value(filter) {
+			Ok(Value::Object(fields)) => fields,
+			_ => { return CompiledFilter { conditions: vec![Condition::Never] }; }
+		};
+		let conditions = filter_fields.iter()
+			.filter(|(_, value)| !value.is_null())
+			.filter_map(|(field, value)| compile_condition(field, value))
+			.collect();
+		CompiledFilter { conditions }
+	}
+
+	/// Returns true if the event satisfies every condition set by the filter.
+	pub fn matches(&self, event: &Event) -> bool {
+		self.conditions.iter().all(|condition| match condition {
+			Condition::Ids(prefixes) => { let event_id = event.id.to_hex(); prefixes.iter().any(|prefix| event_id.starts_with(prefix)) },
+			Condition::Authors(prefixes) => { let author = event.pubkey.to_string(); prefixes.iter().any(|prefix| author.starts_with(prefix)) },
+			Condition::Kinds(kinds) => kinds.contains(&(event.kind.as_u32() as u64)),
+			Condition::Since(since) => event.created_at.as_i64() >= *since,
+			Condition::Until(until) => event.created_at.as_i64() <= *until,
+			Condition::Search(search) => match_search(search, event),
+			Condition::Tag(tag_name, values) => match_tag_values(tag_name, values, event),
+			Condition::Never => false,
+		})
+	}
+}
+
+/// Returns true if the event matches at least one of the subscription filters.
+pub fn match_filters(filters: &[CompiledFilter], event: &Event) -> bool {
+	filters.iter().any(|filter| filter.matches(event))
+}
+
+/// Returns true if the event satisfies every condition set by the filter.
+///
+/// The `limit` field only applies to the replay of stored events and is
+/// ignored for live events. Compile the filter once with `CompiledFilter` to
+/// evaluate it against many events.
+pub fn match_filter(filter: &Filter, event: &Event) -> bool {
+	CompiledFilter::new(filter).matches(event)
+}
+
+/// Returns the `limit` field of the filter, if any.
+pub fn filter_limit(filter: &Filter) -> Option<u64> {
+	match serde_json::to_value(filter) {
+		Ok(Value::Object(fields)) => fields.get("limit").and_then(|limit| limit.as_u64()),
+		_ => None,
+	}
+}
+
+fn compile_condition(field: &str, value: &Value) -> Option<Condition> {
+	let condition = match field {
+		"ids" => string_values(value).map(|prefixes| Condition::Ids(lowercase(prefixes))),
+		"authors" => string_values(value).map(|prefixes| Condition::Authors(lowercase(prefixes))),
+		"kinds" => value.as_array().map(|kinds| Condition::Kinds(kinds.iter().filter_map(|k| k.as_u64()).collect())),
+		"since" => value.as_i64().map(Condition::Since),
+		"until" => value.as_i64().map(Condition::Until),
+		"search" => value.as_str().map(|search| Condition::Search(search.to_string())),
+		tag_query if is_tag_query(tag_query) => string_values(value).map(|values| Condition::Tag(tag_query[1..].to_string(), values)),
+		// `limit` and the unknown fields don't filter the events.
+		_ => { return None; },
+	};
+	Some(condition.unwrap_or(Condition::Never))
+}
+
+/// A tag query is a `#` followed by a single letter tag name.
+pub(crate) fn is_tag_query(field: &str) -> bool {
+	let mut chars = field.chars();
+	chars.next() == Some('#') && chars.next().map_or(false, |c| c.is_ascii_alphabetic()) && chars.next().is_none()
+}
+
+fn string_values(value: &Value) -> Option<Vec<String>> {
+	value.as_array().map(|values| values.iter().filter_map(|v| v.as_str()).map(|v| v.to_string()).collect())
+}
+
+fn lowercase(values: Vec<String>) -> Vec<String> {
+	values.into_iter().map(|value| value.to_lowercase()).collect()
+}
+
+fn match_tag_values(tag_name: &str, values: &[String], event: &Event) -> bool {
+	for tag in event.tags.iter() {
+		let tag = tag.as_vec();
+		if tag.len() < 2 || tag[0] != tag_name { continue }
+		if values.iter().any(|v| *v == tag[1]) {
+			return true;
+		}
+	}
+	false
+}
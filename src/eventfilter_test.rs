@@ -0,0 +1,126 @@
+use crate::eventfilter::{filter_limit, match_filter, match_filters, CompiledFilter};
+use crate::test_utils::{build_event, build_filter};
+
+use nostr::{Keys, Tag};
+
+use serde_json::json;
+
+#[test]
+fn test_match_empty_filter() {
+	let keys = Keys::generate();
+	let event = build_event(&keys, 1, "hello", &[]);
+
+	assert!(match_filter(&build_filter(json!({})), &event));
+}
+
+#[test]
+fn test_match_ids_and_authors_prefixes() {
+	let keys = Keys::generate();
+	let event = build_event(&keys, 1, "hello", &[]);
+	let event_id = event.id.to_hex();
+	let author = event.pubkey.to_string();
+
+	assert!(match_filter(&build_filter(json!({ "ids": [event_id] })), &event));
+	assert!(match_filter(&build_filter(json!({ "ids": [&event_id[..8]] })), &event));
+	assert!(match_filter(&build_filter(json!({ "authors": [author] })), &event));
+	assert!(match_filter(&build_filter(json!({ "authors": [&author[..10]] })), &event));
+
+	let other_keys = Keys::generate();
+	let other_event = build_event(&other_keys, 1, "hello", &[]);
+	assert!(!match_filter(&build_filter(json!({ "ids": [other_event.id.to_hex()] })), &event));
+	assert!(!match_filter(&build_filter(json!({ "authors": [other_event.pubkey.to_string()] })), &event));
+}
+
+#[test]
+fn test_match_kinds() {
+	let keys = Keys::generate();
+	let event = build_event(&keys, 32500, "hello", &[]);
+
+	assert!(match_filter(&build_filter(json!({ "kinds": [1, 32500] })), &event));
+	assert!(!match_filter(&build_filter(json!({ "kinds": [1] })), &event));
+	assert!(!match_filter(&build_filter(json!({ "kinds": [] })), &event));
+}
+
+#[test]
+fn test_match_since_until() {
+	let keys = Keys::generate();
+	let event = build_event(&keys, 1, "hello", &[]);
+	let created_at = event.created_at.as_i64();
+
+	assert!(match_filter(&build_filter(json!({ "since": created_at, "until": created_at })), &event));
+	assert!(!match_filter(&build_filter(json!({ "since": created_at + 1 })), &event));
+	assert!(!match_filter(&build_filter(json!({ "until": created_at - 1 })), &event));
+}
+
+#[test]
+fn test_match_limit_ignored_for_live_events() {
+	let keys = Keys::generate();
+	let event = build_event(&keys, 1, "hello", &[]);
+
+	assert!(match_filter(&build_filter(json!({ "kinds": [1], "limit": 0 })), &event));
+}
+
+#[test]
+fn test_match_tag_queries() {
+	let keys = Keys::generate();
+	let order = build_event(&keys, 32500, "hello", &[]);
+	let order_id = order.id.to_hex();
+	let counterparty = Keys::generate().public_key().to_string();
+
+	let tags = vec![
+		Tag::parse(vec!["e".to_string(), order_id.clone()]).unwrap(),
+		Tag::parse(vec!["p".to_string(), counterparty.clone()]).unwrap(),
+		Tag::parse(vec!["t".to_string(), "btcusd".to_string()]).unwrap(),
+	];
+	let event = build_event(&keys, 1, "hello", &tags);
+
+	assert!(match_filter(&build_filter(json!({ "#e": [order_id] })), &event));
+	assert!(match_filter(&build_filter(json!({ "#p": [counterparty] })), &event));
+	assert!(match_filter(&build_filter(json!({ "#t": ["btceur", "btcusd"] })), &event));
+	assert!(!match_filter(&build_filter(json!({ "#t": ["btceur"] })), &event));
+	assert!(!match_filter(&build_filter(json!({ "#d": ["btcusd"] })), &event));
+	assert!(!match_filter(&build_filter(json!({ "#e": [order_id] })), &order));
+}
+
+#[test]
+fn test_match_fields_are_and_combined() {
+	let keys = Keys::generate();
+	let event = build_event(&keys, 1, "hello", &[Tag::parse(vec!["t".to_string(), "btcusd".to_string()]).unwrap()]);
+	let author = event.pubkey.to_string();
+
+	assert!(match_filter(&build_filter(json!({ "kinds": [1], "authors": [author], "#t": ["btcusd"] })), &event));
+	assert!(!match_filter(&build_filter(json!({ "kinds": [1], "authors": [author], "#t": ["btceur"] })), &event));
+	assert!(!match_filter(&build_filter(json!({ "kinds": [4], "authors": [author], "#t": ["btcusd"] })), &event));
+}
+
+#[test]
+fn test_match_filters_are_or_combined() {
+	let keys = Keys::generate();
+	let event = build_event(&keys, 1, "hello", &[]);
+
+	let filters = vec![CompiledFilter::new(&build_filter(json!({ "kinds": [4] }))), CompiledFilter::new(&build_filter(json!({ "kinds": [1] })))];
+	assert!(match_filters(&filters, &event));
+
+	let filters = vec![CompiledFilter::new(&build_filter(json!({ "kinds": [4] }))), CompiledFilter::new(&build_filter(json!({ "kinds": [32500] })))];
+	assert!(!match_filters(&filters, &event));
+
+	assert!(!match_filters(&[], &event));
+}
+
+#[test]
+fn test_filter_limit() {
+	assert_eq!(filter_limit(&build_filter(json!({ "kinds": [1], "limit": 10 }))), Some(10));
+	assert_eq!(filter_limit(&build_filter(json!({ "kinds": [1] }))), None);
+}
+
+#[test]
+fn test_compiled_filter_reused_across_events() {
+	let keys = Keys::generate();
+	let note = build_event(&keys, 1, "hello", &[]);
+	let order = build_event(&keys, 32500, "hello", &[]);
+
+	let filter = CompiledFilter::new(&build_filter(json!({ "kinds": [1], "authors": [&keys.public_key().to_string()[..10]] })));
+	assert!(filter.matches(&note));
+	assert!(!filter.matches(&order));
+	assert!(filter.matches(&note));
+}
@@ -11,6 +11,8 @@
 //! ClientHandler.
 
 use crate::NostrClient;
+use crate::connectionlimits::ConnectionUsage;
+use crate::rejection::RejectionReason;
 
 use nostr::{Event, EventId, SubscriptionId};
 
@@ -27,21 +29,34 @@ pub enum ClientEvents {
 	TextNote { event: Event },
 	Server { cmd: ServerCmd },
 	OrderNote { order: Event },
-	StoredEvent { client_id: u64, events: Vec<Event> },
+	StoredEvent { client_id: u64, sub_id: SubscriptionId, events: Vec<Event> },
 	EndOfStoredEvents { client_id: u64, sub_id: SubscriptionId },
 	RelayNotice { client_id: u64, message: String },
 	SubscribedEvent { client_id: u64, sub_id: SubscriptionId, event: Event },
-	OkEvent { client_id: u64, event_id: EventId, ret: bool, msg: Option<String> },
+	/// The result of a NIP-45 COUNT of the client, or the reason it is refused.
+	EventCount { client_id: u64, sub_id: SubscriptionId, count: Result<u64, RejectionReason> },
+	/// The outcome of an event published by the client, `reason` is `None` if accepted.
+	OkEvent { client_id: u64, event_id: EventId, reason: Option<RejectionReason> },
 	ServiceRegistration { pubkey: PublicKey, credential_policy: CredentialPolicy, service_policy: ServicePolicy },
-	Credential { client_id: u64, deliverance_id: u64, event: Event },
-	ValidationResult { client_id: u64, deliverance_id: u64, event: Event },
+	Credential { client_id: u64, event: Event },
+	/// The credentials redeemed by a client to pay for the publication of an event,
+	/// serialized. They are spent when the event is stored, only the `max_spent`
+	/// last spent credentials are remembered. `payment_id` is the service
+	/// deliverance event carrying them.
+	ValidationResult { client_id: u64, payment_id: EventId, event_id: EventId, service_id: u64, credentials: Vec<Vec<u8>>, max_spent: u64 },
+	/// The outcome of a redemption, `reason` is `None` if the paid event is
+	/// stored and the credentials spent.
+	RedemptionResult { client_id: u64, payment_id: EventId, reason: Option<RejectionReason> },
 	ServiceAnnouncement { credential_policy: CredentialPolicy, service_policy: ServicePolicy },
+	/// The client websocket is closed, its pending requests can be dropped.
+	ClientDisconnected { client_id: u64 },
 }
 
 #[derive(Debug)]
 pub enum ServerCmd {
 	DisconnectClient { client_id: u64 },
-	GetClients { respond_to: oneshot::Sender<Vec<NostrClient>> }
+	GetClients { respond_to: oneshot::Sender<Vec<NostrClient>> },
+	GetConnectionUsage { respond_to: oneshot::Sender<ConnectionUsage> },
 }
 
 pub trait EventsProvider {
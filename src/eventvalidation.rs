@@ -0,0 +1,46 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! NIP-01 validation of the events received from clients, before any
+//! filtering, credential check or storage.
+
+use nostr::{Event, EventId};
+use nostr::secp256k1::{Message, Secp256k1};
+
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+	/// The event id is not the hash of the serialized event.
+	InvalidId,
+	/// The event signature does not verify against the event id and pubkey.
+	InvalidSignature,
+}
+
+impl fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ValidationError::InvalidId => write!(f, "event id does not match the serialized event"),
+			ValidationError::InvalidSignature => write!(f, "event signature verification failed"),
+		}
+	}
+}
+
+/// Checks the event id commits to the event content and the Schnorr signature
+/// is valid for the event pubkey.
+pub fn validate_event(event: &Event) -> Result<(), ValidationError> {
+	let computed_id = EventId::new(&event.pubkey, event.created_at, &event.kind, &event.tags, &event.content);
+	if computed_id != event.id {
+		return Err(ValidationError::InvalidId);
+	}
+
+	let message = Message::from_slice(event.id.as_bytes()).map_err(|_| ValidationError::InvalidId)?;
+	let secp_ctx = Secp256k1::verification_only();
+	secp_ctx.verify_schnorr(&event.sig, &message, &event.pubkey).map_err(|_| ValidationError::InvalidSignature)
+}
@@ -0,0 +1,84 @@
+use crate::eventvalidation::{validate_event, ValidationError};
+use crate::test_utils::{build_event, build_event_at};
+
+use nostr::{Event, EventId, Keys, Tag};
+
+use serde_json::{json, Value};
+
+/// Returns a copy of the event with one field of its wire form replaced.
+fn tamper(event: &Event, field: &str, value: Value) -> Event {
+	let mut raw: Value = serde_json::from_str(&event.as_json()).unwrap();
+	raw[field] = value;
+	Event::from_json(raw.to_string()).unwrap()
+}
+
+fn valid_corpus() -> Vec<Event> {
+	let keys = Keys::generate();
+	let order_tags = vec![
+		Tag::parse(vec!["d".to_string(), "order-1".to_string()]).unwrap(),
+		Tag::parse(vec!["t".to_string(), "btcusd".to_string()]).unwrap(),
+	];
+	vec![
+		build_event(&keys, 1, "hello", &[]),
+		build_event(&keys, 1, "", &[]),
+		build_event(&keys, 1, "unicode ₿ \"quoted\" \n newline", &[]),
+		build_event(&keys, 32500, "{\"side\":\"buy\"}", &order_tags),
+		build_event(&Keys::generate(), 20001, "ephemeral", &[]),
+		build_event_at(&keys, 1, "dated", vec![], 100),
+	]
+}
+
+#[test]
+fn test_valid_events_accepted() {
+	for event in valid_corpus() {
+		assert_eq!(validate_event(&event), Ok(()));
+	}
+}
+
+#[test]
+fn test_tampered_fields_rejected() {
+	for event in valid_corpus() {
+		let other_keys = Keys::generate();
+		let tampered_events = vec![
+			tamper(&event, "content", json!("tampered")),
+			tamper(&event, "kind", json!(4)),
+			tamper(&event, "created_at", json!(event.created_at.as_i64() + 1)),
+			tamper(&event, "tags", json!([["t", "btceur"]])),
+			tamper(&event, "pubkey", json!(other_keys.public_key().to_string())),
+		];
+		for tampered in tampered_events {
+			assert_eq!(validate_event(&tampered), Err(ValidationError::InvalidId));
+		}
+	}
+}
+
+#[test]
+fn test_forged_id_rejected() {
+	let event = build_event(&Keys::generate(), 1, "hello", &[]);
+	let forged = tamper(&event, "id", json!(format!("{:064x}", 1)));
+
+	assert_eq!(validate_event(&forged), Err(ValidationError::InvalidId));
+}
+
+#[test]
+fn test_invalid_signatures_rejected() {
+	let keys = Keys::generate();
+	let event = build_event(&keys, 1, "hello", &[]);
+	let other_event = build_event(&keys, 1, "other", &[]);
+
+	// A valid signature from the same key over another event.
+	let swapped = tamper(&event, "sig", json!(other_event.sig.to_string()));
+	assert_eq!(validate_event(&swapped), Err(ValidationError::InvalidSignature));
+
+	// A signature with one flipped bit.
+	let mut sig = event.sig.to_string().into_bytes();
+	sig[0] = if sig[0] == b'0' { b'1' } else { b'0' };
+	let flipped = tamper(&event, "sig", json!(String::from_utf8(sig).unwrap()));
+	assert_eq!(validate_event(&flipped), Err(ValidationError::InvalidSignature));
+
+	// The content re-signed by another key, keeping the original author.
+	let resigned = build_event(&Keys::generate(), 1, "hello", &[]);
+	let impersonated = tamper(&resigned, "pubkey", json!(keys.public_key().to_string()));
+	let impersonated = tamper(&impersonated, "id", json!(EventId::new(&impersonated.pubkey, impersonated.created_at, &impersonated.kind, &impersonated.tags, &impersonated.content).to_hex()));
+	assert_eq!(validate_event(&impersonated), Err(ValidationError::InvalidSignature));
+}
@@ -15,8 +15,9 @@ use serde_json::{Value, from_str, to_value};
 
 use crate::mainstay::{get_proof};
 use crate::config::Config;
-use crate::nostr_db::{write_new_inclusion_proof_db};
+use crate::nostr_db::{Storage, ops_to_json_string};
 use crate::rpcclient::{Client, Auth};
+use crate::shutdown::ShutdownSignal;
 
 #[derive(Debug, Clone)]
 pub struct InclusionProof {
@@ -48,8 +49,8 @@ impl InclusionProof {
         }
     }
 
-    pub async fn run(&mut self) {
-		loop {
+    pub async fn run(&mut self, storage: Storage, mut shutdown: ShutdownSignal) {
+		while !shutdown.is_triggered() {
             let req = get_proof(&self.config.mainstay).await.unwrap();
 
             match req.send().await {
@@ -93,13 +94,22 @@ impl InclusionProof {
                                 *self.raw_tx.lock().unwrap() = json_value;
                             }
                         }
-                        write_new_inclusion_proof_db(self).await;
+                        let txid = self.txid.lock().unwrap().clone();
+                        let commitment = self.commitment.lock().unwrap().clone();
+                        let merkle_root = self.merkle_root.lock().unwrap().clone();
+                        let ops = ops_to_json_string(self.ops.clone());
+                        if let Err(err) = storage.write_inclusion_proof(txid, commitment, merkle_root, ops).await {
+                            println!("Error in storing inclusion proof: {}", err);
+                        }
                     }
                 },
                 Err(err) => println!("Error in retrieving inclusion proof: {}", err),
             }
             
-			sleep(Duration::from_millis(60 * 1000)).await;
+			tokio::select! {
+				_ = sleep(Duration::from_millis(60 * 1000)) => {},
+				_ = shutdown.recv() => {},
+			}
         }
     }
 }
@@ -14,55 +14,84 @@ use nostr::Filter;
 
 use crate::mainstay::send_commitment;
 
+use crate::admission::AdmissionPolicy;
+use crate::eventfilter::filter_limit;
 use crate::events::ClientEvents;
 use crate::nostr_db::DbRequest;
-use crate::nostr_db::{write_new_subscription_db, write_new_event_db, write_new_client_db, print_events_db, print_clients_db, query_events_db, get_cumulative_hash_of_last_event};
+use crate::nostr_db::{Storage, StorageError};
+use crate::rejection::RejectionReason;
+use crate::retention::{is_expired, RetentionPolicy};
+use crate::search::cap_search_limit;
+use crate::shutdown::ShutdownSignal;
 
-use nostr::Event;
+use nostr::{Event, EventId, SubscriptionId, Timestamp};
 
 use crate::config::Config;
 
 use std::sync::Mutex;
 
-use crate::util::is_replaceable;
 
-use tokio::sync::mpsc;
-use tokio::sync::Mutex as TokioMutex;
-use tokio::time::{sleep, Duration};
+use crate::bus::{BusReceiver, BusSender};
 use base64::encode;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
-const MAX_PENDING_DB_REQUEST_PER_CLIENT: u64 = 100;
+/// Max number of events of a client staged for storage until their validation.
+pub const MAX_PENDING_DB_REQUEST_PER_CLIENT: u64 = 100;
+
+/// The credentials redeemed by a client for an event, spent once the event is
+/// stored.
+struct Redemption {
+	client_id: u64,
+	payment_id: EventId,
+	service_id: u64,
+	credentials: Vec<Vec<u8>>,
+	max_spent: u64,
+}
 
 pub struct NoteProcessor {
 	note_counters: Mutex<u64>,
 	current_height: u64,
 
-	receive_db_requests: TokioMutex<mpsc::UnboundedReceiver<DbRequest>>,
-	send_db_result_handler: TokioMutex<mpsc::UnboundedSender<ClientEvents>>,
+	receive_db_requests: BusReceiver<DbRequest>,
+	send_db_result_handler: BusSender<ClientEvents>,
 
-	receive_db_requests_manager: TokioMutex<mpsc::UnboundedReceiver<DbRequest>>,
-	receive_validation_dbrequests_manager: TokioMutex<mpsc::UnboundedReceiver<ClientEvents>>,
+	receive_db_requests_manager: BusReceiver<DbRequest>,
+	receive_validation_dbrequests_manager: BusReceiver<ClientEvents>,
+	send_redemption_results: BusSender<ClientEvents>,
 
-	pending_write_db: HashMap<u64, Vec<(u64, Event)>>,
+	/// The events requiring credentials by publishing client, until paid.
+	pending_write_db: HashMap<u64, Vec<Event>>,
+	/// The redemptions validated before the reception of the event they pay.
+	early_redemptions: HashMap<EventId, Redemption>,
+	admission: AdmissionPolicy,
+
+	storage: Storage,
+	retention: RetentionPolicy,
 
 	config: Config,
 }
 
 impl NoteProcessor {
-	pub fn new(receive_db_requests: mpsc::UnboundedReceiver<DbRequest>, receive_db_requests_manager: mpsc::UnboundedReceiver<DbRequest>, send_db_result_handler: mpsc::UnboundedSender<ClientEvents>, receive_validation_dbrequests_manager: mpsc::UnboundedReceiver<ClientEvents>, our_config: Config) -> Self {
+	pub fn new(receive_db_requests: BusReceiver<DbRequest>, receive_db_requests_manager: BusReceiver<DbRequest>, send_db_result_handler: BusSender<ClientEvents>, receive_validation_dbrequests_manager: BusReceiver<ClientEvents>, send_redemption_results: BusSender<ClientEvents>, storage: Storage, our_config: Config) -> Self {
 		NoteProcessor {
 			note_counters: Mutex::new(0),
 			current_height: 0,
 
-			receive_db_requests: TokioMutex::new(receive_db_requests),
-			send_db_result_handler: TokioMutex::new(send_db_result_handler),
+			receive_db_requests,
+			send_db_result_handler,
 
-			receive_db_requests_manager: TokioMutex::new(receive_db_requests_manager),
-			receive_validation_dbrequests_manager: TokioMutex::new(receive_validation_dbrequests_manager),
+			receive_db_requests_manager,
+			receive_validation_dbrequests_manager,
+			send_redemption_results,
 
 			pending_write_db: HashMap::new(),
+			early_redemptions: HashMap::new(),
+			admission: AdmissionPolicy::new(&our_config),
+
+			storage,
+			retention: RetentionPolicy::new(&our_config.performance),
 
 			config: our_config,
 		}
@@ -86,116 +115,258 @@ impl NoteProcessor {
 		return notes;
 	}
 
-	pub async fn run(&mut self) {
+	pub async fn run(&mut self, mut shutdown: ShutdownSignal) {
+		let mut retention_interval = tokio::time::interval(Duration::from_secs(self.config.performance.retention_interval.max(1)));
 		loop {
-			sleep(Duration::from_millis(1000)).await;
-
-			let mut replay_request = Vec::new();
-			let mut ok_events = Vec::new();
-			{
-				let mut receive_db_requests_lock = self.receive_db_requests.lock();
-				if let Ok(db_request) = receive_db_requests_lock.await.try_recv() {
+			tokio::select! {
+				_ = shutdown.recv() => {
+					println!("[CIVKITD] - CONTROL: NoteProcessor shutting down");
+					self.flush_pending().await;
+					break;
+				},
+				Some(db_request) = self.receive_db_requests.recv() => {
+					println!("[CIVKITD] - NOTE PROCESSING: Note processor received DB requests");
+					self.handle_db_request(db_request).await;
+				},
+				Some(paid_and_validated_event) = self.receive_validation_dbrequests_manager.recv() => {
+					println!("[CIVKITD] - NOTE PROCESSING: Note processor paid and validated events");
+					self.handle_validated_event(paid_and_validated_event).await;
+				},
+				Some(db_request) = self.receive_db_requests_manager.recv() => {
+					println!("[CIVKITD] - NOTE PROCESSING: Note processor received DB requests from ServiceManager");
 					match db_request {
-						DbRequest::WriteEvent { client_id, deliverance_id, ev } => {
-							println!("[CIVKITD] - NOTE PROCESSING: Stagging event for validation");
-							self.pending_write_db.insert(client_id, vec![(deliverance_id, ev)]);
-						},
-						DbRequest::WriteSub(ns) => { write_new_subscription_db(ns); },
-						DbRequest::WriteClient(ct) => { write_new_client_db(ct).await; },
-						DbRequest::ReplayEvents { client_id, filters } => { replay_request.push((client_id, filters)); },
+						DbRequest::DumpEvents => { self.storage.dump_events().await; },
+						DbRequest::DumpClients => { self.storage.dump_clients().await; },
 						_ => {},
 					}
-					println!("[CIVKITD] - NOTE PROCESSING: Note processor received DB requests");
-				}
+				},
+				_ = retention_interval.tick() => {
+					self.prune_events().await;
+				},
+				else => { break; }
 			}
+		}
+	}
 
-			let mut paid_and_validated_events = Vec::new();
-			{
-				let mut receive_validation_dbrequests_manager_lock = self.receive_validation_dbrequests_manager.lock();
-				if let Ok(paid_and_validated_event) = receive_validation_dbrequests_manager_lock.await.try_recv() {
-					println!("[CIVKITD] - NOTE PROCESSING: Note processor paid and validated events");
-					paid_and_validated_events.push(paid_and_validated_event);
-				}
-			}
+	/// Turns the expired and old events into tombstones, they are not served
+	/// anymore but stay in the cumulative hash chain.
+	async fn prune_events(&self) {
+		match self.storage.prune_events(self.retention.clone(), Timestamp::now().as_u64()).await {
+			Ok(stats) if stats.total() > 0 => {
+				println!("[CIVKITD] - NOTE PROCESSING: Pruned {} events ({} expired, {} deleted or replaced, {} too old, {} evicted)", stats.total(), stats.expired, stats.hidden, stats.outdated, stats.evicted);
+			},
+			Ok(_) => {},
+			Err(err) => println!("[CIVKITD] - NOTE PROCESSING: event pruning failed: {}", err),
+		}
+	}
+
+	/// Processes the requests and validated events already queued, so their
+	/// writes and Mainstay commitments are not lost on shutdown.
+	async fn flush_pending(&mut self) {
+		while let Ok(db_request) = self.receive_db_requests.try_recv() {
+			self.handle_db_request(db_request).await;
+		}
+		while let Ok(paid_and_validated_event) = self.receive_validation_dbrequests_manager.try_recv() {
+			self.handle_validated_event(paid_and_validated_event).await;
+		}
+		if let Err(err) = self.storage.flush().await {
+			println!("[CIVKITD] - NOTE PROCESSING: storage flush failed: {}", err);
+		}
+	}
 
-			for client_ev in paid_and_validated_events {
-				match client_ev {
-					ClientEvents::Credential { client_id, deliverance_id, event } => {
-						if let Some(queue_events) = self.pending_write_db.get(&client_id) {
-							for queue_event in queue_events {
-								if queue_event.0 == deliverance_id {
-									let event_id = queue_event.1.id;
-									if is_replaceable(&queue_event.1) {
-										//TODO: build filter and replace event
-										//TODO: If two events have the same timestamp, the event with the lowest id SHOULD be retained, and the other discarded
-										let filter = Filter::new();
-										if let Ok(old_ev) = query_events_db(filter) {
-											//TODO: check if you should query for multiple replaced events
-											write_new_event_db(event.clone(), Some(old_ev)).await;
-										}
-									} else {
-										let ret = write_new_event_db(queue_event.1.clone(), None).await;
-										println!("[CIVKITD] NOTE PROCESSING: Note stored on disk");
-										if ret { ok_events.push((client_id, queue_event.1.id)); }
-									}
-								}
-							}
-						}
-					},
-					_ => {},
+	async fn handle_db_request(&mut self, db_request: DbRequest) {
+		match db_request {
+			DbRequest::WriteEvent { client_id, ev } => {
+				if is_expired(&ev, Timestamp::now().as_u64()) {
+					let reason = RejectionReason::Invalid("event has expired".to_string());
+					let rejected_event = ClientEvents::OkEvent { client_id, event_id: ev.id, reason: Some(reason) };
+					let _ = self.send_db_result_handler.send(rejected_event).await;
+					return;
 				}
-			}
+				if !self.admission.requires_credentials(&ev) {
+					self.store_event(client_id, ev, None).await;
+					return;
+				}
+				if let Some(redemption) = self.early_redemptions.remove(&ev.id) {
+					self.redeem(client_id, ev, redemption).await;
+					return;
+				}
+				let queue_events = self.pending_write_db.entry(client_id).or_insert_with(Vec::new);
+				if queue_events.len() as u64 >= MAX_PENDING_DB_REQUEST_PER_CLIENT {
+					println!("[CIVKITD] - NOTE PROCESSING: Too many events staged for client {}", client_id);
+					let reason = RejectionReason::RateLimited("too many events pending validation".to_string());
+					let rejected_event = ClientEvents::OkEvent { client_id, event_id: ev.id, reason: Some(reason) };
+					let _ = self.send_db_result_handler.send(rejected_event).await;
+					return;
+				}
+				println!("[CIVKITD] - NOTE PROCESSING: Stagging event for validation");
+				queue_events.push(ev);
+			},
+			DbRequest::WriteClient(ct) => {
+				if let Err(err) = self.storage.write_client(ct).await {
+					println!("[CIVKITD] - NOTE PROCESSING: client write failed: {}", err);
+				}
+			},
+			DbRequest::WriteClientDisconnect(ct) => {
+				// The events staged for payment, and the payments, of the client are dropped.
+				// They are refused to release the copies withheld for the subscribers.
+				for ev in self.pending_write_db.remove(&ct.client_id).unwrap_or_default() {
+					let reason = RejectionReason::Error("publisher disconnected before payment".to_string());
+					let rejected_event = ClientEvents::OkEvent { client_id: ct.client_id, event_id: ev.id, reason: Some(reason) };
+					let _ = self.send_db_result_handler.send(rejected_event).await;
+				}
+				self.early_redemptions.retain(|_, redemption| redemption.client_id != ct.client_id);
+				if let Err(err) = self.storage.write_client_disconnect(ct).await {
+					println!("[CIVKITD] - NOTE PROCESSING: client disconnect write failed: {}", err);
+				}
+			},
+			DbRequest::ReplayEvents { client_id, sub_id, filters } => { self.replay_events(client_id, sub_id, filters).await; },
+			DbRequest::CountEvents { client_id, sub_id, filters } => { self.count_events(client_id, sub_id, filters).await; },
+			_ => {},
+		}
+	}
 
-			{
-				let mut receive_db_requests_manager_lock = self.receive_db_requests_manager.lock();
-				if let Ok(db_request) = receive_db_requests_manager_lock.await.try_recv() {
-					match db_request {
-						DbRequest::DumpEvents => { print_events_db().await; },
-						DbRequest::DumpClients => { print_clients_db().await; },
-						_ => {},
-					}
-					println!("[CIVKITD] - NOTE PROCESSING: Note processor received DB requests from ServiceManager");
+	async fn handle_validated_event(&mut self, client_ev: ClientEvents) {
+		let (event_id, redemption) = match client_ev {
+			ClientEvents::ValidationResult { client_id, payment_id, event_id, service_id, credentials, max_spent } => (event_id, Redemption { client_id, payment_id, service_id, credentials, max_spent }),
+			_ => { return; }
+		};
+
+		match self.take_staged_event(&event_id) {
+			Some((publisher, ev)) => { self.redeem(publisher, ev, redemption).await; },
+			None => {
+				// The service deliverance can be validated before the event it pays for is received.
+				let early_redemptions = self.early_redemptions.values().filter(|early| early.client_id == redemption.client_id).count() as u64;
+				if early_redemptions >= MAX_PENDING_DB_REQUEST_PER_CLIENT {
+					println!("[CIVKITD] - NOTE PROCESSING: Too many early redemptions for client {}", redemption.client_id);
+					let reason = RejectionReason::RateLimited("too many payments pending their event".to_string());
+					self.send_redemption_result(&redemption, Some(reason)).await;
+					return;
 				}
+				self.early_redemptions.insert(event_id, redemption);
+			},
+		}
+	}
+
+	fn take_staged_event(&mut self, event_id: &EventId) -> Option<(u64, Event)> {
+		for (client_id, queue_events) in self.pending_write_db.iter_mut() {
+			if let Some(index) = queue_events.iter().position(|ev| ev.id == *event_id) {
+				return Some((*client_id, queue_events.remove(index)));
 			}
+		}
+		None
+	}
+
+	/// Stores the event if the credentials redeemed pay for it, they are spent
+	/// only then.
+	async fn redeem(&mut self, client_id: u64, ev: Event, redemption: Redemption) {
+		match self.admission.check_payment(&ev, redemption.service_id, redemption.credentials.len() as u32) {
+			Ok(()) => { self.store_event(client_id, ev, Some(redemption)).await; },
+			Err(reason) => {
+				// The payer may not be the publisher, both are told.
+				self.send_redemption_result(&redemption, Some(reason.clone())).await;
+				let rejected_event = ClientEvents::OkEvent { client_id, event_id: ev.id, reason: Some(reason) };
+				let _ = self.send_db_result_handler.send(rejected_event).await;
+			},
+		}
+	}
+
+	/// Stores the event, spending the credentials of the redemption paying for
+	/// it in the same write.
+	async fn store_event(&mut self, client_id: u64, ev: Event, redemption: Option<Redemption>) {
+		let event_id = ev.id;
+		// The storage replaces the previous versions of the replaceable events.
+		let written = match redemption.as_ref() {
+			Some(redemption) => self.storage.write_paid_event(ev, redemption.credentials.clone(), redemption.max_spent).await,
+			None => self.storage.write_event(ev).await,
+		};
+		let reason = match written {
+			Ok(()) => {
+				println!("[CIVKITD] NOTE PROCESSING: Note stored on disk");
+				None
+			},
+			Err(StorageError::Duplicate) => Some(RejectionReason::Duplicate("already have this event".to_string())),
+			Err(StorageError::Outdated) => Some(RejectionReason::Duplicate("have a newer version of this event".to_string())),
+			Err(StorageError::Deleted) => Some(RejectionReason::Blocked("event deleted by its author".to_string())),
+			Err(StorageError::Spent) => Some(RejectionReason::Invalid("credentials already redeemed".to_string())),
+			Err(err) => {
+				println!("[CIVKITD] - NOTE PROCESSING: event write failed: {}", err);
+				Some(RejectionReason::Error("could not store the event".to_string()))
+			},
+		};
+
+		let stored = reason.is_none();
+		if let Some(redemption) = redemption {
+			self.send_redemption_result(&redemption, reason.clone()).await;
+		}
+		let ok_event = ClientEvents::OkEvent { client_id, event_id, reason };
+		let _ = self.send_db_result_handler.send(ok_event).await;
+
+		if stored {
+			self.send_mainstay_commitment().await;
+		}
+	}
+
+	/// Tells the CredentialGateway the outcome of the redemption, it answers
+	/// the payer.
+	async fn send_redemption_result(&self, redemption: &Redemption, reason: Option<RejectionReason>) {
+		let redemption_result = ClientEvents::RedemptionResult { client_id: redemption.client_id, payment_id: redemption.payment_id, reason };
+		if let Err(err) = self.send_redemption_results.send(redemption_result).await {
+			println!("[CIVKITD] - NOTE PROCESSING: redemption result lost: {}", err);
+		}
+	}
 
-			let mut event_replay_result = Vec::new();
-			for req in replay_request {
-				let mut client_id_result = Vec::with_capacity(0);
-				for filter in req.1 {
-					if let Ok(events) = query_events_db(filter) {
-						client_id_result = events;
+	async fn replay_events(&mut self, client_id: u64, sub_id: SubscriptionId, filters: Vec<Filter>) {
+		let filters: Vec<Filter> = filters.into_iter().map(|filter| cap_search_limit(filter, self.config.search.max_results)).collect();
+		// The merged replay is bounded by the largest filter limit, unbounded if a
+		// filter has no limit.
+		let replay_limit = filters.iter()
+			.map(filter_limit)
+			.collect::<Option<Vec<u64>>>()
+			.and_then(|limits| limits.into_iter().max());
+
+		let mut replayed_ids: HashSet<EventId> = HashSet::new();
+		let mut client_id_result: Vec<Event> = Vec::new();
+		for filter in filters {
+			if let Ok(events) = self.storage.query_events(filter).await {
+				for ev in events {
+					if replayed_ids.insert(ev.id) {
+						client_id_result.push(ev);
 					}
 				}
-				event_replay_result.push((req.0.clone(), client_id_result));
 			}
+		}
+		client_id_result.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+		if let Some(replay_limit) = replay_limit {
+			client_id_result.truncate(replay_limit as usize);
+		}
 
-			for ret in event_replay_result {
-				let mut send_db_result_handler_lock = self.send_db_result_handler.lock();
-				let stored_event = ClientEvents::StoredEvent { client_id: ret.0, events: ret.1 };
-				send_db_result_handler_lock.await.send(stored_event);
-			}
+		let stored_event = ClientEvents::StoredEvent { client_id, sub_id, events: client_id_result };
+		let _ = self.send_db_result_handler.send(stored_event).await;
+	}
 
-			let ok_event_mainstay = ok_events.clone();
-			for (client_id, ev) in ok_events {
-				println!("[CIVKITD] - NOTE PROCESSING: Note processor flushing events");
-				let mut send_db_result_handler_lock = self.send_db_result_handler.lock();
-				let ok_event = ClientEvents::OkEvent { client_id: client_id, event_id: ev, ret: true, msg: None };
-				send_db_result_handler_lock.await.send(ok_event);
-			}
+	async fn count_events(&mut self, client_id: u64, sub_id: SubscriptionId, filters: Vec<Filter>) {
+		let count = self.storage.count_events(filters).await.map_err(|err| {
+			println!("[CIVKITD] - NOTE PROCESSING: event count failed: {}", err);
+			RejectionReason::Error("could not count the events".to_string())
+		});
+		let event_count = ClientEvents::EventCount { client_id, sub_id, count };
+		let _ = self.send_db_result_handler.send(event_count).await;
+	}
 
-			for (_, ev) in ok_event_mainstay {
-				let event_id = ev.to_string();
-				let commitment = encode(get_cumulative_hash_of_last_event().await.unwrap());
-				let position = self.config.mainstay.position;
-				let token = &self.config.mainstay.token;
-											
-				let req = send_commitment(commitment.as_str(), position as u64, token, &self.config.mainstay).await.unwrap();
-
-				match req.send().await {
-					Ok(_) => println!("Commitment sent successfully"),
-					Err(err) => println!("Error sending commitment: {}", err),
-				}
-			}
+	async fn send_mainstay_commitment(&self) {
+		let commitment = match self.storage.last_cumulative_hash().await {
+			Ok(Some(cumulative_hash)) => encode(cumulative_hash),
+			_ => { return; }
+		};
+		let position = self.config.mainstay.position;
+		let token = &self.config.mainstay.token;
+
+		let req = send_commitment(commitment.as_str(), position as u64, token, &self.config.mainstay).await.unwrap();
+
+		match req.send().await {
+			Ok(_) => println!("Commitment sent successfully"),
+			Err(err) => println!("Error sending commitment: {}", err),
 		}
 	}
 }
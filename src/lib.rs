@@ -7,6 +7,8 @@
 // You may not use this file except in accordance with one or both of these
 // licenses.
 
+use crate::eventfilter::CompiledFilter;
+
 use bitcoin::secp256k1::PublicKey;
 
 use nostr::{SubscriptionId, Filter};
@@ -18,16 +20,21 @@ use std::net::SocketAddr;
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct NostrSub {
 	our_side_id: u64,
+	client_id: u64,
 	id: SubscriptionId,
-	filters: Vec<Filter>
+	filters: Vec<Filter>,
+	compiled_filters: Vec<CompiledFilter>,
 }
 
 impl NostrSub {
-	pub fn new(our_side_id: u64, id: SubscriptionId, filters: Vec<Filter>) -> Self {
+	pub fn new(our_side_id: u64, client_id: u64, id: SubscriptionId, filters: Vec<Filter>) -> Self {
+		let compiled_filters = filters.iter().map(CompiledFilter::new).collect();
 		NostrSub {
 			our_side_id,
+			client_id,
 			id,
 			filters,
+			compiled_filters,
 		}
 	}
 
@@ -35,9 +42,24 @@ impl NostrSub {
 		self.id == *id
 	}
 
+	/// The subscription id as supplied by the client in its REQ, it must be
+	/// echoed back on every EVENT and EOSE of this subscription.
+	pub fn get_id(&self) -> &SubscriptionId {
+		&self.id
+	}
+
+	pub fn get_client_id(&self) -> u64 {
+		self.client_id
+	}
+
 	pub fn get_filters(&self) -> &Vec<Filter> {
 		&self.filters
 	}
+
+	/// The filters as evaluated against the live events.
+	pub fn get_compiled_filters(&self) -> &[CompiledFilter] {
+		&self.compiled_filters
+	}
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -54,15 +76,18 @@ impl NostrPeer {
 }
 
 
-//TODO: implement config maxconnections
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct NostrClient {
-	//TODO: check we're using Schnorr not ECDSA
+	/// The key the client authenticated with (NIP-42).
 	pub pubkey: Option<XOnlyPublicKey>,
 	pub client_id: u64,
 	pub associated_socket: SocketAddr,
 
-	pub subscriptions: HashMap<u64, ()>,
+	/// The client subscriptions, by the ids it supplied, with our side ids.
+	pub subscriptions: HashMap<SubscriptionId, u64>,
+
+	/// The NIP-42 challenge sent to the client on connection.
+	pub auth_challenge: String,
 }
 
 impl NostrClient {
@@ -72,33 +97,53 @@ impl NostrClient {
 			client_id,
 			associated_socket: socket,
 			subscriptions: HashMap::new(),
+			auth_challenge: clientauth::generate_challenge(),
 		}
 	}
 
-	fn has_pubkey(&self) -> bool {
+	pub fn is_authenticated(&self) -> bool {
 		self.pubkey.is_some()
 	}
 
-	fn add_pubkey(&mut self, pubkey: XOnlyPublicKey) {
+	fn authenticate(&mut self, pubkey: XOnlyPublicKey) {
 		self.pubkey = Some(pubkey);
 	}
 
-	fn add_sub(&mut self, sub_id: u64, max_sub: u64) -> bool {
-		if self.subscriptions.len() as u64 <= max_sub {
-			return self.subscriptions.insert(sub_id, ()).is_none();
+	/// Registers a subscription, replacing the one with the same id if any.
+	/// Returns false if the client has already `max_sub` other subscriptions.
+	fn add_sub(&mut self, sub_id: SubscriptionId, our_side_id: u64, max_sub: u64) -> bool {
+		if !self.subscriptions.contains_key(&sub_id) && self.subscriptions.len() as u64 >= max_sub {
+			return false;
 		}
-		false
+		self.subscriptions.insert(sub_id, our_side_id);
+		true
+	}
+
+	fn remove_sub(&mut self, sub_id: &SubscriptionId) -> Option<u64> {
+		self.subscriptions.remove(sub_id)
 	}
 
-	fn has_sub(&self, sub_id: u64) -> bool {
-		self.subscriptions.get(&sub_id).is_some()
+	fn has_sub(&self, sub_id: &SubscriptionId) -> bool {
+		self.subscriptions.contains_key(sub_id)
 	}
 }
 
 pub extern crate jsonrpc;
 
+pub mod bus;
 pub mod events;
+pub mod eventfilter;
+pub mod eventcount;
+pub mod eventvalidation;
+pub mod clientauth;
+pub mod connectionlimits;
+pub mod ratelimit;
+pub mod pow;
+pub mod admission;
+pub mod rejection;
 pub mod nostr_db;
+pub mod retention;
+pub mod search;
 pub mod anchormanager;
 pub mod credentialgateway;
 pub mod kindprocessor;
@@ -106,6 +151,8 @@ pub mod nodesigner;
 pub mod oniongateway;
 pub mod peerhandler;
 pub mod clienthandler;
+pub mod relayinfo;
+pub mod shutdown;
 pub mod config;
 pub mod util;
 pub mod bitcoind_client;
@@ -113,4 +160,20 @@ pub mod mainstay;
 pub mod inclusionproof;
 pub mod verifycommitment;
 pub mod rpcclient;
+pub mod test_utils;
 pub mod verifycommitment_test;
+pub mod eventfilter_test;
+pub mod eventcount_test;
+pub mod nostr_db_test;
+pub mod eventvalidation_test;
+pub mod relayinfo_test;
+pub mod clientauth_test;
+pub mod shutdown_test;
+pub mod connectionlimits_test;
+pub mod ratelimit_test;
+pub mod pow_test;
+pub mod admission_test;
+pub mod bus_test;
+pub mod rejection_test;
+pub mod retention_test;
+pub mod search_test;
@@ -6,7 +6,6 @@ use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use base64::encode;
 use bitcoin_hashes::{Hash, sha256};
-use crate::nostr_db::{get_cumulative_hash_of_last_event};
 
 pub struct Request(reqwest::RequestBuilder);
 
@@ -80,10 +79,9 @@ pub async fn get_proof(config: &Mainstay) -> Result<Request, Box<dyn std::error:
     Ok(req)
 }
 
-pub async fn calculate_cumulative_hash(eventId: EventId) -> Vec<u8> {
-    let cumulative_hash = get_cumulative_hash_of_last_event().await;
-
-    match cumulative_hash {
+/// Chains the event id to the cumulative hash of the previous stored event.
+pub fn calculate_cumulative_hash(previous_hash: Option<Vec<u8>>, eventId: &EventId) -> Vec<u8> {
+    match previous_hash {
         Some(cumulative_hash) => {
             let mut concatenated_hash = cumulative_hash;
             concatenated_hash.extend_from_slice(eventId.as_bytes());
@@ -7,29 +7,94 @@
 // You may not use this file except in accordance with one or both of these
 // licenses.
 
-use nostr::{Event, EventBuilder, Filter, Keys, Kind};
-
-use crate::{NostrSub, NostrPeer, NostrClient};
-
-use crate::mainstay::{calculate_cumulative_hash};
-
-use crate::inclusionproof::{InclusionProof, Ops};
-
-use rusqlite::{Connection, OpenFlags, params};
-
-use std::path::Path;
-use serde_json::json;
+//! The storage of the civkitd events, clients and inclusion proofs.
+//!
+//! All the accesses go through a `Storage` handle, which forwards them to the
+//! `StorageBackend` owned by a dedicated blocking thread.
+
+use nostr::{Event, EventId, Filter, SubscriptionId, Timestamp};
+use nostr::key::XOnlyPublicKey;
+
+use crate::{NostrPeer, NostrClient};
+
+use crate::eventfilter::{filter_limit, is_tag_query, match_filters, CompiledFilter};
+use crate::mainstay::calculate_cumulative_hash;
+use crate::inclusionproof::Ops;
+use crate::retention::{expiration, is_expired, PruneStats, RetentionPolicy, DELETION_KIND};
+use crate::search::fts_query;
+use crate::util::{d_tag, is_deletion, is_parameterized_replaceable, is_replaceable};
+
+use rusqlite::{ffi, Connection, OpenFlags, params_from_iter};
+use rusqlite::types::Value as SqlValue;
+
+use tokio::sync::{mpsc, oneshot};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use serde_json::{json, Map, Value};
 use std::sync::Arc;
 use std::sync::Mutex;
 
-const CIVKITD_DB_FILE: &str = "civkitd.db";
+/// The database file name, under the civkitd data directory.
+pub const CIVKITD_DB_FILE: &str = "civkitd.db";
+
+/// The files of a SQLite database beside the main one.
+const DB_SIDE_FILE_SUFFIXES: &[&str] = &["-journal", "-wal", "-shm"];
+
+/// Moves the database the previous versions kept in the working directory to
+/// the data directory, its schema is then migrated when opened. Returns true
+/// if a legacy database was moved.
+///
+/// We refuse to pick one if both exist, the stored events, credentials and
+/// cumulative hash chain would otherwise be silently left behind.
+pub fn adopt_legacy_db(legacy_path: &Path, db_path: &Path) -> Result<bool, StorageError> {
+	if !legacy_path.exists() { return Ok(false); }
+	if let (Ok(legacy), Ok(current)) = (legacy_path.canonicalize(), db_path.canonicalize()) {
+		// The node runs from its data directory.
+		if legacy == current { return Ok(false); }
+	}
+	if db_path.exists() {
+		return Err(StorageError::LegacyDatabase(legacy_path.to_path_buf()));
+	}
+	// The side files first, the main file is only moved once they are.
+	for suffix in DB_SIDE_FILE_SUFFIXES {
+		let side_file = with_suffix(legacy_path, suffix);
+		if side_file.exists() {
+			move_file(&side_file, &with_suffix(db_path, suffix))?;
+		}
+	}
+	move_file(legacy_path, db_path)?;
+	println!("[CIVKITD] - NOTE PROCESSING: Moved legacy database {:?} to {:?}", legacy_path, db_path);
+	Ok(true)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+	let mut path = path.as_os_str().to_owned();
+	path.push(suffix);
+	PathBuf::from(path)
+}
+
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+	// The data directory may be on another filesystem.
+	fs::rename(from, to).or_else(|_| {
+		fs::copy(from, to)?;
+		fs::remove_file(from)
+	})
+}
 
 #[derive(Debug)]
 pub enum DbRequest {
-	WriteEvent { client_id: u64, deliverance_id: u64, ev: Event },
-	WriteSub(NostrSub),
+	WriteEvent { client_id: u64, ev: Event },
 	WriteClient(NostrClient),
-	ReplayEvents { client_id: u64, filters: Vec<Filter> },
+	/// Records the end of the client connection.
+	WriteClientDisconnect(NostrClient),
+	ReplayEvents { client_id: u64, sub_id: SubscriptionId, filters: Vec<Filter> },
+	/// Counts the stored events matching the filters (NIP-45).
+	CountEvents { client_id: u64, sub_id: SubscriptionId, filters: Vec<Filter> },
 	DumpEvents,
 	DumpClients,
 }
@@ -45,16 +110,15 @@ struct DbEvent {
 	cumulative_hash: Vec<u8>,
 }
 
-#[derive(Debug)]
-struct DbSub {
-	sub_id: i32,
-	data: Option<Vec<u8>>,
-}
-
 #[derive(Debug)]
 struct DbClient {
 	client_id: i32,
 	data: Option<Vec<u8>>,
+	relay_client_id: Option<i64>,
+	address: Option<String>,
+	pubkey: Option<Vec<u8>>,
+	connected_at: Option<i64>,
+	disconnected_at: Option<i64>,
 }
 
 #[derive(Debug)]
@@ -66,63 +130,321 @@ struct DbInclusionProof {
 	ops: Option<String>,
 }
 
-pub async fn write_new_event_db(event: Event, old_event: Option<Vec<Event>>) -> bool {
+#[derive(Debug)]
+pub enum StorageError {
+	/// An event with the same id is already stored.
+	Duplicate,
+	/// The event has been deleted by its author (NIP-09).
+	Deleted,
+	/// A newer version of the replaceable event is stored (NIP-16/NIP-33).
+	Outdated,
+	/// A credential paying for the event is already spent.
+	Spent,
+	/// The storage thread is not running anymore.
+	Closed,
+	/// A database of a previous version is left in the working directory
+	/// while the data directory already has one.
+	LegacyDatabase(PathBuf),
+	Io(io::Error),
+	Sqlite(rusqlite::Error),
+}
 
-	//TODO: spawn new thread
-	if let Ok(mut conn) = Connection::open_with_flags(
-		Path::new(CIVKITD_DB_FILE),
-		OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
-	) {
-		println!("[CIVKITD] - NOTE PROCESSING: Opening database for read / write new event");
+impl fmt::Display for StorageError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			StorageError::Duplicate => write!(f, "event already stored"),
+			StorageError::Deleted => write!(f, "event deleted by its author"),
+			StorageError::Outdated => write!(f, "newer replaceable event stored"),
+			StorageError::Spent => write!(f, "credentials already redeemed"),
+			StorageError::Closed => write!(f, "storage thread closed"),
+			StorageError::LegacyDatabase(path) => write!(f, "legacy database {:?} left beside the data directory one, move or remove it", path),
+			StorageError::Io(err) => write!(f, "io error: {}", err),
+			StorageError::Sqlite(err) => write!(f, "sqlite error: {}", err),
+		}
+	}
+}
 
-		match conn.execute("CREATE TABLE event (
-			event_id			INTEGER PRIMARY KEY,
-			sha256				BLOB,
-			pubkey				BLOB,
-			timestamp			BIG INT,
-			kind				UNSIGNED INTEGER,
-			content				TEXT,
-			cumulative_hash 	BLOB
-		)",
-		()) {
-			Ok(create) => println!("[CIVKITD] - NOTE PROCESSING: {} rows were updated", create),
-			Err(err) => println!("[CIVKITD] - NOTE PROCESSING: table creation failed: {}", err),
-		}
-
-		//TODO: add complete event
-		let event = DbEvent {
-			id: 0,
-			sha256: event.id.as_bytes().to_vec(),
-			pubkey: event.pubkey.serialize().to_vec(),
-			timestamp: event.created_at.as_i64(),
-			kind: event.kind.as_u32(),
-			content: Some(event.content),
-			cumulative_hash: calculate_cumulative_hash(event.id).await,
-		};
+impl From<rusqlite::Error> for StorageError {
+	fn from(err: rusqlite::Error) -> Self {
+		match err {
+			// Only the unique index on the event ids tells a duplicate.
+			rusqlite::Error::SqliteFailure(ref failure, Some(ref message)) if failure.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE && message.ends_with("event.sha256") => StorageError::Duplicate,
+			err => StorageError::Sqlite(err),
+		}
+	}
+}
+
+impl From<io::Error> for StorageError {
+	fn from(err: io::Error) -> Self {
+		StorageError::Io(err)
+	}
+}
+
+/// A storage backend for civkitd. Methods are blocking and only called from
+/// the storage thread.
+pub trait StorageBackend: Send {
+	/// Stores a signed event, chaining its id in the cumulative hash. A
+	/// deletion event (NIP-09) hides the events it references and a replaceable
+	/// event (NIP-16/NIP-33) its previous versions, they are kept for the
+	/// cumulative hash chain.
+	fn write_event(&mut self, event: &Event) -> Result<(), StorageError>;
+
+	/// Returns the stored events matching the filter, newest first, except the
+	/// deleted, replaced, expired or pruned ones.
+	fn query_events(&mut self, filter: &Filter) -> Result<Vec<Event>, StorageError>;
+
+	/// Returns the number of stored events matching any of the filters, as
+	/// they would be returned by `query_events`.
+	fn count_events(&mut self, filters: &[Filter]) -> Result<u64, StorageError>;
+
+	fn write_client(&mut self, client: &NostrClient) -> Result<(), StorageError>;
+
+	/// Records the disconnection of a client written with `write_client`.
+	fn write_client_disconnect(&mut self, client: &NostrClient) -> Result<(), StorageError>;
+
+	fn write_inclusion_proof(&mut self, txid: String, commitment: String, merkle_root: String, ops: String) -> Result<(), StorageError>;
+
+	/// Returns the cumulative hash of the last stored event.
+	fn last_cumulative_hash(&mut self) -> Result<Option<Vec<u8>>, StorageError>;
+
+	/// Returns the ids of all the stored events, in storage order.
+	fn event_hashes(&mut self) -> Result<Vec<Vec<u8>>, StorageError>;
+
+	fn dump_events(&mut self);
+
+	fn dump_clients(&mut self);
+
+	fn dump_inclusion_proofs(&mut self);
+
+	/// Turns the events past the retention policy into tombstones, keeping
+	/// their id and cumulative hash.
+	fn prune_events(&mut self, policy: &RetentionPolicy, now: u64) -> Result<PruneStats, StorageError>;
+
+	/// Stores an event paid by the serialized credentials of a service
+	/// deliverance, recording them as spent. Nothing is written if one of them
+	/// is already spent. Only the `max_spent` last spent credentials are
+	/// remembered.
+	fn write_paid_event(&mut self, event: &Event, credentials: &[Vec<u8>], max_spent: u64) -> Result<(), StorageError>;
+
+	/// Whether one of the serialized credentials is already spent.
+	fn is_spent(&mut self, credentials: &[Vec<u8>]) -> Result<bool, StorageError>;
+
+	/// Makes the writes durable, called before civkitd exits.
+	fn flush(&mut self) -> Result<(), StorageError> { Ok(()) }
+}
+
+type StorageJob = Box<dyn FnOnce(&mut dyn StorageBackend) + Send>;
 
-		match conn.execute("INSERT INTO event (sha256, pubkey, timestamp, kind, content, cumulative_hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-			(&event.sha256, &event.pubkey, &event.timestamp, &event.kind, &event.content, &event.cumulative_hash),
-		) {
-			Ok(update) => println!("[CIVKITD] - NOTE PROCESSING: {} rows were updated", update),
-			Err(err) => println!("[CIVKITD] - NOTE PROCESSING: update insert failed: {}", err),
+/// A handle to the storage thread, it can be cloned and shared between
+/// components.
+#[derive(Clone, Debug)]
+pub struct Storage {
+	send_job: mpsc::UnboundedSender<StorageJob>,
+}
+
+impl Storage {
+	/// Spawns the storage thread owning the backend.
+	pub fn spawn(mut backend: Box<dyn StorageBackend>) -> Self {
+		let (send_job, mut receive_job) = mpsc::unbounded_channel::<StorageJob>();
+
+		thread::Builder::new()
+			.name("civkitd-storage".to_string())
+			.spawn(move || {
+				while let Some(job) = receive_job.blocking_recv() {
+					job(backend.as_mut());
+				}
+				println!("[CIVKITD] - NOTE PROCESSING: Storage thread exiting");
+			})
+			.expect("Failed to spawn the storage thread");
+
+		Storage {
+			send_job,
 		}
+	}
+
+	async fn execute<T, F>(&self, job: F) -> Result<T, StorageError>
+		where T: Send + 'static, F: FnOnce(&mut dyn StorageBackend) -> Result<T, StorageError> + Send + 'static
+	{
+		let (respond_to, response) = oneshot::channel();
+		let job: StorageJob = Box::new(move |backend| { let _ = respond_to.send(job(backend)); });
+		self.send_job.send(job).map_err(|_| StorageError::Closed)?;
+		response.await.map_err(|_| StorageError::Closed)?
+	}
+
+	pub async fn write_event(&self, event: Event) -> Result<(), StorageError> {
+		self.execute(move |backend| backend.write_event(&event)).await
+	}
+
+	pub async fn query_events(&self, filter: Filter) -> Result<Vec<Event>, StorageError> {
+		self.execute(move |backend| backend.query_events(&filter)).await
+	}
+
+	pub async fn count_events(&self, filters: Vec<Filter>) -> Result<u64, StorageError> {
+		self.execute(move |backend| backend.count_events(&filters)).await
+	}
+
+	pub async fn write_client(&self, client: NostrClient) -> Result<(), StorageError> {
+		self.execute(move |backend| backend.write_client(&client)).await
+	}
+
+	pub async fn write_client_disconnect(&self, client: NostrClient) -> Result<(), StorageError> {
+		self.execute(move |backend| backend.write_client_disconnect(&client)).await
+	}
+
+	pub async fn write_inclusion_proof(&self, txid: String, commitment: String, merkle_root: String, ops: String) -> Result<(), StorageError> {
+		self.execute(move |backend| backend.write_inclusion_proof(txid, commitment, merkle_root, ops)).await
+	}
+
+	pub async fn last_cumulative_hash(&self) -> Result<Option<Vec<u8>>, StorageError> {
+		self.execute(|backend| backend.last_cumulative_hash()).await
+	}
+
+	pub async fn event_hashes(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+		self.execute(|backend| backend.event_hashes()).await
+	}
 
-		conn.close().ok();
-		return true;
-	} else { println!("Failure to open database"); }
-	return false;
+	pub async fn dump_events(&self) {
+		let _ = self.execute(|backend| Ok(backend.dump_events())).await;
+	}
+
+	pub async fn dump_clients(&self) {
+		let _ = self.execute(|backend| Ok(backend.dump_clients())).await;
+	}
+
+	pub async fn dump_inclusion_proofs(&self) {
+		let _ = self.execute(|backend| Ok(backend.dump_inclusion_proofs())).await;
+	}
+
+	pub async fn prune_events(&self, policy: RetentionPolicy, now: u64) -> Result<PruneStats, StorageError> {
+		self.execute(move |backend| backend.prune_events(&policy, now)).await
+	}
+
+	pub async fn write_paid_event(&self, event: Event, credentials: Vec<Vec<u8>>, max_spent: u64) -> Result<(), StorageError> {
+		self.execute(move |backend| backend.write_paid_event(&event, &credentials, max_spent)).await
+	}
+
+	pub async fn is_spent(&self, credentials: Vec<Vec<u8>>) -> Result<bool, StorageError> {
+		self.execute(move |backend| backend.is_spent(&credentials)).await
+	}
+
+	pub async fn flush(&self) -> Result<(), StorageError> {
+		self.execute(|backend| backend.flush()).await
+	}
+}
+
+/// The SQLite backend, holding one connection in WAL mode for the lifetime of
+/// civkitd.
+pub struct SqliteStorage {
+	conn: Connection,
 }
 
-pub async fn print_events_db() {
+impl SqliteStorage {
+	/// Opens or creates the database file and migrates its schema.
+	pub fn open(path: &Path) -> Result<Self, StorageError> {
+		let conn = Connection::open_with_flags(
+			path,
+			OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
+		)?;
+		conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+		conn.pragma_update(None, "synchronous", "NORMAL")?;
+		println!("[CIVKITD] - NOTE PROCESSING: Opened database {:?}", path);
+		Self::from_connection(conn)
+	}
+
+	/// A database living only in memory, mainly for tests.
+	pub fn open_in_memory() -> Result<Self, StorageError> {
+		Self::from_connection(Connection::open_in_memory()?)
+	}
+
+	fn from_connection(mut conn: Connection) -> Result<Self, StorageError> {
+		conn.pragma_update(None, "foreign_keys", true)?;
+		let version = migrate_db(&mut conn)?;
+		println!("[CIVKITD] - NOTE PROCESSING: Database schema at version {}", version);
+		Ok(SqliteStorage { conn })
+	}
+}
+
+impl StorageBackend for SqliteStorage {
+	fn write_event(&mut self, event: &Event) -> Result<(), StorageError> {
+		let tx = self.conn.unchecked_transaction()?;
+		store_event(&tx, event)?;
+		tx.commit()?;
+		Ok(())
+	}
+
+	fn query_events(&mut self, filter: &Filter) -> Result<Vec<Event>, StorageError> {
+		Ok(query_events(&self.conn, filter)?)
+	}
+
+	fn count_events(&mut self, filters: &[Filter]) -> Result<u64, StorageError> {
+		Ok(count_events(&self.conn, filters)?)
+	}
+
+	fn write_client(&mut self, client: &NostrClient) -> Result<(), StorageError> {
+		insert_client(&self.conn, client)?;
+		Ok(())
+	}
+
+	fn write_client_disconnect(&mut self, client: &NostrClient) -> Result<(), StorageError> {
+		record_client_disconnect(&self.conn, client)?;
+		Ok(())
+	}
+
+	fn write_inclusion_proof(&mut self, txid: String, commitment: String, merkle_root: String, ops: String) -> Result<(), StorageError> {
+		let inclusion_proof = DbInclusionProof {
+			inclusion_proof_id: 0,
+			txid: txid.into_bytes(),
+			commitment: commitment.into_bytes(),
+			merkle_root: merkle_root.into_bytes(),
+			ops: Some(ops),
+		};
+
+		self.conn.execute("INSERT INTO inclusion_proof (txid, commitment, merkle_root, ops) VALUES (?1, ?2, ?3, ?4)",
+			(&inclusion_proof.txid, &inclusion_proof.commitment, &inclusion_proof.merkle_root, &inclusion_proof.ops),
+		)?;
+		Ok(())
+	}
+
+	fn last_cumulative_hash(&mut self) -> Result<Option<Vec<u8>>, StorageError> {
+		Ok(last_cumulative_hash(&self.conn)?)
+	}
+
+	fn prune_events(&mut self, policy: &RetentionPolicy, now: u64) -> Result<PruneStats, StorageError> {
+		Ok(prune_events(&self.conn, policy, now)?)
+	}
+
+	fn write_paid_event(&mut self, event: &Event, credentials: &[Vec<u8>], max_spent: u64) -> Result<(), StorageError> {
+		let tx = self.conn.unchecked_transaction()?;
+		if !spend_credentials(&tx, credentials, max_spent)? {
+			return Err(StorageError::Spent);
+		}
+		store_event(&tx, event)?;
+		tx.commit()?;
+		Ok(())
+	}
+
+	fn is_spent(&mut self, credentials: &[Vec<u8>]) -> Result<bool, StorageError> {
+		Ok(is_spent(&self.conn, credentials)?)
+	}
+
+	fn flush(&mut self) -> Result<(), StorageError> {
+		// We move the WAL content back into the database file.
+		self.conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+		Ok(())
+	}
 
-	if let Ok(mut conn) = Connection::open_with_flags(
-		Path::new(CIVKITD_DB_FILE),
-		OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
-	) {
-		println!("[CIVKITD] - NOTE PROCESSING: Opening database for read events");
+	fn event_hashes(&mut self) -> Result<Vec<Vec<u8>>, StorageError> {
+		let mut stmt = self.conn.prepare("SELECT sha256 FROM event ORDER BY event_id ASC")?;
+		let rows = stmt.query_map([], |row| row.get(0))?;
+		let mut hashes = Vec::new();
+		for hash in rows {
+			hashes.push(hash?);
+		}
+		Ok(hashes)
+	}
 
-		{
-			let mut stmt = conn.prepare("SELECT event_id, sha256, pubkey, timestamp, kind, content, cumulative_hash FROM event").unwrap();
+	fn dump_events(&mut self) {
+		if let Ok(mut stmt) = self.conn.prepare("SELECT event_id, sha256, pubkey, timestamp, kind, content, cumulative_hash FROM event") {
 			let event_iter = stmt.query_map([], |row| {
 				Ok(DbEvent {
 					id: row.get(0)?,
@@ -133,271 +455,923 @@ pub async fn print_events_db() {
 					content: row.get(5)?,
 					cumulative_hash: row.get(6)?,
 				})
-			}).unwrap();
+			});
 
-			for event in event_iter {
-				println!("[CIVKITD] - NOTE PROCESSING: Found event {:?}", event.unwrap());
+			if let Ok(event_iter) = event_iter {
+				for event in event_iter {
+					println!("[CIVKITD] - NOTE PROCESSING: Found event {:?}", event);
+				}
 			}
 		}
+	}
 
-		conn.close().ok();
-	} else { println!("Failure to open database"); }
-}
-
-pub fn query_events_db(filter: Filter) -> Result<Vec<Event>, ()> {
-
-	if let Ok(mut conn) = Connection::open_with_flags(
-		Path::new(CIVKITD_DB_FILE),
-		OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
-	) {
-		if let Some(kinds) = filter.kinds {
-			//TODO: iter on all the kinds provided by the filter
-			let sql = format!("SELECT event_id, sha256, pubkey, timestamp, kind, content, cumulative_hash FROM event WHERE kind = {}", kinds[0].as_u32());
-			if let Ok(mut stmt) = conn.prepare(&sql) {
-				let event_iter = stmt.query_map([], |row| {
-					Ok(DbEvent {
-						id: row.get(0)?,
-						sha256: row.get(1)?,
-						pubkey: row.get(2)?,
-						timestamp: row.get(3)?,
-						kind: row.get(4)?,
-						content: row.get(5)?,
-						cumulative_hash: row.get(6)?,
-					})
-				}).unwrap();
-
-				let mut result_events = Vec::new();
-
-				//TODO: write keys on DB
-				let dummy_keys = Keys::generate();
-				for event in event_iter {
-					let db_event = event.unwrap();
-					let e = EventBuilder::new(Kind::from(db_event.kind as u64), db_event.content.unwrap(), &[]).to_event(&dummy_keys).unwrap();
-					result_events.push(e);
+	fn dump_clients(&mut self) {
+		if let Ok(mut stmt) = self.conn.prepare("SELECT client_id, data, relay_client_id, address, pubkey, connected_at, disconnected_at FROM client") {
+			let client_iter = stmt.query_map([], |row| {
+				Ok(DbClient {
+					client_id: row.get(0)?,
+					data: row.get(1)?,
+					relay_client_id: row.get(2)?,
+					address: row.get(3)?,
+					pubkey: row.get(4)?,
+					connected_at: row.get(5)?,
+					disconnected_at: row.get(6)?,
+				})
+			});
+
+			if let Ok(client_iter) = client_iter {
+				for client in client_iter {
+					println!("[CIVKITD] - NOTE PROCESSING: Found client {:?}", client);
 				}
+			}
+		}
+	}
 
-				return Ok(result_events);
-			} else { return Err(()) }
+	fn dump_inclusion_proofs(&mut self) {
+		if let Ok(mut stmt) = self.conn.prepare("SELECT inclusion_proof_id, txid, commitment, merkle_root, ops FROM inclusion_proof") {
+			let inclusion_proof_iter = stmt.query_map([], |row| {
+				Ok(DbInclusionProof {
+					inclusion_proof_id: row.get(0)?,
+					txid: row.get(1)?,
+					commitment: row.get(2)?,
+					merkle_root: row.get(3)?,
+					ops: row.get(4)?,
+				})
+			});
+
+			if let Ok(inclusion_proof_iter) = inclusion_proof_iter {
+				for inclusion_proof in inclusion_proof_iter {
+					println!("[CIVKITD] - NOTE PROCESSING: Found inclusion proof {:?}", inclusion_proof);
+				}
+			}
 		}
 	}
+}
 
-	Err(())
+/// Stores the event in the caller transaction, chained to the last stored
+/// one: the previous versions of a replaceable event are marked replaced and
+/// the events referenced by a deletion are marked deleted.
+fn store_event(conn: &Connection, event: &Event) -> Result<(), StorageError> {
+	if is_deleted(conn, event)? {
+		return Err(StorageError::Deleted);
+	}
+	let previous_versions = replaceable_versions(conn, event)?;
+	if previous_versions.iter().any(|(_, previous)| !replaces(event, previous)) {
+		return Err(StorageError::Outdated);
+	}
+	let previous_hash = last_cumulative_hash(conn)?;
+	let cumulative_hash = calculate_cumulative_hash(previous_hash, &event.id);
+	insert_event_rows(conn, event, cumulative_hash)?;
+	for (event_id, _) in previous_versions {
+		conn.execute("UPDATE event SET replaced_by = ?1 WHERE event_id = ?2", (event.id.as_bytes().to_vec(), event_id))?;
+	}
+	if is_deletion(event) {
+		let deleted = delete_events(conn, event)?;
+		println!("[CIVKITD] - NOTE PROCESSING: Deletion {} hides {} events", event.id.to_hex(), deleted);
+	}
+	Ok(())
 }
 
-pub async fn get_cumulative_hash_of_last_event() -> Option<Vec<u8>> {
-    if let Ok(mut conn) = Connection::open_with_flags(
-            Path::new(CIVKITD_DB_FILE),
-            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
-        ) {
-        let mut stmt = conn
-            .prepare("SELECT cumulative_hash FROM event ORDER BY event_id DESC LIMIT 1")
-            .unwrap();
-		return match stmt.query_row([], |row| row.get(0)) {
-			Ok(cumulative_hash) => {
-				Some(cumulative_hash)
-			},
-			Err(_) => None,
-		};
-    }
+fn last_cumulative_hash(conn: &Connection) -> rusqlite::Result<Option<Vec<u8>>> {
+	let mut stmt = conn.prepare_cached("SELECT cumulative_hash FROM event ORDER BY event_id DESC LIMIT 1")?;
+	match stmt.query_row([], |row| row.get(0)) {
+		Ok(cumulative_hash) => Ok(Some(cumulative_hash)),
+		Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+		Err(err) => Err(err),
+	}
+}
 
-    None
+/// Stores the complete signed event and indexes its tags. An event already
+/// stored is rejected by the unique constraint on its id.
+pub(crate) fn insert_event(conn: &Connection, event: &Event, cumulative_hash: Vec<u8>) -> rusqlite::Result<usize> {
+	let tx = conn.unchecked_transaction()?;
+	let update = insert_event_rows(&tx, event, cumulative_hash)?;
+	tx.commit()?;
+	Ok(update)
 }
 
-pub async fn get_hashes_of_all_events() -> Option<Vec<Vec<u8>>> {
-	if let Ok(mut conn) = Connection::open_with_flags(
-			Path::new(CIVKITD_DB_FILE),
-			OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
-		) {
-		let mut stmt = conn
-			.prepare("SELECT sha256 FROM event ORDER BY event_id ASC")
-			.unwrap();
-		let mut hashes = Vec::new();
-		let event_iter = stmt.query_map([], |row| {
-			Ok(row.get(0)?)
-		}).unwrap();
+/// Inserts the event, its tags and its searched content, in the caller
+/// transaction.
+fn insert_event_rows(conn: &Connection, event: &Event, cumulative_hash: Vec<u8>) -> rusqlite::Result<usize> {
+	let db_event = DbEvent {
+		id: 0,
+		sha256: event.id.as_bytes().to_vec(),
+		pubkey: event.pubkey.serialize().to_vec(),
+		timestamp: event.created_at.as_i64(),
+		kind: event.kind.as_u32(),
+		content: Some(event.content.clone()),
+		cumulative_hash,
+	};
+
+	// The signed event is kept serialized, with its original id and signature,
+	// to be replayed to clients. The fields are the received ones, their JSON
+	// formatting is not.
+	let sig = event.sig.to_string();
+	let raw_event = event.as_json();
+
+	let expiration = expiration(event).map(|expiration| expiration.min(i64::MAX as u64) as i64);
+
+	let update = conn.execute("INSERT INTO event (sha256, pubkey, timestamp, kind, content, cumulative_hash, sig, raw_event, expiration) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+		(&db_event.sha256, &db_event.pubkey, &db_event.timestamp, &db_event.kind, &db_event.content, &db_event.cumulative_hash, &sig, &raw_event, &expiration),
+	)?;
+	let event_id = conn.last_insert_rowid();
+	insert_event_tags(conn, event_id, event)?;
+	conn.execute("INSERT INTO event_search (rowid, content) VALUES (?1, ?2)", (event_id, &event.content))?;
+
+	Ok(update)
+}
 
-		for event in event_iter {
-			hashes.push(event.unwrap());
+/// Whether the deletion event requests the deletion of the event: the author
+/// is the same and either an `e` tag references the event id or an `a` tag
+/// references its coordinates, for the versions up to the deletion.
+pub(crate) fn deletes(deletion: &Event, event: &Event) -> bool {
+	if !is_deletion(deletion) || is_deletion(event) || deletion.pubkey != event.pubkey {
+		return false;
+	}
+	let event_id = event.id.to_hex();
+	let event_d = d_tag(event);
+	for tag in deletion.tags.iter() {
+		let tag = tag.as_vec();
+		if tag.len() < 2 { continue }
+		match tag[0].as_str() {
+			"e" => if tag[1] == event_id { return true; },
+			"a" => {
+				let coordinates: Vec<&str> = tag[1].splitn(3, ':').collect();
+				if coordinates.len() < 2 { continue }
+				let d = coordinates.get(2).cloned().unwrap_or("");
+				if coordinates[0] == event.kind.as_u32().to_string() && coordinates[1] == event.pubkey.to_string() && d == event_d && event.created_at <= deletion.created_at {
+					return true;
+				}
+			},
+			_ => {},
 		}
-
-		return Some(hashes);
 	}
-
-	None
+	false
 }
 
-pub async fn write_new_client_db(client: NostrClient) {
+/// Whether the event is a newer version of the previous replaceable event:
+/// same author, kind and d tag for the parameterized ones, and a later
+/// timestamp or, on a tie, the lowest id.
+pub(crate) fn replaces(event: &Event, previous: &Event) -> bool {
+	if !(is_replaceable(event) || is_parameterized_replaceable(event)) || event.id == previous.id {
+		return false;
+	}
+	if event.pubkey != previous.pubkey || event.kind != previous.kind {
+		return false;
+	}
+	if is_parameterized_replaceable(event) && d_tag(event) != d_tag(previous) {
+		return false;
+	}
+	event.created_at > previous.created_at || (event.created_at == previous.created_at && event.id.as_bytes() < previous.id.as_bytes())
+}
 
-	//TODO: spawn new thread
-	if let Ok(mut conn) = Connection::open_with_flags(
-		Path::new(CIVKITD_DB_FILE),
-		OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
-	) {
-		println!("[CIVKITD] - NOTE PROCESSING: Opening database for read / write new client");
+/// Returns the stored versions of a replaceable event, empty for the other
+/// events.
+pub(crate) fn replaceable_versions(conn: &Connection, event: &Event) -> rusqlite::Result<Vec<(i64, Event)>> {
+	if !(is_replaceable(event) || is_parameterized_replaceable(event)) {
+		return Ok(Vec::new());
+	}
+	let params = [SqlValue::Integer(event.kind.as_u32() as i64), SqlValue::Blob(event.pubkey.serialize().to_vec())];
+	let mut versions = stored_events(conn, "SELECT event_id, raw_event FROM event WHERE kind = ? AND pubkey = ? AND raw_event IS NOT NULL AND replaced_by IS NULL AND deleted_by IS NULL", &params)?;
+	versions.retain(|(_, previous)| previous.id != event.id && (!is_parameterized_replaceable(event) || d_tag(event) == d_tag(previous)));
+	Ok(versions)
+}
 
-		match conn.execute("CREATE TABLE client (
-			client_id	INTEGER PRIMARY KEY,
-			data		BLOB
-		)",
-		()) {
-			Ok(create) => println!("[CIVKITD] - NOTE PROCESSING: {} rows were updated", create),
-			Err(err) => println!("[CIVKITD] - NOTE PROCESSING: table creation failed: {}", err),
+fn stored_events(conn: &Connection, sql: &str, params: &[SqlValue]) -> rusqlite::Result<Vec<(i64, Event)>> {
+	let mut stmt = conn.prepare(sql)?;
+	let rows = stmt.query_map(params_from_iter(params.iter()), |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+	let mut events = Vec::new();
+	for row in rows {
+		let (event_id, raw_event) = row?;
+		if let Ok(event) = Event::from_json(raw_event) {
+			events.push((event_id, event));
 		}
+	}
+	Ok(events)
+}
 
-		let client = DbClient {
-			client_id: 0,
-			data: None,
-		};
+/// Marks the stored events requested for deletion by a deletion event, they
+/// are not replayed anymore, and records its references to refuse the events
+/// received later. Returns the number of deleted events.
+pub(crate) fn delete_events(conn: &Connection, deletion: &Event) -> rusqlite::Result<usize> {
+	insert_deleted_refs(conn, deletion)?;
+	let mut params = vec![SqlValue::Blob(deletion.pubkey.serialize().to_vec())];
+	let mut ids = Vec::new();
+	let mut kinds = Vec::new();
+	for tag in deletion.tags.iter() {
+		let tag = tag.as_vec();
+		if tag.len() < 2 { continue }
+		match tag[0].as_str() {
+			"e" => if let Ok(id) = hex::decode(&tag[1]) { ids.push(SqlValue::Blob(id)); },
+			"a" => if let Some(kind) = tag[1].split(':').next().and_then(|kind| kind.parse::<i64>().ok()) { kinds.push(SqlValue::Integer(kind)); },
+			_ => {},
+		}
+	}
+	let ids_condition = in_condition("sha256", ids, &mut params);
+	let kinds_condition = in_condition("kind", kinds, &mut params);
+	let sql = format!("SELECT event_id, raw_event FROM event WHERE pubkey = ? AND raw_event IS NOT NULL AND deleted_by IS NULL AND ({} OR {})", ids_condition, kinds_condition);
+
+	let mut deleted = 0;
+	for (event_id, event) in stored_events(conn, &sql, &params)? {
+		if deletes(deletion, &event) {
+			deleted += conn.execute("UPDATE event SET deleted_by = ?1 WHERE event_id = ?2", (deletion.id.as_bytes().to_vec(), event_id))?;
+		}
+	}
+	Ok(deleted)
+}
 
-		match conn.execute("INSERT INTO client (data) VALUES (:data)",
-			&[(&client.data)],
-		) {
-			Ok(update) => println!("[CIVKITD] - NOTE PROCESSING: {} rows were updated", update),
-			Err(err) => println!("[CIVKITD] - NOTE PROCESSING: update insert failed: {}", err),
+/// The references to the events of its author a deletion requests the
+/// deletion of: the ids of its `e` tags and the coordinates of its `a` tags,
+/// as `<kind>:<pubkey>:<d>`.
+pub(crate) fn deleted_refs(deletion: &Event) -> Vec<String> {
+	let mut refs = Vec::new();
+	if !is_deletion(deletion) { return refs; }
+	let author = deletion.pubkey.to_string();
+	for tag in deletion.tags.iter() {
+		let tag = tag.as_vec();
+		if tag.len() < 2 { continue }
+		match tag[0].as_str() {
+			"e" => if EventId::from_hex(&tag[1]).is_ok() { refs.push(tag[1].clone()); },
+			"a" => {
+				let coordinates: Vec<&str> = tag[1].splitn(3, ':').collect();
+				if coordinates.len() < 2 || coordinates[1] != author { continue }
+				refs.push(format!("{}:{}:{}", coordinates[0], coordinates[1], coordinates.get(2).cloned().unwrap_or("")));
+			},
+			_ => {},
 		}
+	}
+	refs
+}
 
-		conn.close().ok();
-	} else { println!("Failure to open database"); }
+/// The coordinates of the event, as referenced by the deletions.
+fn coordinates(event: &Event) -> String {
+	format!("{}:{}:{}", event.kind.as_u32(), event.pubkey, d_tag(event))
 }
 
-pub async fn print_clients_db() {
+/// Records the references of the deletion, the latest deletion time of a
+/// reference is kept.
+fn insert_deleted_refs(conn: &Connection, deletion: &Event) -> rusqlite::Result<()> {
+	let mut stmt = conn.prepare_cached("INSERT INTO deleted_ref (reference, pubkey, deleted_at) VALUES (?1, ?2, ?3)
+		ON CONFLICT (reference, pubkey) DO UPDATE SET deleted_at = MAX(deleted_at, excluded.deleted_at)")?;
+	let pubkey = deletion.pubkey.serialize().to_vec();
+	for reference in deleted_refs(deletion) {
+		stmt.execute((&reference, &pubkey, deletion.created_at.as_i64()))?;
+	}
+	Ok(())
+}
 
-	if let Ok(mut conn) = Connection::open_with_flags(
-		Path::new(CIVKITD_DB_FILE),
-		OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
-	) {
-		println!("[CIVKITD] - NOTE PROCESSING: Opening database for read clients");
+/// Whether a stored deletion event requests the deletion of the event, which
+/// must not be stored again: its id is referenced, or its coordinates by a
+/// deletion not older than the event.
+pub(crate) fn is_deleted(conn: &Connection, event: &Event) -> rusqlite::Result<bool> {
+	if is_deletion(event) { return Ok(false); }
+	let mut stmt = conn.prepare_cached("SELECT EXISTS (SELECT 1 FROM deleted_ref WHERE pubkey = ?1 AND (reference = ?2 OR (reference = ?3 AND deleted_at >= ?4)))")?;
+	stmt.query_row((event.pubkey.serialize().to_vec(), event.id.to_hex(), coordinates(event), event.created_at.as_i64()), |row| row.get(0))
+}
 
-		{
-			let mut stmt = conn.prepare("SELECT client_id, data FROM client").unwrap();
-			let client_iter = stmt.query_map([], |row| {
-				Ok(DbClient {
-					client_id: row.get(0)?,
-					data: row.get(1)?,
-				})
-			}).unwrap();
+/// Turns the served events matching the condition into tombstones: the event
+/// content, signature and tags are dropped, the id and the cumulative hash are
+/// kept for the Mainstay commitments.
+fn tombstone_events(conn: &Connection, condition: &str, params: &[SqlValue], now: u64) -> rusqlite::Result<u64> {
+	let condition = format!("raw_event IS NOT NULL AND ({})", condition);
+	conn.execute(&format!("DELETE FROM event_tag WHERE event_id IN (SELECT event_id FROM event WHERE {})", condition), params_from_iter(params.iter()))?;
+	conn.execute(&format!("DELETE FROM event_search WHERE rowid IN (SELECT event_id FROM event WHERE {})", condition), params_from_iter(params.iter()))?;
+	let mut update_params = vec![SqlValue::Integer(now as i64)];
+	update_params.extend(params.iter().cloned());
+	let pruned = conn.execute(&format!("UPDATE event SET content = NULL, sig = NULL, raw_event = NULL, pruned_at = ? WHERE {}", condition), params_from_iter(update_params.iter()))?;
+	Ok(pruned as u64)
+}
 
-			for client in client_iter {
-				println!("[CIVKITD] - NOTE PROCESSING: Found client {:?}", client.unwrap());
-			}
+/// Prunes the expired events (NIP-40), the deleted and replaced ones, the
+/// events older than their max age and then the oldest ones beyond the max
+/// number of events. The deletions are neither aged out nor evicted, nor
+/// counted in the max number of events.
+pub(crate) fn prune_events(conn: &Connection, policy: &RetentionPolicy, now: u64) -> rusqlite::Result<PruneStats> {
+	let tx = conn.unchecked_transaction()?;
+	let mut stats = PruneStats::default();
+
+	stats.expired = tombstone_events(&tx, "expiration IS NOT NULL AND expiration <= ?", &[SqlValue::Integer(now as i64)], now)?;
+	stats.hidden = tombstone_events(&tx, "deleted_by IS NOT NULL OR replaced_by IS NOT NULL", &[], now)?;
+
+	let mut overridden_kinds = vec![SqlValue::Integer(DELETION_KIND as i64)];
+	for (kind, max_age) in policy.max_event_age_per_kind.iter() {
+		if *kind == DELETION_KIND { continue }
+		overridden_kinds.push(SqlValue::Integer(*kind as i64));
+		if *max_age == 0 { continue }
+		let params = [SqlValue::Integer(*kind as i64), SqlValue::Integer(now.saturating_sub(*max_age) as i64)];
+		stats.outdated += tombstone_events(&tx, "kind = ? AND timestamp < ?", &params, now)?;
+	}
+	if policy.max_event_age > 0 {
+		let mut params = vec![SqlValue::Integer(now.saturating_sub(policy.max_event_age) as i64)];
+		let kinds_condition = in_condition("kind", overridden_kinds, &mut params);
+		stats.outdated += tombstone_events(&tx, &format!("timestamp < ? AND NOT {}", kinds_condition), &params, now)?;
+	}
+
+	if policy.max_events > 0 {
+		let served: i64 = tx.query_row("SELECT COUNT(*) FROM event WHERE raw_event IS NOT NULL AND kind != ?1", [DELETION_KIND], |row| row.get(0))?;
+		let excess = served - policy.max_events as i64;
+		if excess > 0 {
+			let params = [SqlValue::Integer(DELETION_KIND as i64), SqlValue::Integer(excess)];
+			stats.evicted = tombstone_events(&tx, "event_id IN (SELECT event_id FROM event WHERE raw_event IS NOT NULL AND kind != ? ORDER BY timestamp ASC, event_id ASC LIMIT ?)", &params, now)?;
 		}
+	}
 
-		conn.close().ok();
-	} else { println!("Failure to open database"); }
+	tx.commit()?;
+	Ok(stats)
 }
 
-pub async fn write_new_subscription_db(subscription: NostrSub) {
-
-	if let Ok(conn) = Connection::open_in_memory() {
-		conn.execute("CREATE TABLE event (
-			sub_id		INTEGER PRIMARY KEY,
-			data		BLOB
-		)",
-		());
+/// Whether one of the credentials is already spent, or repeated.
+pub(crate) fn is_spent(conn: &Connection, credentials: &[Vec<u8>]) -> rusqlite::Result<bool> {
+	if credentials.iter().collect::<HashSet<_>>().len() != credentials.len() {
+		return Ok(true);
+	}
+	let mut stmt = conn.prepare_cached("SELECT EXISTS (SELECT 1 FROM spent_credential WHERE credential = ?1)")?;
+	for credential in credentials {
+		if stmt.query_row([credential], |row| row.get::<_, bool>(0))? {
+			return Ok(true);
+		}
+	}
+	Ok(false)
+}
 
-		let subscription = DbSub {
-			sub_id: 0,
-			data: None,
-		};
+/// Records the credentials as spent in the caller transaction, unless one of
+/// them is already spent or repeated. The oldest spent credentials beyond
+/// `max_spent` are forgotten, 0 for no bound.
+pub(crate) fn spend_credentials(conn: &Connection, credentials: &[Vec<u8>], max_spent: u64) -> rusqlite::Result<bool> {
+	if is_spent(conn, credentials)? {
+		return Ok(false);
+	}
+	let mut stmt = conn.prepare_cached("INSERT INTO spent_credential (credential, spent_at) VALUES (?1, strftime('%s', 'now'))")?;
+	for credential in credentials {
+		stmt.execute([credential])?;
+	}
+	if max_spent > 0 {
+		conn.execute("DELETE FROM spent_credential WHERE spent_credential_id IN
+			(SELECT spent_credential_id FROM spent_credential ORDER BY spent_credential_id DESC LIMIT -1 OFFSET ?1)",
+			[max_spent.min(i64::MAX as u64) as i64],
+		)?;
+	}
+	Ok(true)
+}
 
-		conn.execute(
-			"INSERT INTO event (data) VALUES (:data)",
-			&[(&subscription.data)],
-		);
+fn insert_event_tags(conn: &Connection, event_id: i64, event: &Event) -> rusqlite::Result<()> {
+	let mut stmt = conn.prepare_cached("INSERT INTO event_tag (event_id, name, value) VALUES (?1, ?2, ?3)")?;
+	for tag in event.tags.iter() {
+		let tag = tag.as_vec();
+		if tag.len() < 2 { continue }
+		stmt.execute((event_id, &tag[0], &tag[1]))?;
 	}
+	Ok(())
 }
 
-pub async fn log_new_peer_db(peer: NostrPeer) {
+/// Records a new client connection, the relay client ids restart from one on
+/// every civkitd start.
+pub(crate) fn insert_client(conn: &Connection, client: &NostrClient) -> rusqlite::Result<usize> {
+	conn.execute("INSERT INTO client (relay_client_id, address, connected_at) VALUES (?1, ?2, strftime('%s', 'now'))",
+		(client.client_id as i64, client.associated_socket.to_string()),
+	)
+}
 
-	if let Ok(conn) = Connection::open_in_memory() {
-		conn.execute("CREATE TABLE event (
-			event_id	INTEGER PRIMARY KEY,
-			data		BLOB
-		)",
-		());
+/// Closes the last connection recorded for the relay client id, with the key
+/// the client authenticated with if any.
+pub(crate) fn record_client_disconnect(conn: &Connection, client: &NostrClient) -> rusqlite::Result<usize> {
+	let pubkey = client.pubkey.map(|pubkey| pubkey.serialize().to_vec());
+	conn.execute("UPDATE client SET pubkey = ?1, disconnected_at = strftime('%s', 'now')
+		WHERE client_id = (SELECT MAX(client_id) FROM client WHERE relay_client_id = ?2 AND disconnected_at IS NULL)",
+		(pubkey, client.client_id as i64),
+	)
+}
 
-		//let event = DbEvent {
-		//	id: 0,
-		//	kind: 0,
-		//	data: None,
-		//};
+/// The schema migrations, the schema version is the index of the last applied
+/// migration plus one. Migrations are append-only.
+const MIGRATIONS: &[fn(&Connection) -> rusqlite::Result<()>] = &[
+	migrate_to_v1,
+	migrate_to_v2,
+	migrate_to_v3,
+	migrate_to_v4,
+	migrate_to_v5,
+	migrate_to_v6,
+	migrate_to_v7,
+	migrate_to_v8,
+];
+
+/// Applies all the migrations not yet recorded in the `schema_version` table,
+/// each in its own transaction, and returns the resulting schema version.
+pub(crate) fn migrate_db(conn: &mut Connection) -> rusqlite::Result<u32> {
+	conn.execute("CREATE TABLE IF NOT EXISTS schema_version (
+		version			INTEGER PRIMARY KEY,
+		applied_at		BIG INT
+	)", ())?;
+
+	let mut version: u32 = conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))?;
+
+	while (version as usize) < MIGRATIONS.len() {
+		let tx = conn.transaction()?;
+		MIGRATIONS[version as usize](&tx)?;
+		version += 1;
+		tx.execute("INSERT INTO schema_version (version, applied_at) VALUES (?1, strftime('%s', 'now'))", [version])?;
+		tx.commit()?;
+		println!("[CIVKITD] - NOTE PROCESSING: Database schema migrated to version {}", version);
+	}
 
-		//conn.execute(
-		//	"INSERT INTO event (data) VALUES (:data)",
-		//	&[(&event.data)],
-		//);
+	Ok(version)
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+	let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+	let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+	for name in columns {
+		if name? == column { return Ok(true); }
 	}
+	Ok(false)
 }
 
-//TODO: log function for client
+/// Version 1: store the complete signed events and index their tags.
+///
+/// Databases created before schema versioning have an `event` table without
+/// signature. Those events are kept for the cumulative hash chain but can't
+/// be replayed to clients. The duplicates accepted back then are kept too,
+/// marked with the first insertion of their id, as they are chained.
+fn migrate_to_v1(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute("CREATE TABLE IF NOT EXISTS event (
+		event_id			INTEGER PRIMARY KEY,
+		sha256				BLOB,
+		pubkey				BLOB,
+		timestamp			BIG INT,
+		kind				UNSIGNED INTEGER,
+		content				TEXT,
+		cumulative_hash 	BLOB,
+		sig					TEXT,
+		raw_event			TEXT,
+		duplicate_of		INTEGER
+	)", ())?;
+	if !has_column(conn, "event", "sig")? {
+		conn.execute("ALTER TABLE event ADD COLUMN sig TEXT", ())?;
+	}
+	if !has_column(conn, "event", "raw_event")? {
+		conn.execute("ALTER TABLE event ADD COLUMN raw_event TEXT", ())?;
+	}
+	if !has_column(conn, "event", "duplicate_of")? {
+		conn.execute("ALTER TABLE event ADD COLUMN duplicate_of INTEGER", ())?;
+	}
 
-pub async fn write_new_inclusion_proof_db(inclusion_proof: &mut InclusionProof) {
+	// Duplicates were accepted before, only the first insertion is unique.
+	conn.execute("UPDATE event SET duplicate_of = (SELECT MIN(first.event_id) FROM event AS first WHERE first.sha256 = event.sha256), sig = NULL, raw_event = NULL
+		WHERE event_id NOT IN (SELECT MIN(event_id) FROM event GROUP BY sha256)", ())?;
+	conn.execute_batch("
+		CREATE UNIQUE INDEX IF NOT EXISTS event_sha256_idx ON event (sha256) WHERE duplicate_of IS NULL;
+		CREATE INDEX IF NOT EXISTS event_pubkey_idx ON event (pubkey);
+		CREATE INDEX IF NOT EXISTS event_kind_timestamp_idx ON event (kind, timestamp);
+		CREATE INDEX IF NOT EXISTS event_timestamp_idx ON event (timestamp);
+
+		CREATE TABLE IF NOT EXISTS event_tag (
+			event_id		INTEGER NOT NULL REFERENCES event (event_id) ON DELETE CASCADE,
+			name			TEXT NOT NULL,
+			value			TEXT NOT NULL
+		);
+		CREATE INDEX IF NOT EXISTS event_tag_name_value_idx ON event_tag (name, value);
+		CREATE INDEX IF NOT EXISTS event_tag_event_id_idx ON event_tag (event_id);
 
-	//TODO: spawn new thread
-	if let Ok(mut conn) = Connection::open_with_flags(
-		Path::new(CIVKITD_DB_FILE),
-		OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
-	) {
-		println!("[CIVKITD] - NOTE PROCESSING: Opening database for read / write new inclusion proof");
+		CREATE TABLE IF NOT EXISTS client (
+			client_id	INTEGER PRIMARY KEY,
+			data		BLOB
+		);
 
-		match conn.execute("CREATE TABLE inclusion_proof (
+		CREATE TABLE IF NOT EXISTS inclusion_proof (
 			inclusion_proof_id	INTEGER PRIMARY KEY,
 			txid				BLOB,
 			commitment          BLOB,
 			merkle_root         BLOB,
 			ops					BLOB
-		)",
-		()) {
-			Ok(create) => println!("[CIVKITD] - NOTE PROCESSING: {} rows were updated", create),
-			Err(err) => println!("[CIVKITD] - NOTE PROCESSING: table creation failed: {}", err),
+		);
+	")?;
+
+	// Index the tags of the events stored with their raw form.
+	let mut stored_events = Vec::new();
+	{
+		let mut stmt = conn.prepare("SELECT event_id, raw_event FROM event WHERE raw_event IS NOT NULL")?;
+		let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+		for row in rows {
+			stored_events.push(row?);
+		}
+	}
+	for (event_id, raw_event) in stored_events {
+		if let Ok(event) = Event::from_json(raw_event) {
+			insert_event_tags(conn, event_id, &event)?;
 		}
+	}
 
-		let inclusion_proof = DbInclusionProof {
-			inclusion_proof_id: 0,
-			txid: inclusion_proof.txid.lock().unwrap().as_bytes().to_vec(),
-			commitment: inclusion_proof.commitment.lock().unwrap().as_bytes().to_vec(),
-			merkle_root: inclusion_proof.merkle_root.lock().unwrap().as_bytes().to_vec(),
-			ops: Some(ops_to_json_string(inclusion_proof.ops.clone())),
-		};
+	Ok(())
+}
 
-		match conn.execute("INSERT INTO inclusion_proof (txid, commitment, merkle_root, ops) VALUES (?1, ?2, ?3, ?4)",
-			(&inclusion_proof.txid, &inclusion_proof.commitment, &inclusion_proof.merkle_root, &inclusion_proof.ops),
-		) {
-			Ok(update) => println!("[CIVKITD] - NOTE PROCESSING: {} rows were updated", update),
-			Err(err) => println!("[CIVKITD] - NOTE PROCESSING: update insert failed: {}", err),
+/// Version 2: record the client connections and disconnections.
+fn migrate_to_v2(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute_batch("
+		ALTER TABLE client ADD COLUMN relay_client_id INTEGER;
+		ALTER TABLE client ADD COLUMN address TEXT;
+		ALTER TABLE client ADD COLUMN pubkey BLOB;
+		ALTER TABLE client ADD COLUMN connected_at BIG INT;
+		ALTER TABLE client ADD COLUMN disconnected_at BIG INT;
+		CREATE INDEX IF NOT EXISTS client_relay_client_id_idx ON client (relay_client_id);
+	")
+}
+
+/// Version 3: mark the events deleted by their author (NIP-09).
+fn migrate_to_v3(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute("ALTER TABLE event ADD COLUMN deleted_by BLOB", ())?;
+	Ok(())
+}
+
+/// Version 4: mark the replaceable events replaced by a newer version
+/// (NIP-16/NIP-33).
+fn migrate_to_v4(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute("ALTER TABLE event ADD COLUMN replaced_by BLOB", ())?;
+	conn.execute("CREATE INDEX IF NOT EXISTS event_pubkey_kind_idx ON event (pubkey, kind)", ())?;
+	Ok(())
+}
+
+/// Version 5: index the NIP-40 expiration of the events and record when they
+/// are pruned.
+fn migrate_to_v5(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute_batch("
+		ALTER TABLE event ADD COLUMN expiration BIG INT;
+		ALTER TABLE event ADD COLUMN pruned_at BIG INT;
+		CREATE INDEX IF NOT EXISTS event_expiration_idx ON event (expiration);
+		UPDATE event SET expiration = (SELECT CAST(value AS INTEGER) FROM event_tag WHERE event_tag.event_id = event.event_id AND event_tag.name = 'expiration' LIMIT 1)
+			WHERE EXISTS (SELECT 1 FROM event_tag WHERE event_tag.event_id = event.event_id AND event_tag.name = 'expiration');
+	")
+}
+
+/// Version 6: index the content of the served events for the NIP-50 search.
+fn migrate_to_v6(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute_batch("
+		CREATE VIRTUAL TABLE IF NOT EXISTS event_search USING fts5(content, tokenize = 'unicode61 remove_diacritics 0');
+		INSERT INTO event_search (rowid, content) SELECT event_id, content FROM event WHERE raw_event IS NOT NULL AND content IS NOT NULL;
+	")
+}
+
+/// Version 7: remember the credentials spent by the service deliverances,
+/// across restarts.
+fn migrate_to_v7(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute_batch("
+		CREATE TABLE IF NOT EXISTS spent_credential (
+			spent_credential_id	INTEGER PRIMARY KEY,
+			credential		BLOB NOT NULL UNIQUE,
+			spent_at		BIG INT
+		);
+	")
+}
+
+/// Version 8: index the references of the deletions, checked on every write.
+fn migrate_to_v8(conn: &Connection) -> rusqlite::Result<()> {
+	conn.execute_batch("
+		CREATE TABLE IF NOT EXISTS deleted_ref (
+			reference		TEXT NOT NULL,
+			pubkey			BLOB NOT NULL,
+			deleted_at		BIG INT NOT NULL,
+			PRIMARY KEY (reference, pubkey)
+		);
+	")?;
+	for (_, deletion) in stored_events(conn, "SELECT event_id, raw_event FROM event WHERE kind = 5 AND raw_event IS NOT NULL", &[])? {
+		insert_deleted_refs(conn, &deletion)?;
+	}
+	Ok(())
+}
+
+/// A SQL statement built from a NIP-01 filter with its bound parameters.
+#[derive(Debug)]
+pub(crate) struct FilterQuery {
+	pub(crate) sql: String,
+	pub(crate) params: Vec<SqlValue>,
+}
+
+/// The events served to the clients: not deleted, replaced or pruned. The
+/// expired events are not served, even before being pruned.
+const SERVED_CONDITION: &str = "raw_event IS NOT NULL AND deleted_by IS NULL AND replaced_by IS NULL AND (expiration IS NULL OR expiration > CAST(strftime('%s', 'now') AS INTEGER))";
+
+/// Translates every field of a NIP-01 filter in a SQL condition. All the
+/// client-supplied values are passed as bound parameters.
+pub(crate) fn build_filter_query(filter: &Filter) -> FilterQuery {
+	let mut params = Vec::new();
+	let (mut conditions, limit) = filter_conditions(filter, &mut params);
+	conditions.insert(0, String::from(SERVED_CONDITION));
+
+	let mut sql = format!("SELECT raw_event FROM event WHERE {} ORDER BY timestamp DESC, event_id DESC", conditions.join(" AND "));
+	if let Some(limit) = limit {
+		sql.push_str(" LIMIT ?");
+		params.push(SqlValue::Integer(limit));
+	}
+
+	FilterQuery { sql, params }
+}
+
+/// Counts the events matching any of the filters (NIP-45), their limits are
+/// ignored.
+pub(crate) fn build_count_query(filters: &[Filter]) -> FilterQuery {
+	let mut params = Vec::new();
+	let mut filters_conditions = Vec::new();
+	for filter in filters {
+		let (conditions, _) = filter_conditions(filter, &mut params);
+		filters_conditions.push(if conditions.is_empty() { String::from("1") } else { format!("({})", conditions.join(" AND ")) });
+	}
+	let filters_condition = if filters_conditions.is_empty() { String::from("0") } else { filters_conditions.join(" OR ") };
+
+	FilterQuery { sql: format!("SELECT COUNT(*) FROM event WHERE {} AND ({})", SERVED_CONDITION, filters_condition), params }
+}
+
+/// Returns the SQL conditions of the filter fields and the filter limit.
+fn filter_conditions(filter: &Filter, params: &mut Vec<SqlValue>) -> (Vec<String>, Option<i64>) {
+	let mut conditions = Vec::new();
+	let mut limit = None;
+
+	let filter_fields = match serde_json::to_value(filter) {
+		Ok(Value::Object(fields)) => fields,
+		_ => Map::new(),
+	};
+
+	for (field, value) in filter_fields.iter() {
+		if value.is_null() { continue }
+
+		match field.as_str() {
+			"ids" => conditions.push(prefixes_condition("sha256", value, params)),
+			"authors" => conditions.push(prefixes_condition("pubkey", value, params)),
+			"kinds" => {
+				let kinds: Vec<SqlValue> = value.as_array().map(|kinds| kinds.iter().filter_map(|k| k.as_i64()).map(SqlValue::Integer).collect()).unwrap_or_default();
+				conditions.push(in_condition("kind", kinds, params));
+			},
+			"since" => {
+				conditions.push(String::from("timestamp >= ?"));
+				params.push(SqlValue::Integer(value.as_i64().unwrap_or(i64::MAX)));
+			},
+			"until" => {
+				conditions.push(String::from("timestamp <= ?"));
+				params.push(SqlValue::Integer(value.as_i64().unwrap_or(i64::MIN)));
+			},
+			"limit" => { limit = value.as_i64(); },
+			"search" => if let Some(query) = value.as_str().and_then(fts_query) {
+				conditions.push(String::from("event_id IN (SELECT rowid FROM event_search WHERE event_search MATCH ?)"));
+				params.push(SqlValue::Text(query));
+			},
+			tag_query if is_tag_query(tag_query) => {
+				let values: Vec<SqlValue> = value.as_array().map(|values| values.iter().filter_map(|v| v.as_str()).map(|v| SqlValue::Text(v.to_string())).collect()).unwrap_or_default();
+				params.push(SqlValue::Text(tag_query[1..].to_string()));
+				let values_condition = in_condition("event_tag.value", values, params);
+				conditions.push(format!("EXISTS (SELECT 1 FROM event_tag WHERE event_tag.event_id = event.event_id AND event_tag.name = ? AND {})", values_condition));
+			},
+			_ => {},
 		}
+	}
 
-		conn.close().ok();
-	} else { println!("Failure to open database"); }
+	(conditions, limit)
 }
 
-pub async fn print_inclusion_proofs_db() {
+/// Matches a column storing 32-bytes values against a list of hex prefixes.
+fn prefixes_condition(column: &str, value: &Value, params: &mut Vec<SqlValue>) -> String {
+	let mut alternatives = Vec::new();
+	for prefix in value.as_array().map(|p| p.iter().filter_map(|p| p.as_str()).collect::<Vec<&str>>()).unwrap_or_default() {
+		if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_hexdigit()) { continue }
+		if prefix.len() == 64 {
+			if let Ok(bytes) = hex::decode(prefix) {
+				alternatives.push(format!("{} = ?", column));
+				params.push(SqlValue::Blob(bytes));
+			}
+		} else {
+			alternatives.push(format!("hex({}) LIKE ?", column));
+			params.push(SqlValue::Text(format!("{}%", prefix.to_uppercase())));
+		}
+	}
+	if alternatives.is_empty() { return String::from("0"); }
+	format!("({})", alternatives.join(" OR "))
+}
 
-	if let Ok(mut conn) = Connection::open_with_flags(
-		Path::new(CIVKITD_DB_FILE),
-		OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
-	) {
-		println!("[CIVKITD] - NOTE PROCESSING: Opening database for read inclusion proof");
+fn in_condition(column: &str, values: Vec<SqlValue>, params: &mut Vec<SqlValue>) -> String {
+	if values.is_empty() { return String::from("0"); }
+	let placeholders = vec!["?"; values.len()].join(", ");
+	params.extend(values);
+	format!("{} IN ({})", column, placeholders)
+}
 
-		{
-			let mut stmt = conn.prepare("SELECT inclusion_proof_id, txid, commitment, merkle_root, ops FROM inclusion_proof").unwrap();
-			let inclusion_proof_iter = stmt.query_map([], |row| {
-				Ok(DbInclusionProof {
-					inclusion_proof_id: row.get(0)?,
-					txid: row.get(1)?,
-					commitment: row.get(2)?,
-					merkle_root: row.get(3)?,
-					ops: row.get(4)?,
-				})
-			}).unwrap();
+/// Returns the number of served events matching any of the filters.
+pub(crate) fn count_events(conn: &Connection, filters: &[Filter]) -> rusqlite::Result<u64> {
+	let count_query = build_count_query(filters);
+	let count: i64 = conn.query_row(&count_query.sql, params_from_iter(count_query.params.iter()), |row| row.get(0))?;
+	Ok(count as u64)
+}
+
+/// Returns the stored signed events matching the filter, newest first.
+pub(crate) fn query_events(conn: &Connection, filter: &Filter) -> rusqlite::Result<Vec<Event>> {
+	let filter_query = build_filter_query(filter);
+
+	let mut stmt = conn.prepare(&filter_query.sql)?;
+	let raw_events = stmt.query_map(params_from_iter(filter_query.params.iter()), |row| row.get::<_, String>(0))?;
+
+	let mut result_events = Vec::new();
+	for raw_event in raw_events {
+		match Event::from_json(raw_event?) {
+			Ok(event) => result_events.push(event),
+			Err(err) => println!("[CIVKITD] - NOTE PROCESSING: stored event deserialization failure: {}", err),
+		}
+	}
+
+	Ok(result_events)
+}
+
+/// A backend keeping everything in memory, for tests and ephemeral relays.
+#[derive(Default)]
+pub struct MemoryStorage {
+	events: Vec<(Event, Vec<u8>)>,
+	/// The ids of the events deleted by their author.
+	deleted: HashSet<EventId>,
+	/// The references of the stored deletions, by author, with their latest
+	/// deletion time.
+	deleted_refs: HashMap<(XOnlyPublicKey, String), Timestamp>,
+	/// The ids of the replaceable events replaced by a newer version.
+	replaced: HashSet<EventId>,
+	/// The ids of the events pruned by the retention policy.
+	pruned: HashSet<EventId>,
+	clients: Vec<NostrClient>,
+	disconnected_clients: Vec<NostrClient>,
+	inclusion_proofs: Vec<(String, String, String, String)>,
+	/// The spent credentials, the oldest first.
+	spent_credentials: VecDeque<Vec<u8>>,
+}
+
+impl MemoryStorage {
+	pub fn new() -> Self {
+		MemoryStorage::default()
+	}
+
+	fn is_deleted(&self, event: &Event) -> bool {
+		if is_deletion(event) { return false; }
+		self.deleted_refs.contains_key(&(event.pubkey, event.id.to_hex()))
+			|| self.deleted_refs.get(&(event.pubkey, coordinates(event))).map_or(false, |deleted_at| event.created_at <= *deleted_at)
+	}
+
+	fn is_served(&self, event: &Event, now: u64) -> bool {
+		!self.deleted.contains(&event.id) && !self.replaced.contains(&event.id) && !self.pruned.contains(&event.id) && !is_expired(event, now)
+	}
+}
 
-			for inclusion_proof in inclusion_proof_iter {
-				println!("[CIVKITD] - NOTE PROCESSING: Found inclusion proof {:?}", inclusion_proof.unwrap());
+impl StorageBackend for MemoryStorage {
+	fn write_event(&mut self, event: &Event) -> Result<(), StorageError> {
+		if self.is_deleted(event) {
+			return Err(StorageError::Deleted);
+		}
+		if self.events.iter().any(|(stored, _)| stored.id == event.id) {
+			return Err(StorageError::Duplicate);
+		}
+		let previous_versions: Vec<EventId> = self.events.iter()
+			.filter(|(stored, _)| !self.deleted.contains(&stored.id) && !self.replaced.contains(&stored.id) && !self.pruned.contains(&stored.id))
+			.filter(|(stored, _)| replaces(event, stored) || replaces(stored, event))
+			.map(|(stored, _)| stored.id).collect();
+		if self.events.iter().any(|(stored, _)| previous_versions.contains(&stored.id) && !replaces(event, stored)) {
+			return Err(StorageError::Outdated);
+		}
+		self.replaced.extend(previous_versions);
+		let previous_hash = self.events.last().map(|(_, cumulative_hash)| cumulative_hash.clone());
+		let cumulative_hash = calculate_cumulative_hash(previous_hash, &event.id);
+		self.events.push((event.clone(), cumulative_hash));
+		if is_deletion(event) {
+			let deleted: Vec<EventId> = self.events.iter().filter(|(stored, _)| deletes(event, stored)).map(|(stored, _)| stored.id).collect();
+			self.deleted.extend(deleted);
+			for reference in deleted_refs(event) {
+				let deleted_at = self.deleted_refs.entry((event.pubkey, reference)).or_insert(event.created_at);
+				*deleted_at = (*deleted_at).max(event.created_at);
 			}
 		}
+		Ok(())
+	}
+
+	fn query_events(&mut self, filter: &Filter) -> Result<Vec<Event>, StorageError> {
+		let now = Timestamp::now().as_u64();
+		// Iterate from the last stored to break timestamp ties as the SQLite backend.
+		let compiled_filter = CompiledFilter::new(filter);
+		let mut events: Vec<Event> = self.events.iter().rev().map(|(event, _)| event).filter(|event| self.is_served(event, now) && compiled_filter.matches(event)).cloned().collect();
+		events.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+		if let Some(limit) = filter_limit(filter) {
+			events.truncate(limit as usize);
+		}
+		Ok(events)
+	}
+
+	fn count_events(&mut self, filters: &[Filter]) -> Result<u64, StorageError> {
+		let now = Timestamp::now().as_u64();
+		let compiled_filters: Vec<CompiledFilter> = filters.iter().map(CompiledFilter::new).collect();
+		Ok(self.events.iter().filter(|(event, _)| self.is_served(event, now) && match_filters(&compiled_filters, event)).count() as u64)
+	}
+
+	fn write_client(&mut self, client: &NostrClient) -> Result<(), StorageError> {
+		self.clients.push(client.clone());
+		Ok(())
+	}
+
+	fn write_client_disconnect(&mut self, client: &NostrClient) -> Result<(), StorageError> {
+		self.disconnected_clients.push(client.clone());
+		Ok(())
+	}
 
-		conn.close().ok();
-	} else { println!("Failure to open database"); }
+	fn write_inclusion_proof(&mut self, txid: String, commitment: String, merkle_root: String, ops: String) -> Result<(), StorageError> {
+		self.inclusion_proofs.push((txid, commitment, merkle_root, ops));
+		Ok(())
+	}
+
+	fn last_cumulative_hash(&mut self) -> Result<Option<Vec<u8>>, StorageError> {
+		Ok(self.events.last().map(|(_, cumulative_hash)| cumulative_hash.clone()))
+	}
+
+	fn event_hashes(&mut self) -> Result<Vec<Vec<u8>>, StorageError> {
+		Ok(self.events.iter().map(|(event, _)| event.id.as_bytes().to_vec()).collect())
+	}
+
+	fn prune_events(&mut self, policy: &RetentionPolicy, now: u64) -> Result<PruneStats, StorageError> {
+		let mut stats = PruneStats::default();
+		let mut served = Vec::new();
+		for (event, _) in self.events.iter() {
+			if self.pruned.contains(&event.id) { continue }
+			if is_expired(event, now) {
+				stats.expired += 1;
+			} else if self.deleted.contains(&event.id) || self.replaced.contains(&event.id) {
+				stats.hidden += 1;
+			} else if policy.is_outdated(event, now) {
+				stats.outdated += 1;
+			} else {
+				if event.kind.as_u32() != DELETION_KIND {
+					served.push((event.created_at, event.id));
+				}
+				continue;
+			}
+			self.pruned.insert(event.id);
+		}
+		if policy.max_events > 0 && served.len() as u64 > policy.max_events {
+			// A stable sort, the oldest stored first on timestamp ties.
+			served.sort_by(|a, b| a.0.cmp(&b.0));
+			let excess = served.len() - policy.max_events as usize;
+			for (_, id) in served.into_iter().take(excess) {
+				self.pruned.insert(id);
+				stats.evicted += 1;
+			}
+		}
+		Ok(stats)
+	}
+
+	fn write_paid_event(&mut self, event: &Event, credentials: &[Vec<u8>], max_spent: u64) -> Result<(), StorageError> {
+		if self.is_spent(credentials)? {
+			return Err(StorageError::Spent);
+		}
+		self.write_event(event)?;
+		self.spent_credentials.extend(credentials.iter().cloned());
+		while max_spent > 0 && self.spent_credentials.len() as u64 > max_spent {
+			self.spent_credentials.pop_front();
+		}
+		Ok(())
+	}
+
+	fn is_spent(&mut self, credentials: &[Vec<u8>]) -> Result<bool, StorageError> {
+		Ok(credentials.iter().collect::<HashSet<_>>().len() != credentials.len() || credentials.iter().any(|credential| self.spent_credentials.contains(credential)))
+	}
+
+	fn dump_events(&mut self) {
+		for (event, _) in self.events.iter() {
+			println!("[CIVKITD] - NOTE PROCESSING: Found event {:?}", event);
+		}
+	}
+
+	fn dump_clients(&mut self) {
+		for client in self.clients.iter() {
+			println!("[CIVKITD] - NOTE PROCESSING: Found client {:?}", client);
+		}
+		for client in self.disconnected_clients.iter() {
+			println!("[CIVKITD] - NOTE PROCESSING: Found disconnected client {:?}", client);
+		}
+	}
+
+	fn dump_inclusion_proofs(&mut self) {
+		for inclusion_proof in self.inclusion_proofs.iter() {
+			println!("[CIVKITD] - NOTE PROCESSING: Found inclusion proof {:?}", inclusion_proof);
+		}
+	}
+}
+
+pub async fn log_new_peer_db(peer: NostrPeer) {
+
+	if let Ok(conn) = Connection::open_in_memory() {
+		conn.execute("CREATE TABLE event (
+			event_id	INTEGER PRIMARY KEY,
+			data		BLOB
+		)",
+		());
+
+		//let event = DbEvent {
+		//	id: 0,
+		//	kind: 0,
+		//	data: None,
+		//};
+
+		//conn.execute(
+		//	"INSERT INTO event (data) VALUES (:data)",
+		//	&[(&event.data)],
+		//);
+	}
 }
 
 pub fn ops_to_json_string(ops: Arc<Mutex<Vec<Ops>>>) -> String {
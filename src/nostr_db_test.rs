@@ -0,0 +1,560 @@
+use crate::NostrClient;
+use crate::nostr_db::{adopt_legacy_db, build_count_query, build_filter_query, count_events, delete_events, deletes, insert_client, insert_event, is_deleted, migrate_db, prune_events, query_events, record_client_disconnect, replaces, MemoryStorage, SqliteStorage, Storage, StorageBackend, StorageError};
+use crate::mainstay::calculate_cumulative_hash;
+use crate::retention::{PruneStats, RetentionPolicy};
+use crate::test_utils::{build_event_at, build_filter};
+
+use nostr::{Event, EventBuilder, Filter, Keys, Kind, Tag};
+
+use rusqlite::Connection;
+
+use serde_json::json;
+
+fn setup_db(events: &[Event]) -> Connection {
+	let mut conn = Connection::open_in_memory().unwrap();
+	migrate_db(&mut conn).unwrap();
+	for event in events {
+		insert_event(&conn, event, event.id.as_bytes().to_vec()).unwrap();
+	}
+	conn
+}
+
+fn query_ids(conn: &Connection, value: serde_json::Value) -> Vec<String> {
+	query_events(conn, &build_filter(value)).unwrap().iter().map(|ev| ev.id.to_hex()).collect()
+}
+
+#[test]
+fn test_query_returns_original_signed_events() {
+	let keys = Keys::generate();
+	let tags = vec![Tag::parse(vec!["t".to_string(), "btcusd".to_string()]).unwrap()];
+	let event = EventBuilder::new(Kind::from(32500), "order", &tags).to_event(&keys).unwrap();
+	let conn = setup_db(&[event.clone()]);
+
+	let events = query_events(&conn, &Filter::new()).unwrap();
+	assert_eq!(events, vec![event.clone()]);
+	assert!(events[0].verify().is_ok());
+}
+
+#[test]
+fn test_query_filter_fields() {
+	let keys = Keys::generate();
+	let other_keys = Keys::generate();
+	let note = EventBuilder::new(Kind::from(1), "note", &[]).to_event(&keys).unwrap();
+	let order = EventBuilder::new(Kind::from(32500), "order", &[Tag::parse(vec!["e".to_string(), note.id.to_hex()]).unwrap()]).to_event(&other_keys).unwrap();
+	let conn = setup_db(&[note.clone(), order.clone()]);
+
+	assert_eq!(query_ids(&conn, json!({ "kinds": [32500] })), vec![order.id.to_hex()]);
+	assert_eq!(query_ids(&conn, json!({ "kinds": [1, 32500] })).len(), 2);
+	assert_eq!(query_ids(&conn, json!({ "ids": [note.id.to_hex()] })), vec![note.id.to_hex()]);
+	assert_eq!(query_ids(&conn, json!({ "ids": [&note.id.to_hex()[..6]] })), vec![note.id.to_hex()]);
+	assert_eq!(query_ids(&conn, json!({ "authors": [other_keys.public_key().to_string()] })), vec![order.id.to_hex()]);
+	assert_eq!(query_ids(&conn, json!({ "#e": [note.id.to_hex()] })), vec![order.id.to_hex()]);
+	assert!(query_ids(&conn, json!({ "#e": [order.id.to_hex()] })).is_empty());
+	assert!(query_ids(&conn, json!({ "since": note.created_at.as_i64() + 1000 })).is_empty());
+	assert!(query_ids(&conn, json!({ "until": note.created_at.as_i64() - 1000 })).is_empty());
+	assert!(query_ids(&conn, json!({ "kinds": [] })).is_empty());
+}
+
+#[test]
+fn test_query_limit_newest_first() {
+	let keys = Keys::generate();
+	let mut events = Vec::new();
+	for timestamp in [1000, 3000, 2000] {
+		events.push(build_event_at(&keys, 1, "", vec![], timestamp));
+	}
+	let conn = setup_db(&events);
+
+	let returned = query_events(&conn, &build_filter(json!({ "limit": 2 }))).unwrap();
+	let timestamps: Vec<i64> = returned.iter().map(|ev| ev.created_at.as_i64()).collect();
+	assert_eq!(timestamps, vec![3000, 2000]);
+}
+
+#[test]
+fn test_query_values_are_bound_parameters() {
+	let filter = build_filter(json!({ "#t": ["'; DROP TABLE event; --"], "authors": ["' OR 1=1 --"] }));
+	let filter_query = build_filter_query(&filter);
+
+	assert!(!filter_query.sql.contains("DROP TABLE"));
+	assert_eq!(filter_query.params.len(), 2);
+
+	let conn = setup_db(&[]);
+	assert!(query_events(&conn, &filter).unwrap().is_empty());
+}
+
+#[test]
+fn test_search_events() {
+	let keys = Keys::generate();
+	let sepa_order = build_event_at(&keys, 32500, "Selling BTC/EUR by SEPA", vec![], 100);
+	let revolut_order = build_event_at(&keys, 32500, "Buying BTC/EUR by Revolut", vec![], 200);
+	let note = build_event_at(&keys, 1, "sepa is slow", vec![], 300);
+	let conn = setup_db(&[sepa_order.clone(), revolut_order.clone(), note.clone()]);
+
+	assert_eq!(query_ids(&conn, json!({ "search": "btc eur" })), vec![revolut_order.id.to_hex(), sepa_order.id.to_hex()]);
+	assert_eq!(query_ids(&conn, json!({ "kinds": [32500], "search": "SEPA" })), vec![sepa_order.id.to_hex()]);
+	assert_eq!(query_ids(&conn, json!({ "search": "sepa", "limit": 1 })), vec![note.id.to_hex()]);
+	assert!(query_ids(&conn, json!({ "search": "paypal" })).is_empty());
+	assert!(query_ids(&conn, json!({ "search": "\"sepa\" OR" })).is_empty());
+
+	// The pruned events are removed from the index.
+	let policy = RetentionPolicy { max_events: 1, ..Default::default() };
+	prune_events(&conn, &policy, 1000).unwrap();
+	assert_eq!(query_ids(&conn, json!({ "search": "sepa" })), vec![note.id.to_hex()]);
+	let indexed: i64 = conn.query_row("SELECT COUNT(*) FROM event_search", [], |row| row.get(0)).unwrap();
+	assert_eq!(indexed, 1);
+}
+
+#[test]
+fn test_count_events() {
+	let keys = Keys::generate();
+	let board = |value: &str| vec![vec!["t".to_string(), value.to_string()]];
+	let orders: Vec<Event> = (0..3).map(|i| build_event_at(&keys, 32500, &format!("order {}", i), board("btceur"), 100 + i)).collect();
+	let other_order = build_event_at(&keys, 32500, "other order", board("btcusd"), 100);
+	let expired_order = build_event_at(&keys, 32500, "expired order", vec![vec!["t".to_string(), "btceur".to_string()], vec!["expiration".to_string(), "1000".to_string()]], 100);
+	let note = build_event_at(&keys, 1, "note", board("btceur"), 100);
+
+	let mut events = orders.clone();
+	events.extend([other_order, expired_order, note]);
+	let conn = setup_db(&events);
+
+	let count = |value: serde_json::Value| count_events(&conn, &[build_filter(value)]).unwrap();
+	assert_eq!(count(json!({ "kinds": [32500], "#t": ["btceur"] })), 3);
+	assert_eq!(count(json!({ "kinds": [32500] })), 4);
+	assert_eq!(count(json!({ "#t": ["btceur"], "limit": 1 })), 4);
+	assert_eq!(count(json!({})), 5);
+
+	// An event matching several filters is counted once.
+	let filters = vec![build_filter(json!({ "kinds": [32500] })), build_filter(json!({ "#t": ["btceur"] }))];
+	assert_eq!(count_events(&conn, &filters).unwrap(), 5);
+	assert_eq!(count_events(&conn, &[]).unwrap(), 0);
+	assert_eq!(build_count_query(&filters).params.len(), 3);
+}
+
+#[test]
+fn test_duplicate_event_rejected() {
+	let keys = Keys::generate();
+	let event = EventBuilder::new(Kind::from(1), "note", &[]).to_event(&keys).unwrap();
+	let conn = setup_db(&[event.clone()]);
+
+	let err = insert_event(&conn, &event, event.id.as_bytes().to_vec()).unwrap_err();
+	assert!(matches!(StorageError::from(err), StorageError::Duplicate));
+	assert_eq!(query_events(&conn, &Filter::new()).unwrap().len(), 1);
+
+	// The other constraint violations are not duplicate events.
+	conn.execute("INSERT INTO spent_credential (credential) VALUES (x'00')", ()).unwrap();
+	let err = conn.execute("INSERT INTO spent_credential (credential) VALUES (x'00')", ()).unwrap_err();
+	assert!(matches!(StorageError::from(err), StorageError::Sqlite(_)));
+}
+
+#[test]
+fn test_migrate_legacy_database() {
+	let mut conn = Connection::open_in_memory().unwrap();
+	// The event table as created before schema versioning.
+	conn.execute("CREATE TABLE event (
+		event_id			INTEGER PRIMARY KEY,
+		sha256				BLOB,
+		pubkey				BLOB,
+		timestamp			BIG INT,
+		kind				UNSIGNED INTEGER,
+		content				TEXT,
+		cumulative_hash 	BLOB
+	)", ()).unwrap();
+	for cumulative_hash in [vec![1], vec![2]] {
+		conn.execute("INSERT INTO event (sha256, pubkey, timestamp, kind, content, cumulative_hash) VALUES (x'00', x'01', 0, 1, 'legacy', ?1)", [cumulative_hash]).unwrap();
+	}
+
+	let version = migrate_db(&mut conn).unwrap();
+	assert!(version >= 1);
+	assert_eq!(migrate_db(&mut conn).unwrap(), version);
+
+	// The duplicates are kept in the cumulative hash chain.
+	let legacy_events: Vec<(Vec<u8>, Option<i64>)> = {
+		let mut stmt = conn.prepare("SELECT cumulative_hash, duplicate_of FROM event ORDER BY event_id").unwrap();
+		let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+		rows.map(|row| row.unwrap()).collect()
+	};
+	assert_eq!(legacy_events, vec![(vec![1], None), (vec![2], Some(1))]);
+	assert!(conn.execute("INSERT INTO event (sha256, cumulative_hash) VALUES (x'00', x'03')", ()).is_err());
+	// Legacy events have no signature and are not replayed.
+	assert!(query_events(&conn, &Filter::new()).unwrap().is_empty());
+
+	let keys = Keys::generate();
+	let tags = vec![Tag::parse(vec!["t".to_string(), "btcusd".to_string()]).unwrap()];
+	let event = EventBuilder::new(Kind::from(1), "note", &tags).to_event(&keys).unwrap();
+	insert_event(&conn, &event, event.id.as_bytes().to_vec()).unwrap();
+	assert_eq!(query_events(&conn, &build_filter(json!({ "#t": ["btcusd"] }))).unwrap(), vec![event]);
+}
+
+#[test]
+fn test_client_connections_recorded() {
+	let conn = setup_db(&[]);
+	let addr = "127.0.0.1:50021".parse().unwrap();
+	let keys = Keys::generate();
+
+	// A client id from a previous run of civkitd, never closed.
+	let stale_client = NostrClient::new(1, addr);
+	insert_client(&conn, &stale_client).unwrap();
+
+	let mut client = NostrClient::new(1, addr);
+	client.authenticate(keys.public_key());
+	insert_client(&conn, &client).unwrap();
+	assert_eq!(record_client_disconnect(&conn, &client).unwrap(), 1);
+
+	let sessions: Vec<(i64, String, Option<Vec<u8>>, bool)> = {
+		let mut stmt = conn.prepare("SELECT relay_client_id, address, pubkey, disconnected_at IS NOT NULL FROM client ORDER BY client_id").unwrap();
+		let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap();
+		rows.map(|row| row.unwrap()).collect()
+	};
+	assert_eq!(sessions, vec![
+		(1, "127.0.0.1:50021".to_string(), None, false),
+		(1, "127.0.0.1:50021".to_string(), Some(keys.public_key().serialize().to_vec()), true),
+	]);
+
+	// The last connection only is closed.
+	assert_eq!(record_client_disconnect(&conn, &stale_client).unwrap(), 1);
+	assert_eq!(record_client_disconnect(&conn, &stale_client).unwrap(), 0);
+}
+
+#[test]
+fn test_deletion_requests() {
+	let keys = Keys::generate();
+	let other_keys = Keys::generate();
+	let note = build_event_at(&keys, 1, "note", vec![], 100);
+	let order = build_event_at(&keys, 32500, "order", vec![vec!["d".to_string(), "order-1".to_string()]], 100);
+	let newer_order = build_event_at(&keys, 32500, "newer order", vec![vec!["d".to_string(), "order-1".to_string()]], 300);
+	let other_order = build_event_at(&keys, 32500, "other order", vec![vec!["d".to_string(), "order-2".to_string()]], 100);
+	let coordinates = format!("32500:{}:order-1", keys.public_key());
+
+	let deletion = build_event_at(&keys, 5, "cancelled", vec![vec!["e".to_string(), note.id.to_hex()], vec!["a".to_string(), coordinates]], 200);
+	assert!(deletes(&deletion, &note));
+	assert!(deletes(&deletion, &order));
+	assert!(!deletes(&deletion, &newer_order));
+	assert!(!deletes(&deletion, &other_order));
+
+	// Only the author can delete its events, and deletions can't be deleted.
+	let forged_deletion = build_event_at(&other_keys, 5, "forged", vec![vec!["e".to_string(), note.id.to_hex()]], 200);
+	assert!(!deletes(&forged_deletion, &note));
+	let undeletion = build_event_at(&keys, 5, "undelete", vec![vec!["e".to_string(), deletion.id.to_hex()]], 200);
+	assert!(!deletes(&undeletion, &deletion));
+}
+
+#[test]
+fn test_deleted_events_not_replayed() {
+	let keys = Keys::generate();
+	let note = build_event_at(&keys, 1, "note", vec![], 100);
+	let order = build_event_at(&keys, 32500, "order", vec![vec!["d".to_string(), "order-1".to_string()]], 100);
+	let kept_note = build_event_at(&keys, 1, "kept note", vec![], 100);
+	let coordinates = format!("32500:{}:order-1", keys.public_key());
+	let deletion = build_event_at(&keys, 5, "cancelled", vec![vec!["e".to_string(), note.id.to_hex()], vec!["a".to_string(), coordinates]], 200);
+
+	let conn = setup_db(&[note.clone(), order.clone(), kept_note.clone(), deletion.clone()]);
+	assert_eq!(delete_events(&conn, &deletion).unwrap(), 2);
+
+	let mut replayed = query_ids(&conn, json!({}));
+	replayed.sort();
+	let mut expected = vec![kept_note.id.to_hex(), deletion.id.to_hex()];
+	expected.sort();
+	assert_eq!(replayed, expected);
+
+	// The deleted events are kept for the cumulative hash chain.
+	let stored_events: i64 = conn.query_row("SELECT COUNT(*) FROM event", [], |row| row.get(0)).unwrap();
+	assert_eq!(stored_events, 4);
+
+	assert!(is_deleted(&conn, &note).unwrap());
+	assert!(is_deleted(&conn, &order).unwrap());
+	assert!(!is_deleted(&conn, &kept_note).unwrap());
+	// The versions of the order published after the deletion are kept.
+	let newer_order = build_event_at(&keys, 32500, "order", vec![vec!["d".to_string(), "order-1".to_string()]], 300);
+	assert!(!is_deleted(&conn, &newer_order).unwrap());
+}
+
+#[test]
+fn test_deleted_refs_migrated() {
+	let keys = Keys::generate();
+	let note = build_event_at(&keys, 1, "note", vec![], 100);
+	let deletion = build_event_at(&keys, 5, "cancelled", vec![vec!["e".to_string(), note.id.to_hex()]], 200);
+
+	// The deletions stored before the references were indexed.
+	let mut conn = setup_db(&[deletion]);
+	conn.execute_batch("DROP TABLE deleted_ref; DELETE FROM schema_version WHERE version = 8;").unwrap();
+	migrate_db(&mut conn).unwrap();
+
+	assert!(is_deleted(&conn, &note).unwrap());
+}
+
+#[test]
+fn test_replaceable_versions() {
+	let keys = Keys::generate();
+	let d = |value: &str| vec![vec!["d".to_string(), value.to_string()]];
+
+	let metadata = build_event_at(&keys, 0, "metadata", vec![], 100);
+	let newer_metadata = build_event_at(&keys, 0, "newer metadata", vec![], 200);
+	assert!(replaces(&newer_metadata, &metadata));
+	assert!(!replaces(&metadata, &newer_metadata));
+
+	// On a timestamp tie the lowest id is kept.
+	let tie = build_event_at(&keys, 0, "tie", vec![], 100);
+	let (lowest, highest) = if tie.id.as_bytes() < metadata.id.as_bytes() { (&tie, &metadata) } else { (&metadata, &tie) };
+	assert!(replaces(lowest, highest));
+	assert!(!replaces(highest, lowest));
+
+	// Parameterized replaceable events are replaced by d tag.
+	let order = build_event_at(&keys, 32500, "order", d("order-1"), 100);
+	let updated_order = build_event_at(&keys, 32500, "updated order", d("order-1"), 200);
+	let other_order = build_event_at(&keys, 32500, "other order", d("order-2"), 200);
+	assert!(replaces(&updated_order, &order));
+	assert!(!replaces(&other_order, &order));
+
+	// Regular events and other authors never replace.
+	let other_metadata = build_event_at(&Keys::generate(), 0, "other metadata", vec![], 200);
+	assert!(!replaces(&other_metadata, &metadata));
+	let note = build_event_at(&keys, 1, "note", vec![], 100);
+	let newer_note = build_event_at(&keys, 1, "newer note", vec![], 200);
+	assert!(!replaces(&newer_note, &note));
+}
+
+async fn check_replaceable_events(backend: Box<dyn StorageBackend>) {
+	let storage = Storage::spawn(backend);
+	let keys = Keys::generate();
+	let d = |value: &str| vec![vec!["d".to_string(), value.to_string()]];
+	let stored_ids = |events: Vec<Event>| { let mut ids: Vec<String> = events.iter().map(|ev| ev.id.to_hex()).collect(); ids.sort(); ids };
+
+	let contacts = build_event_at(&keys, 3, "contacts", vec![], 100);
+	let newer_contacts = build_event_at(&keys, 3, "newer contacts", vec![], 200);
+	storage.write_event(contacts.clone()).await.unwrap();
+	storage.write_event(newer_contacts.clone()).await.unwrap();
+	assert!(matches!(storage.write_event(build_event_at(&keys, 3, "older contacts", vec![], 50)).await, Err(StorageError::Outdated)));
+	assert_eq!(storage.query_events(build_filter(json!({ "kinds": [3] }))).await.unwrap(), vec![newer_contacts]);
+
+	let order = build_event_at(&keys, 32500, "order", d("order-1"), 100);
+	let updated_order = build_event_at(&keys, 32500, "updated order", d("order-1"), 200);
+	let other_order = build_event_at(&keys, 32500, "other order", d("order-2"), 100);
+	storage.write_event(order.clone()).await.unwrap();
+	storage.write_event(other_order.clone()).await.unwrap();
+	storage.write_event(updated_order.clone()).await.unwrap();
+	assert_eq!(stored_ids(storage.query_events(build_filter(json!({ "kinds": [32500] }))).await.unwrap()), stored_ids(vec![updated_order, other_order]));
+
+	// The replaced events are kept in the cumulative hash chain.
+	assert_eq!(storage.event_hashes().await.unwrap().len(), 5);
+}
+
+async fn check_storage_backend(backend: Box<dyn StorageBackend>) {
+	let storage = Storage::spawn(backend);
+	let keys = Keys::generate();
+	let note = EventBuilder::new(Kind::from(1), "note", &[]).to_event(&keys).unwrap();
+	let order = EventBuilder::new(Kind::from(32500), "order", &[]).to_event(&keys).unwrap();
+
+	assert_eq!(storage.last_cumulative_hash().await.unwrap(), None);
+	storage.write_event(note.clone()).await.unwrap();
+	storage.write_event(order.clone()).await.unwrap();
+	assert!(matches!(storage.write_event(note.clone()).await, Err(StorageError::Duplicate)));
+
+	let first_hash = calculate_cumulative_hash(None, &note.id);
+	assert_eq!(storage.last_cumulative_hash().await.unwrap(), Some(calculate_cumulative_hash(Some(first_hash), &order.id)));
+	assert_eq!(storage.event_hashes().await.unwrap(), vec![note.id.as_bytes().to_vec(), order.id.as_bytes().to_vec()]);
+
+	assert_eq!(storage.query_events(build_filter(json!({ "kinds": [32500] }))).await.unwrap(), vec![order.clone()]);
+	assert_eq!(storage.query_events(Filter::new()).await.unwrap().len(), 2);
+
+	let deletion = EventBuilder::new(Kind::from(5), "cancelled", &[Tag::parse(vec!["e".to_string(), order.id.to_hex()]).unwrap()]).to_event(&keys).unwrap();
+	storage.write_event(deletion.clone()).await.unwrap();
+	assert_eq!(storage.query_events(build_filter(json!({ "kinds": [32500] }))).await.unwrap(), vec![]);
+	assert_eq!(storage.query_events(build_filter(json!({ "kinds": [5] }))).await.unwrap(), vec![deletion]);
+	assert!(matches!(storage.write_event(order).await, Err(StorageError::Deleted)));
+	assert_eq!(storage.count_events(vec![Filter::new()]).await.unwrap(), 2);
+	assert_eq!(storage.count_events(vec![build_filter(json!({ "kinds": [1, 5] })), build_filter(json!({ "kinds": [5] }))]).await.unwrap(), 2);
+}
+
+#[test]
+fn test_expired_events_not_served() {
+	let keys = Keys::generate();
+	let expired = build_event_at(&keys, 1, "expired", vec![vec!["expiration".to_string(), "1000".to_string()]], 100);
+	let expiring = build_event_at(&keys, 1, "expiring", vec![vec!["expiration".to_string(), "99999999999".to_string()]], 100);
+	let note = build_event_at(&keys, 1, "note", vec![], 100);
+
+	let conn = setup_db(&[expired.clone(), expiring.clone(), note.clone()]);
+	let mut served = query_ids(&conn, json!({}));
+	served.sort();
+	let mut expected = vec![expiring.id.to_hex(), note.id.to_hex()];
+	expected.sort();
+	assert_eq!(served, expected);
+}
+
+#[test]
+fn test_pruned_events_keep_hash_chain() {
+	let keys = Keys::generate();
+	let expired = build_event_at(&keys, 1, "expired", vec![vec!["expiration".to_string(), "1000".to_string()]], 100);
+	let old_note = build_event_at(&keys, 1, "old note", vec![vec!["t".to_string(), "old".to_string()]], 100);
+	let old_order = build_event_at(&keys, 32500, "old order", vec![], 100);
+	let notes: Vec<Event> = (0..3).map(|i| build_event_at(&keys, 1, &format!("note {}", i), vec![], 5000 + i)).collect();
+
+	let mut events = vec![expired.clone(), old_note.clone(), old_order.clone()];
+	events.extend(notes.iter().cloned());
+	let conn = setup_db(&events);
+	let hashes_before: Vec<Vec<u8>> = conn.prepare("SELECT cumulative_hash FROM event ORDER BY event_id").unwrap()
+		.query_map([], |row| row.get(0)).unwrap().map(|hash| hash.unwrap()).collect();
+
+	let mut policy = RetentionPolicy { max_event_age: 1000, max_events: 2, ..Default::default() };
+	policy.max_event_age_per_kind.insert(32500, 0);
+	let stats = prune_events(&conn, &policy, 5000).unwrap();
+	assert_eq!(stats, PruneStats { expired: 1, hidden: 0, outdated: 1, evicted: 2 });
+
+	// The oldest events beyond the max number of events are evicted.
+	let mut served = query_ids(&conn, json!({}));
+	served.sort();
+	let mut expected = vec![notes[1].id.to_hex(), notes[2].id.to_hex()];
+	expected.sort();
+	assert_eq!(served, expected);
+
+	// The tombstones keep the ids and the cumulative hashes, not the tags.
+	let hashes_after: Vec<Vec<u8>> = conn.prepare("SELECT cumulative_hash FROM event ORDER BY event_id").unwrap()
+		.query_map([], |row| row.get(0)).unwrap().map(|hash| hash.unwrap()).collect();
+	assert_eq!(hashes_after, hashes_before);
+	let pruned: i64 = conn.query_row("SELECT COUNT(*) FROM event WHERE pruned_at = 5000 AND raw_event IS NULL AND content IS NULL", [], |row| row.get(0)).unwrap();
+	assert_eq!(pruned, 4);
+	assert_eq!(query_ids(&conn, json!({ "#t": ["old"] })), Vec::<String>::new());
+	let pruned_tags: i64 = conn.query_row("SELECT COUNT(*) FROM event_tag WHERE name = 't'", [], |row| row.get(0)).unwrap();
+	assert_eq!(pruned_tags, 0);
+
+	// Pruning again is a no-op.
+	assert_eq!(prune_events(&conn, &policy, 5000).unwrap().total(), 0);
+}
+
+async fn check_prune_events(backend: Box<dyn StorageBackend>) {
+	let storage = Storage::spawn(backend);
+	let keys = Keys::generate();
+	let contacts = build_event_at(&keys, 3, "contacts", vec![], 4000);
+	let newer_contacts = build_event_at(&keys, 3, "newer contacts", vec![], 4500);
+	let expired = build_event_at(&keys, 1, "expired", vec![vec!["expiration".to_string(), "1000".to_string()]], 4000);
+	let note = build_event_at(&keys, 1, "note", vec![], 4000);
+	for event in [contacts, newer_contacts.clone(), expired, note.clone()] {
+		storage.write_event(event).await.unwrap();
+	}
+
+	let policy = RetentionPolicy { max_events: 1, ..Default::default() };
+	let stats = storage.prune_events(policy, 5000).await.unwrap();
+	assert_eq!(stats, PruneStats { expired: 1, hidden: 1, outdated: 0, evicted: 1 });
+	assert_eq!(storage.query_events(Filter::new()).await.unwrap(), vec![newer_contacts]);
+	assert_eq!(storage.event_hashes().await.unwrap().len(), 4);
+	assert!(matches!(storage.write_event(note).await, Err(StorageError::Duplicate)));
+}
+
+#[tokio::test]
+async fn test_prune_events_storage() {
+	check_prune_events(Box::new(SqliteStorage::open_in_memory().unwrap())).await;
+	check_prune_events(Box::new(MemoryStorage::new())).await;
+	check_pruned_deletions(Box::new(SqliteStorage::open_in_memory().unwrap())).await;
+	check_pruned_deletions(Box::new(MemoryStorage::new())).await;
+}
+
+async fn check_pruned_deletions(backend: Box<dyn StorageBackend>) {
+	let storage = Storage::spawn(backend);
+	let keys = Keys::generate();
+	let contacts = build_event_at(&keys, 3, "contacts", vec![], 1000);
+	let coordinates = format!("3:{}:", keys.public_key());
+	let deletion = build_event_at(&keys, 5, "", vec![vec!["a".to_string(), coordinates]], 2000);
+	let note = build_event_at(&keys, 1, "note", vec![], 4000);
+	for event in [contacts.clone(), deletion.clone(), note.clone()] {
+		storage.write_event(event).await.unwrap();
+	}
+
+	// The old deletion is neither aged out nor evicted.
+	let policy = RetentionPolicy { max_event_age: 100, max_events: 1, ..Default::default() };
+	let stats = storage.prune_events(policy, 4050).await.unwrap();
+	assert_eq!(stats, PruneStats { expired: 0, hidden: 1, outdated: 0, evicted: 0 });
+	assert_eq!(storage.query_events(Filter::new()).await.unwrap(), vec![note, deletion]);
+
+	let older_contacts = build_event_at(&keys, 3, "older contacts", vec![], 1500);
+	assert!(matches!(storage.write_event(older_contacts).await, Err(StorageError::Deleted)));
+}
+
+#[tokio::test]
+async fn test_sqlite_storage() {
+	check_storage_backend(Box::new(SqliteStorage::open_in_memory().unwrap())).await;
+}
+
+#[tokio::test]
+async fn test_memory_storage() {
+	check_storage_backend(Box::new(MemoryStorage::new())).await;
+}
+
+#[tokio::test]
+async fn test_replaceable_events_storage() {
+	check_replaceable_events(Box::new(SqliteStorage::open_in_memory().unwrap())).await;
+	check_replaceable_events(Box::new(MemoryStorage::new())).await;
+}
+
+async fn check_paid_events(backend: Box<dyn StorageBackend>) {
+	let storage = Storage::spawn(backend);
+	let keys = Keys::generate();
+	let note = |content: &str| build_event_at(&keys, 1, content, vec![], 4000);
+	let (first, second, third) = (vec![1; 32], vec![2; 32], vec![3; 32]);
+
+	storage.write_paid_event(note("first"), vec![first.clone(), second.clone()], 2).await.unwrap();
+	assert!(storage.is_spent(vec![first.clone()]).await.unwrap());
+	// A replay, even partial, writes nothing and spends none of the credentials.
+	assert!(matches!(storage.write_paid_event(note("second"), vec![third.clone(), first.clone()], 2).await, Err(StorageError::Spent)));
+	assert!(matches!(storage.write_paid_event(note("second"), vec![third.clone(), third.clone()], 2).await, Err(StorageError::Spent)));
+	assert!(!storage.is_spent(vec![third.clone()]).await.unwrap());
+	// A refused event does not spend its credentials.
+	assert!(matches!(storage.write_paid_event(note("first"), vec![third.clone()], 2).await, Err(StorageError::Duplicate)));
+	assert!(!storage.is_spent(vec![third.clone()]).await.unwrap());
+	assert_eq!(storage.query_events(Filter::new()).await.unwrap().len(), 1);
+
+	// The oldest spent credential is forgotten beyond the bound.
+	storage.write_paid_event(note("third"), vec![third.clone()], 2).await.unwrap();
+	assert!(storage.is_spent(vec![second]).await.unwrap());
+	assert!(storage.is_spent(vec![third]).await.unwrap());
+	assert!(!storage.is_spent(vec![first]).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_paid_events_storage() {
+	check_paid_events(Box::new(SqliteStorage::open_in_memory().unwrap())).await;
+	check_paid_events(Box::new(MemoryStorage::new())).await;
+}
+
+#[test]
+fn test_spent_credentials_survive_restart() {
+	let path = std::env::temp_dir().join(format!("civkitd-spent-credentials-{}.db", std::process::id()));
+	let credential = vec![7; 32];
+	let order = EventBuilder::new(Kind::from(32500), "order", &[]).to_event(&Keys::generate()).unwrap();
+	{
+		let mut storage = SqliteStorage::open(&path).unwrap();
+		storage.write_paid_event(&order, &[credential.clone()], 0).unwrap();
+		storage.flush().unwrap();
+	}
+	let mut storage = SqliteStorage::open(&path).unwrap();
+	assert!(storage.is_spent(&[credential]).unwrap());
+	drop(storage);
+	for suffix in ["", "-wal", "-shm"] {
+		let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+	}
+}
+
+#[test]
+fn test_adopt_legacy_db() {
+	let dir = std::env::temp_dir().join(format!("civkitd-legacy-db-{}", std::process::id()));
+	let data_dir = dir.join("data");
+	std::fs::create_dir_all(&data_dir).unwrap();
+	let legacy_path = dir.join("civkitd.db");
+	let db_path = data_dir.join("civkitd.db");
+
+	assert!(!adopt_legacy_db(&legacy_path, &db_path).unwrap());
+
+	let order = EventBuilder::new(Kind::from(32500), "order", &[]).to_event(&Keys::generate()).unwrap();
+	{
+		let mut storage = SqliteStorage::open(&legacy_path).unwrap();
+		storage.write_event(&order).unwrap();
+		storage.flush().unwrap();
+	}
+	assert!(adopt_legacy_db(&legacy_path, &db_path).unwrap());
+	assert!(!legacy_path.exists());
+	let mut storage = SqliteStorage::open(&db_path).unwrap();
+	assert_eq!(storage.query_events(&build_filter(json!({}))).unwrap().iter().map(|ev| ev.id).collect::<Vec<_>>(), vec![order.id]);
+	drop(storage);
+
+	// Both databases existing, none is picked.
+	SqliteStorage::open(&legacy_path).unwrap();
+	assert!(matches!(adopt_legacy_db(&legacy_path, &db_path), Err(StorageError::LegacyDatabase(_))));
+	let _ = std::fs::remove_dir_all(&dir);
+}
@@ -16,9 +16,10 @@ use bitcoin::secp256k1::{SecretKey, PublicKey};
 use bitcoin::secp256k1::Secp256k1;
 use bitcoin::secp256k1;
 
+use crate::shutdown::ShutdownSignal;
+
 use std::sync::Arc;
 use std::time::Duration;
-use std::thread;
 
 struct FakeLogger;
 impl Logger for FakeLogger {
@@ -54,12 +55,9 @@ impl OnionBox {
 		}
 	}
 
-	pub async fn run(&self) {
-		loop {
-			let one_second = Duration::from_secs(1);
-
-			thread::sleep(one_second);
-			//TODO: receive onion messages and send them to the CredentialGateway
-		}
+	pub async fn run(&self, mut shutdown: ShutdownSignal) {
+		//TODO: receive onion messages and send them to the CredentialGateway
+		shutdown.recv().await;
+		println!("[CIVKITD] - CONTROL: OnionBox shutting down");
 	}
 }
@@ -24,12 +24,12 @@ use crate::nostr_db::log_new_peer_db;
 use lightning_net_tokio::SocketDescriptor;
 
 use std::ops::Deref;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use crate::shutdown::ShutdownSignal;
 
-use std::thread::sleep;
+use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::sync::mpsc;
+use crate::bus::BusReceiver;
 
 pub struct PeerInfo {
 	pub local_port: u64,
@@ -51,11 +51,11 @@ impl Logger for FakeLogger {
 pub struct NoiseGateway {
 	pub peer_manager: Arc<PeerManager<SocketDescriptor, Arc<ErroringMessageHandler>, Arc<IgnoringMessageHandler>, IgnoringMessageHandler, Arc<FakeLogger>, IgnoringMessageHandler, Arc<KeysManager>>>,
 
-	gateway_receive: Mutex<mpsc::UnboundedReceiver<PeerInfo>>,
+	gateway_receive: BusReceiver<PeerInfo>,
 }
 
 impl NoiseGateway {
-	pub fn new(gateway_receive: mpsc::UnboundedReceiver<PeerInfo>) -> Self {
+	pub fn new(gateway_receive: BusReceiver<PeerInfo>) -> Self {
 		let secp_ctx = Secp256k1::new();
 		let pubkey = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42;32]).unwrap());
 		let ephemeral_bytes = [1 as u8; 32];
@@ -81,24 +81,21 @@ impl NoiseGateway {
 
 		NoiseGateway {
 			peer_manager,
-			gateway_receive: Mutex::new(gateway_receive),
+			gateway_receive,
 		}
 	}
 
-	pub async fn run(&self) {
+	pub async fn run(&mut self, mut shutdown: ShutdownSignal) {
 		loop {
-			let one_second = Duration::from_secs(1);
-
-			sleep(one_second);
-
-			let mut local_port = 0;
-			{
-				let mut gateway_receive_lock = self.gateway_receive.lock().unwrap();
-
-				if let Ok(peer) = gateway_receive_lock.try_recv() {
-					local_port = peer.local_port;
-				}
-			}
+			let local_port = tokio::select! {
+				_ = shutdown.recv() => {
+					println!("[CIVKITD] - CONTROL: NoiseGateway shutting down");
+					self.peer_manager.disconnect_all_peers();
+					break;
+				},
+				Some(peer) = self.gateway_receive.recv() => { peer.local_port },
+				else => { break; }
+			};
 			if local_port > 0 {
 				let peer_mngr = self.peer_manager.clone();
 				let secp_ctx = Secp256k1::new();
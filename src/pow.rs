@@ -0,0 +1,56 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! The NIP-13 proof-of-work required to publish events, a cheaper anti-spam
+//! than the staking credentials.
+
+use crate::rejection::RejectionReason;
+
+use nostr::Event;
+
+/// The number of leading zero bits of the event id.
+pub fn difficulty(id: &[u8]) -> u16 {
+	let mut zero_bits = 0;
+	for byte in id {
+		if *byte == 0 {
+			zero_bits += 8;
+		} else {
+			zero_bits += byte.leading_zeros() as u16;
+			break;
+		}
+	}
+	zero_bits
+}
+
+/// The target difficulty committed in the `nonce` tag of the event, if any.
+pub fn committed_target(event: &Event) -> Option<u8> {
+	event.tags.iter()
+		.map(|tag| tag.as_vec())
+		.find(|tag| tag.len() >= 3 && tag[0] == "nonce")
+		.and_then(|tag| tag[2].parse::<u8>().ok())
+}
+
+/// Checks the event id has the required difficulty. A target committed below
+/// the required difficulty is refused too, as the event has only been luckily
+/// mined (NIP-13).
+pub fn check_difficulty(event: &Event, required: u8) -> Result<(), RejectionReason> {
+	if required == 0 {
+		return Ok(());
+	}
+	let difficulty = difficulty(event.id.as_bytes());
+	if difficulty < required as u16 {
+		return Err(RejectionReason::Pow(format!("difficulty {} is less than {}", difficulty, required)));
+	}
+	if let Some(target) = committed_target(event) {
+		if target < required {
+			return Err(RejectionReason::Pow(format!("committed target {} is less than {}", target, required)));
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,77 @@
+use crate::admission::AdmissionPolicy;
+use crate::config::Config;
+use crate::pow::{committed_target, difficulty};
+use crate::rejection::RejectionReason;
+
+use nostr::{Event, EventBuilder, Keys, Kind, Tag};
+
+/// Mines an event until its id has the difficulty, committing to the target.
+fn mine_event(keys: &Keys, kind: u64, difficulty_bits: u8, target: u8) -> Event {
+	let mut nonce: u64 = 0;
+	loop {
+		let nonce_tag = Tag::parse(vec!["nonce".to_string(), nonce.to_string(), target.to_string()]).unwrap();
+		let event = EventBuilder::new(Kind::from(kind), "mined", &[nonce_tag]).to_event(keys).unwrap();
+		if difficulty(event.id.as_bytes()) >= difficulty_bits as u16 {
+			return event;
+		}
+		nonce += 1;
+	}
+}
+
+#[test]
+fn test_difficulty() {
+	assert_eq!(difficulty(&[0xff; 32]), 0);
+	assert_eq!(difficulty(&[0x00, 0x00, 0x0f, 0xff]), 20);
+	assert_eq!(difficulty(&[0x00, 0x80]), 8);
+	assert_eq!(difficulty(&[0x00; 32]), 256);
+}
+
+#[test]
+fn test_pow_policy() {
+	let mut config = Config::default();
+	config.spam_protection.min_pow_difficulty = 4;
+	config.spam_protection.pow_difficulty_per_kind.insert("32500".to_string(), 8);
+	config.spam_protection.pow_difficulty_per_kind.insert("1".to_string(), 0);
+	config.admission.kinds.clear();
+	let policy = AdmissionPolicy::new(&config);
+	assert_eq!(policy.requirement(32500).pow_difficulty, 8);
+	assert_eq!(policy.requirement(1).pow_difficulty, 0);
+	assert_eq!(policy.requirement(0).pow_difficulty, 4);
+
+	let keys = Keys::generate();
+	let order = mine_event(&keys, 32500, 8, 8);
+	assert_eq!(committed_target(&order), Some(8));
+	assert_eq!(policy.check_event(&order, false), Ok(()));
+
+	// A lucky event committing to a lower target is refused.
+	let lucky_order = mine_event(&keys, 32500, 8, 4);
+	assert!(matches!(policy.check_event(&lucky_order, false), Err(RejectionReason::Pow(_))));
+
+	let unmined_order = EventBuilder::new(Kind::from(32500), "unmined", &[]).to_event(&keys).unwrap();
+	if difficulty(unmined_order.id.as_bytes()) < 8 {
+		let reason = policy.check_event(&unmined_order, false).unwrap_err();
+		assert!(reason.to_string().starts_with("pow: difficulty"));
+	}
+
+	let note = EventBuilder::new(Kind::from(1), "note", &[]).to_event(&keys).unwrap();
+	assert_eq!(policy.check_event(&note, false), Ok(()));
+}
+
+#[test]
+fn test_pow_in_place_of_credentials() {
+	let mut config = Config::default();
+	config.spam_protection.pow_difficulty_per_kind.insert("32500".to_string(), 8);
+	let policy = AdmissionPolicy::new(&config);
+
+	let keys = Keys::generate();
+	let mined_order = mine_event(&keys, 32500, 8, 8);
+	assert_eq!(policy.check_event(&mined_order, false), Ok(()));
+	assert!(!policy.requires_credentials(&mined_order));
+
+	// Not mined, the order is paid with credentials.
+	let unmined_order = EventBuilder::new(Kind::from(32500), "unmined", &[]).to_event(&keys).unwrap();
+	if difficulty(unmined_order.id.as_bytes()) < 8 {
+		assert_eq!(policy.check_event(&unmined_order, false), Ok(()));
+		assert!(policy.requires_credentials(&unmined_order));
+	}
+}
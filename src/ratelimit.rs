@@ -0,0 +1,205 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Token-bucket rate limiting of the client messages, per connected client and
+//! per pubkey.
+
+use crate::config::RateLimits;
+use crate::rejection::RejectionReason;
+
+use nostr::key::XOnlyPublicKey;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Instant;
+
+/// A bucket of `capacity` tokens, refilled at `capacity` tokens per minute.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+	capacity: f64,
+	tokens: f64,
+	last_refill: Instant,
+}
+
+impl TokenBucket {
+	pub fn per_minute(capacity: u64, now: Instant) -> Self {
+		TokenBucket {
+			capacity: capacity as f64,
+			tokens: capacity as f64,
+			last_refill: now,
+		}
+	}
+
+	fn refill(&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * self.capacity / 60.0).min(self.capacity);
+		self.last_refill = now;
+	}
+
+	pub fn has(&mut self, cost: u64, now: Instant) -> bool {
+		self.refill(now);
+		self.tokens >= cost as f64
+	}
+
+	pub fn take(&mut self, cost: u64) {
+		self.tokens -= cost as f64;
+	}
+
+	/// Gives back `cost` tokens, up to the capacity.
+	pub fn give(&mut self, cost: u64) {
+		self.tokens = (self.tokens + cost as f64).min(self.capacity);
+	}
+
+	pub fn try_take(&mut self, cost: u64, now: Instant) -> bool {
+		if !self.has(cost, now) { return false; }
+		self.take(cost);
+		true
+	}
+
+	fn is_full(&mut self, now: Instant) -> bool {
+		self.has(self.capacity as u64, now)
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RateLimited {
+	Events,
+	Reqs,
+	Bytes,
+}
+
+impl From<&RateLimited> for RejectionReason {
+	fn from(limited: &RateLimited) -> Self {
+		let message = match limited {
+			RateLimited::Events => "too many events, slow down",
+			RateLimited::Reqs => "too many subscriptions requests, slow down",
+			RateLimited::Bytes => "too much data, slow down",
+		};
+		RejectionReason::RateLimited(message.to_string())
+	}
+}
+
+impl fmt::Display for RateLimited {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", RejectionReason::from(self))
+	}
+}
+
+/// The buckets of one client or pubkey, `None` when the limit is disabled.
+#[derive(Clone, Debug)]
+struct Buckets {
+	events: Option<TokenBucket>,
+	reqs: Option<TokenBucket>,
+	bytes: Option<TokenBucket>,
+}
+
+impl Buckets {
+	fn new(events: u64, reqs: u64, bytes: u64, now: Instant) -> Self {
+		let bucket = |capacity: u64| if capacity > 0 { Some(TokenBucket::per_minute(capacity, now)) } else { None };
+		Buckets {
+			events: bucket(events),
+			reqs: bucket(reqs),
+			bytes: bucket(bytes),
+		}
+	}
+
+	fn get(&mut self, limited: &RateLimited) -> Option<&mut TokenBucket> {
+		match limited {
+			RateLimited::Events => self.events.as_mut(),
+			RateLimited::Reqs => self.reqs.as_mut(),
+			RateLimited::Bytes => self.bytes.as_mut(),
+		}
+	}
+
+	fn is_full(&mut self, now: Instant) -> bool {
+		[&mut self.events, &mut self.reqs, &mut self.bytes].iter_mut().all(|bucket| bucket.as_mut().map_or(true, |bucket| bucket.is_full(now)))
+	}
+}
+
+pub struct RateLimiter {
+	limits: RateLimits,
+
+	clients: HashMap<u64, Buckets>,
+	pubkeys: HashMap<XOnlyPublicKey, Buckets>,
+}
+
+impl RateLimiter {
+	pub fn new(limits: RateLimits) -> Self {
+		RateLimiter {
+			limits,
+
+			clients: HashMap::new(),
+			pubkeys: HashMap::new(),
+		}
+	}
+
+	pub fn max_message_size(&self) -> u64 {
+		self.limits.max_message_size
+	}
+
+	pub fn max_event_tags(&self) -> u64 {
+		self.limits.max_event_tags
+	}
+
+	/// Spends `cost` tokens of the client bucket and of the pubkey bucket, if
+	/// any, only if both have enough of them.
+	fn try_take(&mut self, limited: RateLimited, cost: u64, client_id: u64, pubkey: Option<&XOnlyPublicKey>, now: Instant) -> Result<(), RateLimited> {
+		let limits = &self.limits;
+		let client_buckets = self.clients.entry(client_id)
+			.or_insert_with(|| Buckets::new(limits.events_per_minute, limits.reqs_per_minute, limits.bytes_per_minute, now));
+		let mut pubkey_buckets = pubkey.map(|pubkey| self.pubkeys.entry(*pubkey)
+			.or_insert_with(|| Buckets::new(limits.pubkey_events_per_minute, limits.pubkey_reqs_per_minute, limits.pubkey_bytes_per_minute, now)));
+
+		let client_bucket = client_buckets.get(&limited);
+		let pubkey_bucket = pubkey_buckets.as_mut().and_then(|buckets| buckets.get(&limited));
+
+		let mut buckets: Vec<&mut TokenBucket> = client_bucket.into_iter().chain(pubkey_bucket.into_iter()).collect();
+		if !buckets.iter_mut().all(|bucket| bucket.has(cost, now)) {
+			return Err(limited);
+		}
+		for bucket in buckets {
+			bucket.take(cost);
+		}
+		Ok(())
+	}
+
+	/// Charges the size of a received message, `pubkey` is the authenticated key
+	/// of the client.
+	pub fn check_bytes(&mut self, client_id: u64, pubkey: Option<&XOnlyPublicKey>, bytes: u64, now: Instant) -> Result<(), RateLimited> {
+		self.try_take(RateLimited::Bytes, bytes, client_id, pubkey, now)
+	}
+
+	/// Charges an event published by the client, `author` is the event pubkey.
+	pub fn check_event(&mut self, client_id: u64, author: &XOnlyPublicKey, now: Instant) -> Result<(), RateLimited> {
+		self.try_take(RateLimited::Events, 1, client_id, Some(author), now)
+	}
+
+	/// Gives back the token charged to the author of an event found invalid,
+	/// its author can't be trusted. The client keeps paying for it.
+	pub fn refund_author_event(&mut self, author: &XOnlyPublicKey) {
+		if let Some(bucket) = self.pubkeys.get_mut(author).and_then(|buckets| buckets.events.as_mut()) {
+			bucket.give(1);
+		}
+	}
+
+	/// Charges a subscription request, `pubkey` is the authenticated key of the client.
+	pub fn check_req(&mut self, client_id: u64, pubkey: Option<&XOnlyPublicKey>, now: Instant) -> Result<(), RateLimited> {
+		self.try_take(RateLimited::Reqs, 1, client_id, pubkey, now)
+	}
+
+	pub fn remove_client(&mut self, client_id: u64) {
+		self.clients.remove(&client_id);
+	}
+
+	/// Forgets the pubkeys whose buckets are refilled, they would be recreated
+	/// identical on their next message.
+	pub fn prune_idle_pubkeys(&mut self, now: Instant) {
+		self.pubkeys.retain(|_, buckets| !buckets.is_full(now));
+	}
+}
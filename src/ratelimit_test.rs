@@ -0,0 +1,72 @@
+use crate::config::RateLimits;
+use crate::ratelimit::{RateLimited, RateLimiter, TokenBucket};
+
+use nostr::Keys;
+
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_token_bucket_refill() {
+	let start = Instant::now();
+	let mut bucket = TokenBucket::per_minute(60, start);
+
+	assert!(bucket.try_take(60, start));
+	assert!(!bucket.try_take(1, start));
+
+	// One token per second.
+	assert!(!bucket.try_take(2, start + Duration::from_secs(1)));
+	assert!(bucket.try_take(2, start + Duration::from_secs(2)));
+
+	// The bucket never holds more than its capacity.
+	assert!(!bucket.try_take(61, start + Duration::from_secs(3600)));
+	assert!(bucket.try_take(60, start + Duration::from_secs(3600)));
+}
+
+#[test]
+fn test_client_and_pubkey_limits() {
+	let limits = RateLimits {
+		events_per_minute: 3,
+		reqs_per_minute: 1,
+		bytes_per_minute: 100,
+		pubkey_events_per_minute: 2,
+		pubkey_reqs_per_minute: 0,
+		pubkey_bytes_per_minute: 0,
+		max_message_size: 0,
+		max_event_tags: 0,
+	};
+	let mut limiter = RateLimiter::new(limits);
+	let now = Instant::now();
+	let author = Keys::generate().public_key();
+	let other_author = Keys::generate().public_key();
+
+	// The author bucket is exhausted first, the client can still publish for others.
+	assert_eq!(limiter.check_event(1, &author, now), Ok(()));
+	assert_eq!(limiter.check_event(1, &author, now), Ok(()));
+	assert_eq!(limiter.check_event(1, &author, now), Err(RateLimited::Events));
+	assert_eq!(limiter.check_event(1, &other_author, now), Ok(()));
+	assert_eq!(limiter.check_event(1, &other_author, now), Err(RateLimited::Events));
+
+	// The author is limited across clients.
+	assert_eq!(limiter.check_event(2, &author, now), Err(RateLimited::Events));
+	assert_eq!(limiter.check_event(2, &other_author, now), Ok(()));
+
+	// The event found forged is not charged to its author.
+	let forged_author = Keys::generate().public_key();
+	assert_eq!(limiter.check_event(3, &forged_author, now), Ok(()));
+	limiter.refund_author_event(&forged_author);
+	assert_eq!(limiter.check_event(3, &forged_author, now), Ok(()));
+	assert_eq!(limiter.check_event(3, &forged_author, now), Ok(()));
+	assert_eq!(limiter.check_event(4, &forged_author, now), Err(RateLimited::Events));
+
+	assert_eq!(limiter.check_req(1, None, now), Ok(()));
+	assert_eq!(limiter.check_req(1, Some(&author), now), Err(RateLimited::Reqs));
+
+	assert_eq!(limiter.check_bytes(1, None, 80, now), Ok(()));
+	assert_eq!(limiter.check_bytes(1, None, 30, now), Err(RateLimited::Bytes));
+
+	// A rejected message does not spend tokens.
+	assert_eq!(limiter.check_bytes(1, None, 20, now), Ok(()));
+
+	limiter.remove_client(1);
+	assert_eq!(limiter.check_req(1, None, now), Ok(()));
+}
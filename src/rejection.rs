@@ -0,0 +1,95 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! The reasons given to clients in the OK and CLOSED messages, with the
+//! machine-readable prefixes of NIP-01 and NIP-42.
+
+use nostr::{EventId, RelayMessage, SubscriptionId};
+
+use serde_json::json;
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RejectionReason {
+	/// The event is already stored, the OK is still true.
+	Duplicate(String),
+	/// The event proof-of-work is insufficient (NIP-13).
+	Pow(String),
+	/// The author or the client is banned by relay policy.
+	Blocked(String),
+	/// The client is sending too much, or the relay is overloaded.
+	RateLimited(String),
+	/// The message is malformed or fails validation.
+	Invalid(String),
+	/// The client is not allowed to publish or subscribe this.
+	Restricted(String),
+	/// The client must authenticate first (NIP-42).
+	AuthRequired(String),
+	/// The relay failed to process the message.
+	Error(String),
+}
+
+impl RejectionReason {
+	pub fn prefix(&self) -> &'static str {
+		match self {
+			RejectionReason::Duplicate(_) => "duplicate",
+			RejectionReason::Pow(_) => "pow",
+			RejectionReason::Blocked(_) => "blocked",
+			RejectionReason::RateLimited(_) => "rate-limited",
+			RejectionReason::Invalid(_) => "invalid",
+			RejectionReason::Restricted(_) => "restricted",
+			RejectionReason::AuthRequired(_) => "auth-required",
+			RejectionReason::Error(_) => "error",
+		}
+	}
+
+	pub fn message(&self) -> &str {
+		match self {
+			RejectionReason::Duplicate(message)
+				| RejectionReason::Pow(message)
+				| RejectionReason::Blocked(message)
+				| RejectionReason::RateLimited(message)
+				| RejectionReason::Invalid(message)
+				| RejectionReason::Restricted(message)
+				| RejectionReason::AuthRequired(message)
+				| RejectionReason::Error(message) => message,
+		}
+	}
+
+	/// The OK status of an event refused for this reason, a duplicate event
+	/// has been accepted before.
+	pub fn is_accepted(&self) -> bool {
+		matches!(self, RejectionReason::Duplicate(_))
+	}
+
+	pub fn ok_message(&self, event_id: EventId) -> RelayMessage {
+		RelayMessage::new_ok(event_id, self.is_accepted(), self.to_string())
+	}
+
+	/// The CLOSED message of a subscription refused or terminated by the relay,
+	/// serialized as it is not supported by our nostr version.
+	pub fn closed_message(&self, sub_id: &SubscriptionId) -> String {
+		json!(["CLOSED", sub_id, self.to_string()]).to_string()
+	}
+}
+
+impl fmt::Display for RejectionReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}: {}", self.prefix(), self.message())
+	}
+}
+
+/// The OK message of an event, `reason` is `None` if it was accepted.
+pub fn ok_message(event_id: EventId, reason: Option<&RejectionReason>) -> RelayMessage {
+	match reason {
+		Some(reason) => reason.ok_message(event_id),
+		None => RelayMessage::new_ok(event_id, true, String::new()),
+	}
+}
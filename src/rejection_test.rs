@@ -0,0 +1,46 @@
+use crate::rejection::{ok_message, RejectionReason};
+use crate::ratelimit::RateLimited;
+
+use nostr::{EventBuilder, Keys, Kind, SubscriptionId};
+
+use serde_json::{json, Value};
+
+#[test]
+fn test_rejection_prefixes() {
+	let reasons = vec![
+		(RejectionReason::Duplicate("already have this event".to_string()), "duplicate: already have this event"),
+		(RejectionReason::Pow("difficulty 8 is less than 20".to_string()), "pow: difficulty 8 is less than 20"),
+		(RejectionReason::Blocked("pubkey banned".to_string()), "blocked: pubkey banned"),
+		(RejectionReason::RateLimited("slow down".to_string()), "rate-limited: slow down"),
+		(RejectionReason::Invalid("malformed message".to_string()), "invalid: malformed message"),
+		(RejectionReason::Restricted("not allowed".to_string()), "restricted: not allowed"),
+		(RejectionReason::AuthRequired("authenticate first".to_string()), "auth-required: authenticate first"),
+		(RejectionReason::Error("could not store the event".to_string()), "error: could not store the event"),
+	];
+	for (reason, expected) in reasons {
+		assert_eq!(reason.to_string(), expected);
+	}
+
+	assert_eq!(RateLimited::Reqs.to_string(), "rate-limited: too many subscriptions requests, slow down");
+}
+
+#[test]
+fn test_ok_and_closed_messages() {
+	let event = EventBuilder::new(Kind::from(1), "note", &[]).to_event(&Keys::generate()).unwrap();
+
+	let ok: Value = serde_json::from_str(&ok_message(event.id, None).as_json()).unwrap();
+	assert_eq!(ok, json!(["OK", event.id.to_hex(), true, ""]));
+
+	let reason = RejectionReason::Invalid("event signature verification failed".to_string());
+	let ok: Value = serde_json::from_str(&reason.ok_message(event.id).as_json()).unwrap();
+	assert_eq!(ok, json!(["OK", event.id.to_hex(), false, "invalid: event signature verification failed"]));
+
+	// A duplicate is still accepted.
+	let reason = RejectionReason::Duplicate("already have this event".to_string());
+	let ok: Value = serde_json::from_str(&ok_message(event.id, Some(&reason)).as_json()).unwrap();
+	assert_eq!(ok[2], json!(true));
+
+	let reason = RejectionReason::Blocked("too many subscriptions, max 100".to_string());
+	let closed: Value = serde_json::from_str(&reason.closed_message(&SubscriptionId::new("sub"))).unwrap();
+	assert_eq!(closed, json!(["CLOSED", "sub", "blocked: too many subscriptions, max 100"]));
+}
@@ -0,0 +1,138 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! The NIP-11 relay information document, served over plain HTTP on the
+//! Nostr port to the requests with an `Accept: application/nostr+json` header.
+
+use crate::admission::AdmissionPolicy;
+use crate::clienthandler::MAX_SUBSCRIPTIONS;
+use crate::config::{Config, Connections};
+
+use serde_json::{json, Value};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration};
+
+/// The NIPs implemented by civkitd.
+pub const SUPPORTED_NIPS: &[u16] = &[1, 9, 11, 13, 16, 33, 40, 42, 45];
+
+const NOSTR_JSON_MIME: &str = "application/nostr+json";
+
+/// Max size of the HTTP request head we inspect before the websocket handshake.
+const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+
+/// Number of times we wait for the rest of a partially received request head.
+const MAX_PEEK_ATTEMPTS: u32 = 10;
+
+/// Max wait for the request head, the connection is not admitted yet and not
+/// counted against the connection caps, only against the pending connections.
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Max connections waiting for their request head when the client connections
+/// are not limited.
+const DEFAULT_MAX_PENDING_CONNECTIONS: usize = 1024;
+
+/// The max number of connections waiting for their request head, as many as
+/// the max client connections if limited. The connections beyond are dropped
+/// before reading anything.
+pub fn max_pending_connections(config: &Connections) -> usize {
+	if config.maxclientconnections > 0 { config.maxclientconnections as usize } else { DEFAULT_MAX_PENDING_CONNECTIONS }
+}
+
+/// Builds the relay information document from the node configuration.
+pub fn relay_information_document(config: &Config) -> Value {
+	let mut supported_nips = SUPPORTED_NIPS.to_vec();
+	// The NIP-50 search is opt-in.
+	if config.search.enabled {
+		supported_nips.push(50);
+		supported_nips.sort();
+	}
+	let mut document = json!({
+		"name": config.relay_info.name,
+		"description": config.relay_info.description,
+		"supported_nips": supported_nips,
+		"software": "https://github.com/civkit/civkit-node",
+		"version": env!("CARGO_PKG_VERSION"),
+		"limitation": {
+			"max_subscriptions": MAX_SUBSCRIPTIONS,
+			"max_client_connections": config.connections.maxclientconnections,
+			"max_event_age": config.performance.max_event_age,
+			"max_message_length": config.rate_limits.max_message_size,
+			"max_event_tags": config.rate_limits.max_event_tags,
+		},
+	});
+	if let Some(limitation) = document["limitation"].as_object_mut() {
+		AdmissionPolicy::new(config).advertise(limitation);
+	}
+
+	if !config.relay_info.pubkey.is_empty() {
+		document["pubkey"] = json!(config.relay_info.pubkey);
+	}
+	if !config.relay_info.contact.is_empty() {
+		document["contact"] = json!(config.relay_info.contact);
+	}
+	document
+}
+
+/// Returns true if the HTTP request head asks for the relay information
+/// document rather than a websocket upgrade.
+pub fn is_relay_info_request(request_head: &str) -> bool {
+	let mut accept_nostr_json = false;
+	for line in request_head.lines().skip(1) {
+		if let Some((name, value)) = line.split_once(':') {
+			let name = name.trim().to_ascii_lowercase();
+			let value = value.trim().to_ascii_lowercase();
+			if name == "upgrade" && value.contains("websocket") { return false; }
+			if name == "accept" && value.contains(NOSTR_JSON_MIME) { accept_nostr_json = true; }
+		}
+	}
+	accept_nostr_json
+}
+
+/// Builds the HTTP response carrying the document, with the CORS headers
+/// required by NIP-11.
+pub fn relay_info_response(document: &Value) -> String {
+	let body = document.to_string();
+	format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Headers: *\r\nAccess-Control-Allow-Methods: GET, OPTIONS\r\nConnection: close\r\n\r\n{}", NOSTR_JSON_MIME, body.len(), body)
+}
+
+/// Answers the connection with the relay information document if it is a
+/// NIP-11 request. Returns false if the stream should go on with the websocket
+/// handshake, also when the request head is not received in time: the
+/// connection is then admitted, and counted, as a websocket one.
+pub async fn serve_relay_info(stream: &mut TcpStream, document: &Value) -> bool {
+	let request_head = match timeout(REQUEST_HEAD_TIMEOUT, peek_request_head(stream)).await {
+		Ok(Some(request_head)) => request_head,
+		_ => { return false; },
+	};
+
+	if !is_relay_info_request(&request_head) { return false; }
+
+	if let Err(err) = stream.write_all(relay_info_response(document).as_bytes()).await {
+		println!("[CIVKITD] - NET: relay information document sending failure: {}", err);
+	}
+	let _ = stream.shutdown().await;
+	true
+}
+
+async fn peek_request_head(stream: &TcpStream) -> Option<String> {
+	let mut buf = vec![0; MAX_REQUEST_HEAD_SIZE];
+	for _ in 0..MAX_PEEK_ATTEMPTS {
+		let len = stream.peek(&mut buf).await.ok()?;
+		if len == 0 { return None; }
+		if let Some(end) = buf[..len].windows(4).position(|window| window == b"\r\n\r\n") {
+			return Some(String::from_utf8_lossy(&buf[..end]).to_string());
+		}
+		if len == buf.len() { return None; }
+		// The request head is not fully received yet.
+		sleep(Duration::from_millis(10)).await;
+	}
+	None
+}
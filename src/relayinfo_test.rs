@@ -0,0 +1,84 @@
+use crate::clienthandler::MAX_SUBSCRIPTIONS;
+use crate::config::{Admission, AdmissionRequirement, Config};
+use crate::relayinfo::{is_relay_info_request, max_pending_connections, relay_information_document, relay_info_response};
+
+#[test]
+fn test_relay_information_document_from_config() {
+	let mut config = Config::default();
+	config.relay_info.name = "civkit-test".to_string();
+	config.relay_info.pubkey = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".to_string();
+	config.connections.maxclientconnections = 42;
+	config.performance.max_event_age = 600;
+	config.spam_protection.requestcredentials = false;
+	config.admission.kinds.clear();
+
+	let document = relay_information_document(&config);
+	assert_eq!(document["name"], "civkit-test");
+	assert_eq!(document["pubkey"], config.relay_info.pubkey.as_str());
+	assert!(document["supported_nips"].as_array().unwrap().iter().any(|nip| nip == 11));
+	assert_eq!(document["limitation"]["max_subscriptions"], MAX_SUBSCRIPTIONS);
+	assert_eq!(document["limitation"]["max_client_connections"], 42);
+	assert_eq!(document["limitation"]["max_event_age"], 600);
+	assert_eq!(document["limitation"]["max_message_length"], config.rate_limits.max_message_size);
+	assert_eq!(document["limitation"]["payment_required"], false);
+	assert_eq!(document["limitation"]["min_pow_difficulty"], 0);
+	assert_eq!(document["limitation"]["restricted_writes"], false);
+	assert!(document["limitation"].get("pow_difficulty_per_kind").is_none());
+	assert!(document["limitation"].get("auth_required_per_kind").is_none());
+
+	// Unset optional fields are omitted.
+	assert!(document.get("contact").is_none());
+
+	assert!(!document["supported_nips"].as_array().unwrap().iter().any(|nip| nip == 50));
+	config.search.enabled = true;
+	config.admission = Admission::default();
+	config.spam_protection.min_pow_difficulty = 8;
+	config.spam_protection.pow_difficulty_per_kind.insert("32500".to_string(), 20);
+	config.spam_protection.requireauthcredentials = true;
+	config.admission.kinds.insert("4".to_string(), AdmissionRequirement::Auth);
+	config.admission.kinds.insert("1".to_string(), AdmissionRequirement::Pow { difficulty: 12 });
+	let document = relay_information_document(&config);
+	assert!(document["supported_nips"].as_array().unwrap().iter().any(|nip| nip == 50));
+	assert_eq!(document["limitation"]["min_pow_difficulty"], 8);
+	assert_eq!(document["limitation"]["pow_difficulty_per_kind"]["32500"], 20);
+	// The admission table difficulties are merged in.
+	assert_eq!(document["limitation"]["pow_difficulty_per_kind"]["1"], 12);
+	assert!(document["limitation"]["pow_difficulty_per_kind"].get("4").is_none());
+	assert_eq!(document["limitation"]["auth_required_per_kind"]["4"], true);
+	assert_eq!(document["limitation"]["credential_auth_required"], true);
+	assert_eq!(document["limitation"]["restricted_writes"], true);
+	// Orders are paid with credentials by default.
+	assert_eq!(document["limitation"]["payment_required"], true);
+}
+
+#[test]
+fn test_relay_info_request_detection() {
+	assert!(is_relay_info_request("GET / HTTP/1.1\r\nHost: localhost\r\nAccept: application/nostr+json"));
+	assert!(is_relay_info_request("GET / HTTP/1.1\r\naccept: text/html, Application/Nostr+JSON"));
+	assert!(!is_relay_info_request("GET / HTTP/1.1\r\nHost: localhost\r\nAccept: text/html"));
+	assert!(!is_relay_info_request("GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nAccept: application/nostr+json"));
+}
+
+#[test]
+fn test_relay_info_response() {
+	let document = relay_information_document(&Config::default());
+	let response = relay_info_response(&document);
+	let (head, body) = response.split_once("\r\n\r\n").unwrap();
+
+	assert!(head.starts_with("HTTP/1.1 200 OK"));
+	assert!(head.contains("Content-Type: application/nostr+json"));
+	assert!(head.contains("Access-Control-Allow-Origin: *"));
+	assert!(head.contains(&format!("Content-Length: {}", body.len())));
+	assert_eq!(serde_json::from_str::<serde_json::Value>(body).unwrap(), document);
+}
+
+#[test]
+fn test_max_pending_connections() {
+	let mut config = Config::default();
+	config.connections.maxclientconnections = 42;
+	assert_eq!(max_pending_connections(&config.connections), 42);
+
+	// The pending connections are bounded even without client connections limit.
+	config.connections.maxclientconnections = 0;
+	assert!(max_pending_connections(&config.connections) > 0);
+}
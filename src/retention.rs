@@ -0,0 +1,92 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! The retention of the stored events: NIP-40 expiration, max age by kind and
+//! max number of events served.
+//!
+//! Pruned events are turned into tombstones keeping their id and cumulative
+//! hash, so the chain committed to Mainstay can still be audited.
+
+use crate::config::Performance;
+
+use nostr::Event;
+
+use std::collections::HashMap;
+
+/// The time in seconds after which the event must not be served anymore, from
+/// its NIP-40 `expiration` tag.
+pub fn expiration(event: &Event) -> Option<u64> {
+	event.tags.iter()
+		.map(|tag| tag.as_vec())
+		.find(|tag| tag.len() >= 2 && tag[0] == "expiration")
+		.and_then(|tag| tag[1].parse::<u64>().ok())
+}
+
+pub fn is_expired(event: &Event, now: u64) -> bool {
+	expiration(event).map_or(false, |expiration| expiration <= now)
+}
+
+/// The kind of the NIP-09 deletions. They are never pruned for their age or
+/// evicted, as they keep the deleted events from being published again.
+pub const DELETION_KIND: u32 = 5;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+	/// Max age in seconds of the events, 0 for no limit.
+	pub max_event_age: u64,
+	/// Max ages overriding `max_event_age` by kind, 0 for no limit.
+	pub max_event_age_per_kind: HashMap<u32, u64>,
+	/// Max number of events served, 0 for no limit.
+	pub max_events: u64,
+}
+
+impl RetentionPolicy {
+	pub fn new(performance: &Performance) -> Self {
+		let mut max_event_age_per_kind = HashMap::new();
+		for (kind, max_age) in performance.max_event_age_per_kind.iter() {
+			match kind.parse::<u32>() {
+				Ok(kind) => { max_event_age_per_kind.insert(kind, (*max_age).max(0) as u64); },
+				Err(_) => println!("[CIVKITD] - NOTE PROCESSING: Ignoring max event age of invalid kind {}", kind),
+			}
+		}
+		RetentionPolicy {
+			max_event_age: performance.max_event_age.max(0) as u64,
+			max_event_age_per_kind,
+			max_events: performance.max_events,
+		}
+	}
+
+	/// The max age of the events of the kind, `None` for no limit.
+	pub fn max_age(&self, kind: u32) -> Option<u64> {
+		if kind == DELETION_KIND { return None; }
+		let max_age = self.max_event_age_per_kind.get(&kind).cloned().unwrap_or(self.max_event_age);
+		if max_age > 0 { Some(max_age) } else { None }
+	}
+
+	/// Whether the event is past its expiration or its max age.
+	pub fn is_outdated(&self, event: &Event, now: u64) -> bool {
+		if is_expired(event, now) { return true; }
+		self.max_age(event.kind.as_u32()).map_or(false, |max_age| event.created_at.as_u64().saturating_add(max_age) < now)
+	}
+}
+
+/// The number of events turned into tombstones by a pruning.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PruneStats {
+	pub expired: u64,
+	pub hidden: u64,
+	pub outdated: u64,
+	pub evicted: u64,
+}
+
+impl PruneStats {
+	pub fn total(&self) -> u64 {
+		self.expired + self.hidden + self.outdated + self.evicted
+	}
+}
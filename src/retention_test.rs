@@ -0,0 +1,56 @@
+use crate::config::Config;
+use crate::retention::{expiration, is_expired, RetentionPolicy};
+use crate::test_utils::build_event_at;
+
+use nostr::Keys;
+
+#[test]
+fn test_event_expiration() {
+	let keys = Keys::generate();
+	let expiring = build_event_at(&keys, 1, "retention", vec![vec!["expiration".to_string(), "1000".to_string()]], 100);
+	assert_eq!(expiration(&expiring), Some(1000));
+	assert!(!is_expired(&expiring, 999));
+	assert!(is_expired(&expiring, 1000));
+
+	let note = build_event_at(&keys, 1, "retention", vec![], 100);
+	assert_eq!(expiration(&note), None);
+	assert!(!is_expired(&note, u64::MAX));
+
+	let malformed = build_event_at(&keys, 1, "retention", vec![vec!["expiration".to_string(), "tomorrow".to_string()]], 100);
+	assert_eq!(expiration(&malformed), None);
+}
+
+#[test]
+fn test_default_retention_is_off() {
+	let keys = Keys::generate();
+	let policy = RetentionPolicy::new(&Config::default().performance);
+	assert_eq!(policy, RetentionPolicy::default());
+	assert!(!policy.is_outdated(&build_event_at(&keys, 32500, "retention", vec![], 0), 100_000));
+}
+
+#[test]
+fn test_retention_policy() {
+	let mut config = Config::default();
+	config.performance.max_events = 500;
+	config.performance.max_event_age = 3600;
+	config.performance.max_event_age_per_kind.insert("32500".to_string(), 86400);
+	config.performance.max_event_age_per_kind.insert("0".to_string(), 0);
+	config.performance.max_event_age_per_kind.insert("orders".to_string(), 60);
+
+	let policy = RetentionPolicy::new(&config.performance);
+	assert_eq!(policy.max_events, 500);
+	assert_eq!(policy.max_event_age_per_kind.len(), 2);
+	assert_eq!(policy.max_age(1), Some(3600));
+	assert_eq!(policy.max_age(32500), Some(86400));
+	assert_eq!(policy.max_age(0), None);
+	// The deletions are kept to refuse the deleted events.
+	assert_eq!(policy.max_age(5), None);
+
+	let keys = Keys::generate();
+	let now = 100_000;
+	assert!(policy.is_outdated(&build_event_at(&keys, 1, "retention", vec![], now - 3601), now));
+	assert!(!policy.is_outdated(&build_event_at(&keys, 1, "retention", vec![], now - 3600), now));
+	assert!(!policy.is_outdated(&build_event_at(&keys, 32500, "retention", vec![], now - 3601), now));
+	assert!(!policy.is_outdated(&build_event_at(&keys, 0, "retention", vec![], 0), now));
+	assert!(policy.is_outdated(&build_event_at(&keys, 0, "retention", vec![vec!["expiration".to_string(), now.to_string()]], now), now));
+}
@@ -358,15 +358,24 @@ async fn respond(
 		println!("DEBUG SAMPLE - signature check ok");
 	    }
 
+	    let kind_32500_event = EventBuilder::new_order_note(content.unwrap(), &[]).to_event(client_keys).unwrap();
+
 	    let mut service_deliverance_request = ServiceDeliveranceRequest::new(credentials, signatures, service_id);
 
 	    let mut buffer = vec![];
 	    service_deliverance_request.encode(&mut buffer);
 	    let service_deliverance_hex_str = buffer.to_hex();
+	    // The service deliverance pays for the order it references.
 	    let tags = &[
 		Tag::Credential(service_deliverance_hex_str),
+		Tag::parse(vec!["e".to_string(), kind_32500_event.id.to_hex()]).unwrap(),
 	    ];
 
+	    let client_message = ClientMessage::new_event(kind_32500_event);
+	    let serialized_message = client_message.as_json();
+	    tx.unbounded_send(Message::text(serialized_message))
+		.unwrap();
+
 	    if let Ok(credential_carrier) =
 		EventBuilder::new_text_note("", tags).to_event(client_keys)
 	    {
@@ -375,16 +384,6 @@ async fn respond(
 		tx.unbounded_send(Message::text(serialized_message))
 		    .unwrap();
 	    }
-
-	    if let Ok(kind_32500_event) =
-	        EventBuilder::new_order_note(content.unwrap(), &[]).to_event(client_keys)
-	    {
-
-		let client_message = ClientMessage::new_event(kind_32500_event);
-		let serialized_message = client_message.as_json();
-		tx.unbounded_send(Message::text(serialized_message))
-			.unwrap();
-	    }
 	}
         Some(("opensubscription", matches)) => {
             let subscriptionid: Option<&String> = matches.get_one("subscriptionid");
@@ -0,0 +1,76 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! NIP-50 full-text search of the event content.
+//!
+//! The search string is split in words as by the default FTS5 tokenizer of
+//! the SQLite event store: an event matches if its content has all the words,
+//! case-insensitively. The `key:value` extensions are ignored.
+
+use nostr::{Event, Filter};
+
+use serde_json::{json, Value};
+
+/// Returns the `search` field of the filter, if any.
+pub fn search_field(filter: &Filter) -> Option<String> {
+	match serde_json::to_value(filter) {
+		Ok(Value::Object(fields)) => fields.get("search").and_then(|search| search.as_str()).map(|search| search.to_string()),
+		_ => None,
+	}
+}
+
+pub fn has_search(filters: &[Filter]) -> bool {
+	filters.iter().any(|filter| search_field(filter).is_some())
+}
+
+/// Splits the text in lowercase alphanumeric words.
+fn words(text: &str) -> Vec<String> {
+	text.split(|c: char| !c.is_alphanumeric())
+		.filter(|word| !word.is_empty())
+		.map(|word| word.to_lowercase())
+		.collect()
+}
+
+/// Returns the words searched, without the NIP-50 extensions.
+pub fn search_terms(search: &str) -> Vec<String> {
+	search.split_whitespace()
+		.filter(|term| !term.contains(':'))
+		.flat_map(words)
+		.collect()
+}
+
+/// Translates the search in a FTS5 query, every word is quoted so the client
+/// can't use the FTS5 query syntax. `None` if there is no word to search.
+pub fn fts_query(search: &str) -> Option<String> {
+	let terms = search_terms(search);
+	if terms.is_empty() {
+		return None;
+	}
+	Some(terms.iter().map(|term| format!("\"{}\"", term)).collect::<Vec<String>>().join(" "))
+}
+
+/// Returns true if the event content has all the words searched.
+pub fn match_search(search: &str, event: &Event) -> bool {
+	let content_words = words(&event.content);
+	search_terms(search).iter().all(|term| content_words.contains(term))
+}
+
+/// Caps the number of events returned for a search filter.
+pub fn cap_search_limit(filter: Filter, max_results: u64) -> Filter {
+	if max_results == 0 || search_field(&filter).is_none() {
+		return filter;
+	}
+	let mut fields = match serde_json::to_value(&filter) {
+		Ok(Value::Object(fields)) => fields,
+		_ => { return filter; }
+	};
+	let limit = fields.get("limit").and_then(|limit| limit.as_u64()).map_or(max_results, |limit| limit.min(max_results));
+	fields.insert("limit".to_string(), json!(limit));
+	serde_json::from_value(Value::Object(fields)).unwrap_or(filter)
+}
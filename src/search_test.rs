@@ -0,0 +1,49 @@
+use crate::eventfilter::match_filter;
+use crate::search::{cap_search_limit, fts_query, has_search, match_search, search_field, search_terms};
+use crate::test_utils::{build_event, build_filter};
+
+use nostr::{Filter, Keys};
+
+use serde_json::json;
+
+#[test]
+fn test_search_terms() {
+	assert_eq!(search_terms("BTC/EUR  sepa"), vec!["btc", "eur", "sepa"]);
+	assert_eq!(search_terms("sepa language:en"), vec!["sepa"]);
+	assert!(search_terms("language:en ...").is_empty());
+
+	// The FTS5 query syntax can't be injected.
+	assert_eq!(fts_query("btc\" OR sepa*"), Some("\"btc\" \"or\" \"sepa\"".to_string()));
+	assert_eq!(fts_query("domain:civkit.org"), None);
+}
+
+#[test]
+fn test_match_search() {
+	let order = build_event(&Keys::generate(), 32500, "Selling 0.1 BTC/EUR, payment by SEPA or Revolut", &[]);
+	assert!(match_search("btc sepa", &order));
+	assert!(match_search("REVOLUT", &order));
+	assert!(!match_search("btc paypal", &order));
+	assert!(!match_search("sep", &order));
+
+	assert!(match_filter(&build_filter(json!({ "kinds": [32500], "search": "eur sepa" })), &order));
+	assert!(!match_filter(&build_filter(json!({ "kinds": [32500], "search": "usd" })), &order));
+}
+
+#[test]
+fn test_cap_search_limit() {
+	let search = build_filter(json!({ "search": "sepa", "limit": 500 }));
+	assert_eq!(search_field(&search), Some("sepa".to_string()));
+	assert!(has_search(&[Filter::new(), search.clone()]));
+	assert!(!has_search(&[Filter::new()]));
+
+	let capped = serde_json::to_value(cap_search_limit(search, 100)).unwrap();
+	assert_eq!(capped["limit"], 100);
+	let capped = serde_json::to_value(cap_search_limit(build_filter(json!({ "search": "sepa" })), 100)).unwrap();
+	assert_eq!(capped["limit"], 100);
+	let capped = serde_json::to_value(cap_search_limit(build_filter(json!({ "search": "sepa", "limit": 10 })), 100)).unwrap();
+	assert_eq!(capped["limit"], 10);
+
+	// The other filters are not capped.
+	let uncapped = serde_json::to_value(cap_search_limit(build_filter(json!({ "kinds": [1] })), 100)).unwrap();
+	assert!(uncapped.get("limit").map_or(true, |limit| limit.is_null()));
+}
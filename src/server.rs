@@ -20,15 +20,19 @@ use log;
 use std::fs;
 use crate::servicemanager::ServiceManager;
 use civkit::inclusionproof::InclusionProof;
-use civkit::nostr_db::DbRequest;
+use civkit::nostr_db::{adopt_legacy_db, DbRequest, SqliteStorage, Storage, CIVKITD_DB_FILE};
 use civkit::config::Config;
 use civkit::clienthandler::ClientHandler;
+use civkit::connectionlimits::ConnectionUsage;
+use civkit::relayinfo::{max_pending_connections, relay_information_document, serve_relay_info};
 use civkit::anchormanager::AnchorManager;
 use civkit::credentialgateway::CredentialGateway;
 use civkit::kindprocessor::NoteProcessor;
 use civkit::nodesigner::NodeSigner;
 use civkit::peerhandler::{NoiseGateway, PeerInfo};
 use civkit::bitcoind_client::{BitcoindHandler, BitcoindRequest, BitcoindResult};
+use civkit::shutdown::{ShutdownSignal, ShutdownTrigger};
+use civkit::bus::{policies, BusError, BusMetrics};
 use civkit::NostrClient;
 
 use civkit::oniongateway::OnionBox;
@@ -51,15 +55,16 @@ use nostr::{Keys, EventBuilder};
 
 use std::env;
 use std::net::SocketAddr;
-use std::process;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::path::Path;
 
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
-use tokio::sync::{oneshot, mpsc};
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
 
 use tokio_tungstenite::WebSocketStream;
 
@@ -76,6 +81,14 @@ pub mod civkitservice {
 }
 
 
+/// Maps a message refused by a civkitd component to the RPC status.
+fn bus_status<T>(err: BusError<T>) -> Status {
+	match err {
+		BusError::Full(_) => Status::resource_exhausted("civkitd is overloaded, retry later"),
+		BusError::Closed(_) => Status::unavailable("civkitd component is not running"),
+	}
+}
+
 #[tonic::async_trait]
 impl AdminCtrl for std::sync::Arc<ServiceManager> {
 	async fn ping_handle(&self, request: Request<adminctrl::PingRequest>) -> Result<Response<adminctrl::PongRequest>, Status> {
@@ -87,8 +100,10 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 	}
 
 	async fn shutdown_handle(&self, request: Request<adminctrl::ShutdownRequest>) -> Result<Response<adminctrl::ShutdownReply>, Status> {
-		println!("[CIVKITD] - CONTROL: CivKit node shuting down...");
-		process::exit(0x0);
+		println!("[CIVKITD] - CONTROL: CivKit node shutdown requested");
+		self.shutdown.trigger();
+
+		Ok(Response::new(adminctrl::ShutdownReply {}))
 	}
 
 	async fn publish_text_note(&self, request: Request<adminctrl::SendNote>) -> Result<Response<adminctrl::ReceivedNote>, Status> {
@@ -99,7 +114,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 		if let Ok(kind1_event) = EventBuilder::new_text_note(note_content, &[]).to_event(&service_keys) {
 
 			let mut service_send_lock = self.service_events_send.lock().unwrap();
-			service_send_lock.send(ClientEvents::TextNote { event: kind1_event });
+			service_send_lock.try_send(ClientEvents::TextNote { event: kind1_event }).map_err(bus_status)?;
 		}
 
 		let received_note = adminctrl::ReceivedNote {
@@ -114,7 +129,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 
 		{
 			let mut service_send_lock = self.service_events_send.lock().unwrap();
-			service_send_lock.send(ClientEvents::Server { cmd: ServerCmd::DisconnectClient { client_id: disconnect_request }});
+			service_send_lock.try_send(ClientEvents::Server { cmd: ServerCmd::DisconnectClient { client_id: disconnect_request }}).map_err(bus_status)?;
 		}
 
 		Ok(Response::new(adminctrl::DisconnectClientReply {}))
@@ -128,7 +143,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 			let mut service_mngr_peers_lock = self.service_peers_send.lock().unwrap();
 
 			let peer_info = PeerInfo::new(peer_port);
-			service_mngr_peers_lock.send(peer_info);
+			service_mngr_peers_lock.try_send(peer_info).map_err(bus_status)?;
 		}
 
 		Ok(Response::new(adminctrl::PeerConnectionReply {}))
@@ -148,7 +163,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 		let (send, recv) = oneshot::channel::<Vec<NostrClient>>();
 		{
 			let mut service_mngr_send_lock = self.service_events_send.lock().unwrap();
-			service_mngr_send_lock.send(ClientEvents::Server { cmd: ServerCmd::GetClients { respond_to: send }});
+			service_mngr_send_lock.try_send(ClientEvents::Server { cmd: ServerCmd::GetClients { respond_to: send }}).map_err(bus_status)?;
 		}
 		let response = recv.await.expect("ClientHandler has been killed");
 		
@@ -170,6 +185,46 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 		Ok(Response::new(client_query))
 	}
 
+	async fn connection_usage(&self, request: Request<adminctrl::ConnectionUsageRequest>) -> Result<Response<adminctrl::ConnectionUsageReply>, Status> {
+		let (send, recv) = oneshot::channel::<ConnectionUsage>();
+		{
+			let mut service_mngr_send_lock = self.service_events_send.lock().unwrap();
+			service_mngr_send_lock.try_send(ClientEvents::Server { cmd: ServerCmd::GetConnectionUsage { respond_to: send }}).map_err(bus_status)?;
+		}
+		let usage = recv.await.map_err(|_| Status::unavailable("ClientHandler is not running"))?;
+
+		let usage_reply = adminctrl::ConnectionUsageReply {
+			connections: usage.connections,
+			max_connections: usage.max_connections,
+			max_connections_per_ip: usage.max_connections_per_ip,
+			connections_per_ip: usage.connections_per_ip.iter().map(|(ip, connections)| {
+				adminctrl::IpConnections {
+					ip: ip.to_string(),
+					connections: *connections,
+				}
+			}).collect(),
+			refused_connections: usage.refused_connections,
+		};
+
+		Ok(Response::new(usage_reply))
+	}
+
+	async fn queue_metrics(&self, request: Request<adminctrl::QueueMetricsRequest>) -> Result<Response<adminctrl::QueueMetricsReply>, Status> {
+		let queues = self.bus_metrics.snapshot().into_iter().map(|queue| {
+			adminctrl::Queue {
+				name: queue.name,
+				capacity: queue.capacity,
+				depth: queue.depth,
+				high_water: queue.high_water,
+				sent: queue.sent,
+				dropped: queue.dropped,
+				rejected: queue.rejected,
+			}
+		}).collect();
+
+		Ok(Response::new(adminctrl::QueueMetricsReply { queues }))
+	}
+
 	async fn list_subscriptions(&self, request: Request<adminctrl::ListSubscriptionRequest>) -> Result<Response<adminctrl::ListSubscriptionReply>, Status> {
 
 		let sub_query = adminctrl::ListSubscriptionReply {
@@ -199,7 +254,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 
 		{
 			let mut service_mngr_send_lock = self.service_events_send.lock().unwrap();
-			service_mngr_send_lock.send(ClientEvents::RelayNotice { client_id: 0, message: notice_message });
+			service_mngr_send_lock.try_send(ClientEvents::RelayNotice { client_id: 0, message: notice_message }).map_err(bus_status)?;
 		}
 
 		let received_note = adminctrl::ReceivedNote {
@@ -219,7 +274,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 			if let Ok(kind32500_event) = EventBuilder::new_order_note(encoded_offer, &[]).to_event(&service_keys)
 			{
 				let mut service_mngr_send_lock = self.service_events_send.lock().unwrap();
-				service_mngr_send_lock.send(ClientEvents::OrderNote { order: kind32500_event });
+				service_mngr_send_lock.try_send(ClientEvents::OrderNote { order: kind32500_event }).map_err(bus_status)?;
 			}
 		}
 
@@ -235,7 +290,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 		if let Ok(kind32500_event) = EventBuilder::new_order_note(invoice_message, &[]).to_event(&service_keys)
 		{
 				let mut service_mngr_send_lock = self.service_events_send.lock().unwrap();
-				service_mngr_send_lock.send(ClientEvents::OrderNote { order: kind32500_event });
+				service_mngr_send_lock.try_send(ClientEvents::OrderNote { order: kind32500_event }).map_err(bus_status)?;
 		}
 
 		Ok(Response::new(adminctrl::ReceivedInvoice {}))
@@ -247,7 +302,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 
 		{
 			let mut send_db_request_lock = self.send_db_request.lock().unwrap();
-			send_db_request_lock.send(DbRequest::DumpEvents);
+			send_db_request_lock.try_send(DbRequest::DumpEvents).map_err(bus_status)?;
 		}
 
 		Ok(Response::new(adminctrl::ListDbEventsReply {}))
@@ -259,7 +314,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 
 		{
 			let mut send_db_request_lock = self.send_db_request.lock().unwrap();
-			send_db_request_lock.send(DbRequest::DumpClients);
+			send_db_request_lock.try_send(DbRequest::DumpClients).map_err(bus_status)?;
 		}
 
 		Ok(Response::new(adminctrl::ListDbClientsReply {}))
@@ -271,7 +326,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 
 		{
 			let mut send_bitcoind_request_lock = self.send_bitcoind_request.lock().unwrap();
-			send_bitcoind_request_lock.send(BitcoindRequest::CheckRpcCall);
+			send_bitcoind_request_lock.try_send(BitcoindRequest::CheckRpcCall).map_err(bus_status)?;
 		}
 
 		Ok(Response::new(adminctrl::CheckChainStateReply {}))
@@ -286,7 +341,7 @@ impl AdminCtrl for std::sync::Arc<ServiceManager> {
 		let (send, recv) = oneshot::channel::<Option<String>>();
 		{
 			let mut send_bitcoind_request_lock = self.send_bitcoind_request.lock().unwrap();
-			send_bitcoind_request_lock.send(BitcoindRequest::GenerateTxInclusionProof { txid: txid, respond_to: send });
+			send_bitcoind_request_lock.try_send(BitcoindRequest::GenerateTxInclusionProof { txid: txid, respond_to: send }).map_err(bus_status)?;
 		}
 		if let Some(response) = recv.await.expect("BitcoindHandler has been killed") {
 			Ok(Response::new(adminctrl::GenerateTxInclusionProofReply { merkle_block: response } ))
@@ -341,7 +396,7 @@ impl CivkitService for std::sync::Arc<ServiceManager> {
 		let (send, recv) = oneshot::channel::<Option<String>>();
 		{
 			let mut send_bitcoind_request_lock = self.send_bitcoind_request.lock().unwrap();
-			send_bitcoind_request_lock.send(BitcoindRequest::VerifyInclusionProof { inclusion_proof: (*self.inclusion_proof).clone(), respond_to: send });
+			send_bitcoind_request_lock.try_send(BitcoindRequest::VerifyInclusionProof { inclusion_proof: (*self.inclusion_proof).clone(), respond_to: send }).map_err(bus_status)?;
 		}
 		if let Some(response) = recv.await.expect("BitcoindHandler has been killed") {
 			Ok(Response::new(civkitservice::VerifyInclusionProofReply { verified: response } ))
@@ -361,6 +416,26 @@ struct Cli {
 	#[clap(short, long, default_value = "50031")]
 	cli_port: String,
 }
+/// Max wait for the components to wind down before the process exits anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Waits for SIGINT, SIGTERM or a shutdown requested through the admin RPC.
+async fn wait_for_shutdown_request(mut shutdown: ShutdownSignal) {
+	#[cfg(unix)]
+	let terminate = async {
+		let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("Failed to install the SIGTERM handler");
+		sigterm.recv().await;
+	};
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+
+	tokio::select! {
+		_ = tokio::signal::ctrl_c() => { println!("[CIVKITD] - CONTROL: SIGINT received"); },
+		_ = terminate => { println!("[CIVKITD] - CONTROL: SIGTERM received"); },
+		_ = shutdown.recv() => {},
+	}
+}
+
 #[tokio::main]
 async fn main() {
     let data_dir = util::get_default_data_dir();
@@ -398,37 +473,54 @@ async fn main() {
 
 	println!("[CIVKITD] - INIT: noise port {} nostr port {} cli_port {}", cli.noise_port, cli.nostr_port, cli.cli_port);
 
+	// We open the database and migrate its schema before any component access it.
+	let db_path = data_dir.join(CIVKITD_DB_FILE);
+	adopt_legacy_db(Path::new(CIVKITD_DB_FILE), &db_path).expect("Failed to move the legacy database");
+	let sqlite_storage = SqliteStorage::open(&db_path).expect("Failed to open the database");
+	let storage = Storage::spawn(Box::new(sqlite_storage));
+
+
+	// The bounded queues between the components, with their overflow policies.
+	let bus_metrics = BusMetrics::new();
+	let channels = &config.channels;
 
 	// We initialize the communication channels between the service manager and ClientHandler.
-	let (service_mngr_events_send, handler_receive) = mpsc::unbounded_channel::<ClientEvents>();
+	let (service_mngr_events_send, handler_receive) = bus_metrics.channel::<ClientEvents>("service_events", channels.capacity("service_events"), policies::service_events);
 
 	// We initialize the communication channels between the service manager and NoiseGateway.
-	let (service_mngr_peer_send, gateway_receive) = mpsc::unbounded_channel::<PeerInfo>();
+	let (service_mngr_peer_send, gateway_receive) = bus_metrics.channel::<PeerInfo>("service_peers", channels.capacity("service_peers"), policies::always_reject);
 
 	// We initialize the communication channels between the nostr tcp listener and ClientHandler.
-	let (socket_connector, request_receive) = mpsc::unbounded_channel::<(TcpStream, SocketAddr)>();
+	let (socket_connector, request_receive) = bus_metrics.channel::<(TcpStream, SocketAddr)>("client_connections", channels.capacity("client_connections"), policies::always_reject);
+
+	// We initialize the communication channels between the client connections and ClientHandler.
+	let (send_client_msg, receive_client_msg) = bus_metrics.channel::<(u64, Vec<u8>)>("client_messages", channels.capacity("client_messages"), policies::always_block);
+	let (send_client_disconnect, receive_client_disconnect) = bus_metrics.channel::<u64>("client_disconnections", channels.capacity("client_disconnections"), policies::always_block);
 
 	// We initialize the communication channels between the NoteProcessor and ClientHandler.
-	let (handler_send_dbrequests, processor_receive_dbrequests) = mpsc::unbounded_channel::<(DbRequest)>();
+	let (handler_send_dbrequests, processor_receive_dbrequests) = bus_metrics.channel::<DbRequest>("client_db_requests", channels.capacity("client_db_requests"), policies::client_db_requests);
 
 	// We initialize the communication channels between the NoteProcessor and ServiceManager.
-	let (manager_send_dbrequests, receive_dbrequests_manager) = mpsc::unbounded_channel::<(DbRequest)>();
+	let (manager_send_dbrequests, receive_dbrequests_manager) = bus_metrics.channel::<DbRequest>("service_db_requests", channels.capacity("service_db_requests"), policies::always_reject);
 
-	let (send_db_result_handler, handler_receive_db_result) = mpsc::unbounded_channel::<ClientEvents>();
+	let (send_db_result_handler, handler_receive_db_result) = bus_metrics.channel::<ClientEvents>("db_results", channels.capacity("db_results"), policies::client_results);
 
-	let (send_credential_events_handler, receive_credential_event_gateway) = mpsc::unbounded_channel::<ClientEvents>();
+	let (send_credential_events_handler, receive_credential_event_gateway) = bus_metrics.channel::<ClientEvents>("credential_requests", channels.capacity("credential_requests"), policies::credential_requests);
 
-	let (send_credential_events_gateway, receive_credential_event_handler) = mpsc::unbounded_channel::<ClientEvents>();
+	let (send_credential_events_gateway, receive_credential_event_handler) = bus_metrics.channel::<ClientEvents>("credential_results", channels.capacity("credential_results"), policies::client_results);
 
-	let (manager_send_bitcoind_request, receive_bitcoind_request) = mpsc::unbounded_channel::<(BitcoindRequest)>();
+	let (manager_send_bitcoind_request, receive_bitcoind_request) = bus_metrics.channel::<BitcoindRequest>("service_bitcoind_requests", channels.capacity("service_bitcoind_requests"), policies::bitcoind_requests);
 
-	let (send_bitcoind_request_gateway, receive_bitcoind_request_handler) = mpsc::unbounded_channel::<(BitcoindRequest)>();
+	let (send_bitcoind_request_gateway, receive_bitcoind_request_handler) = bus_metrics.channel::<BitcoindRequest>("gateway_bitcoind_requests", channels.capacity("gateway_bitcoind_requests"), policies::bitcoind_requests);
 
-	let (send_bitcoind_result_gateway, receive_bitcoind_result_handler) = mpsc::unbounded_channel::<(BitcoindResult)>();
+	let (send_bitcoind_result_gateway, receive_bitcoind_result_handler) = bus_metrics.channel::<BitcoindResult>("bitcoind_results", channels.capacity("bitcoind_results"), policies::bitcoind_results);
 
-	let (send_events_gateway, receive_events_gateway) = mpsc::unbounded_channel::<ClientEvents>();
+	let (send_events_gateway, receive_events_gateway) = bus_metrics.channel::<ClientEvents>("service_registrations", channels.capacity("service_registrations"), policies::credential_requests);
 
-	let (send_validation_result_gateway, receive_validation_result_dbrequests_manager) = mpsc::unbounded_channel::<ClientEvents>();
+	// The redemptions are refused to the payer rather than queued, their outcome is awaited by the CredentialGateway.
+	let (send_validation_result_gateway, receive_validation_result_dbrequests_manager) = bus_metrics.channel::<ClientEvents>("validated_events", channels.capacity("validated_events"), policies::always_reject);
+
+	let (send_redemption_results, receive_redemption_results) = bus_metrics.channel::<ClientEvents>("redemption_results", channels.capacity("redemption_results"), policies::always_block);
 
 	// The onion message handler...quite empty for now.
 	let onion_box = OnionBox::new();
@@ -437,10 +529,10 @@ async fn main() {
 	let noise_gateway = NoiseGateway::new(gateway_receive);
 
 	// The staking credentials handler...quite empty for now.
-	let mut credential_gateway = CredentialGateway::new(receive_credential_event_gateway, send_credential_events_gateway, send_bitcoind_request_gateway, receive_bitcoind_result_handler, receive_events_gateway, send_validation_result_gateway);
+	let mut credential_gateway = CredentialGateway::new(receive_credential_event_gateway, send_credential_events_gateway, send_bitcoind_request_gateway, receive_bitcoind_result_handler, receive_events_gateway, send_validation_result_gateway, receive_redemption_results, config.clone());
 
 	// The note or service provider...quite empty for now.
-	let mut note_processor = NoteProcessor::new(processor_receive_dbrequests, receive_dbrequests_manager, send_db_result_handler, receive_validation_result_dbrequests_manager, config.clone());
+	let mut note_processor = NoteProcessor::new(processor_receive_dbrequests, receive_dbrequests_manager, send_db_result_handler, receive_validation_result_dbrequests_manager, send_redemption_results, storage.clone(), config.clone());
 
 	// The service provider signer...quite empty for now.
 	let node_signer = Arc::new(NodeSigner::new());
@@ -450,79 +542,94 @@ async fn main() {
 
 	// Main handler of Nostr connections.
 	// TODO: add receive_credential_events_handler
-	let mut client_handler = ClientHandler::new(handler_receive, request_receive, handler_send_dbrequests, handler_receive_db_result, send_credential_events_handler, receive_credential_event_handler, config.clone());
+	let mut client_handler = ClientHandler::new(handler_receive, request_receive, send_client_msg, receive_client_msg, send_client_disconnect, receive_client_disconnect, handler_send_dbrequests, handler_receive_db_result, send_credential_events_handler, receive_credential_event_handler, config.clone());
 
 	let mut bitcoind_handler = BitcoindHandler::new(config.clone(), receive_bitcoind_request, receive_bitcoind_request_handler, send_bitcoind_result_gateway);
 
 	// We initialize the inclusion proof with txid, commitment and merkle proof as empty strings.
 	let mut inclusion_proof = InclusionProof::new("".to_string(), "".to_string(), "".to_string(), Vec::new(), "".to_string(), Value::Null, config.clone());
 
+	// Every component listens to the shutdown coordinator to wind down its work.
+	let shutdown = ShutdownTrigger::new();
+
 	// Main handler of services provision.
-	let service_manager_arc = Arc::new(ServiceManager::new(node_signer, anchor_manager, service_mngr_events_send, service_mngr_peer_send, manager_send_dbrequests, manager_send_bitcoind_request, send_events_gateway, Arc::new(inclusion_proof.clone()), config.clone()));
+	let service_manager_arc = Arc::new(ServiceManager::new(node_signer, anchor_manager, service_mngr_events_send, service_mngr_peer_send, manager_send_dbrequests, manager_send_bitcoind_request, send_events_gateway, Arc::new(inclusion_proof.clone()), shutdown.clone(), bus_metrics.clone(), config.clone()));
 
 	let addr = format!("[::1]:{}", cli.cli_port).parse().expect("Failed to parse address, port might be invalid?");
 
 	let service_mngr_svc = Server::builder()
 		.add_service(AdminCtrlServer::new(service_manager_arc.clone()))
 		.add_service(CivkitServiceServer::new(service_manager_arc.clone()))
-		.serve(addr);
+		.serve_with_shutdown(addr, {
+			let mut shutdown = shutdown.subscribe();
+			async move { shutdown.recv().await }
+		});
 
 	let peer_manager = noise_gateway.peer_manager.clone();
-	let stop_listen_connect = Arc::new(AtomicBool::new(false));
-	let stop_listen = Arc::clone(&stop_listen_connect);
+
+	let mut components: Vec<JoinHandle<()>> = Vec::new();
 
 	// We start the gRPC server for `civkit-cli`.
-    	tokio::spawn(async move {
+	components.push(tokio::spawn(async move {
 		if let Err(e) = service_mngr_svc.await {
 			eprintln!("Error = {:?}", e);
 		}
-	});
+	}));
 
 	// We start the NIP-01 relay for clients.
-	tokio::spawn(async move {
-		client_handler.run().await;
-	});
+	let client_handler_shutdown = shutdown.subscribe();
+	components.push(tokio::spawn(async move {
+		client_handler.run(client_handler_shutdown).await;
+	}));
 
 	// We start the onion box for received onions.
-	tokio::spawn(async move {
-		onion_box.run().await;
-	});
+	let onion_box_shutdown = shutdown.subscribe();
+	components.push(tokio::spawn(async move {
+		onion_box.run(onion_box_shutdown).await;
+	}));
 
 	// We start the note processor for messages.
-	tokio::spawn(async move {
-		note_processor.run().await;
-	});
+	let note_processor_shutdown = shutdown.subscribe();
+	components.push(tokio::spawn(async move {
+		note_processor.run(note_processor_shutdown).await;
+	}));
 
 	// We start the noise gateway for BOLT8 peers.
-	tokio::spawn(async move {
-		noise_gateway.run().await;
-	});
+	let noise_gateway_shutdown = shutdown.subscribe();
+	components.push(tokio::spawn(async move {
+		noise_gateway.run(noise_gateway_shutdown).await;
+	}));
 
 	// We start the credentials gateway
 	// TODO: give a channel with ClientHandler
-	tokio::spawn(async move {
-		credential_gateway.run().await;
-	});
-
-	tokio::spawn(async move {
-		inclusion_proof.run().await;
-	});
-
-	tokio::spawn(async move {
-		bitcoind_handler.run().await;
-	});
+	let credential_gateway_shutdown = shutdown.subscribe();
+	components.push(tokio::spawn(async move {
+		credential_gateway.run(credential_gateway_shutdown).await;
+	}));
+
+	let inclusion_proof_storage = storage.clone();
+	let inclusion_proof_shutdown = shutdown.subscribe();
+	components.push(tokio::spawn(async move {
+		inclusion_proof.run(inclusion_proof_storage, inclusion_proof_shutdown).await;
+	}));
+
+	let bitcoind_handler_shutdown = shutdown.subscribe();
+	components.push(tokio::spawn(async move {
+		bitcoind_handler.run(bitcoind_handler_shutdown).await;
+	}));
 
 	// We start the tcp listener for BOLT8 peers.
-	tokio::spawn(async move {
+	let mut noise_listener_shutdown = shutdown.subscribe();
+	components.push(tokio::spawn(async move {
 		let listener = tokio::net::TcpListener::bind(format!("[::1]:{}", cli.noise_port)).await.expect("Failed to bind to listen port");
 
 		loop {
 			let inbound_peer_mgr = peer_manager.clone();
-			let tcp_stream = listener.accept().await.unwrap().0;
+			let tcp_stream = tokio::select! {
+				_ = noise_listener_shutdown.recv() => { return; },
+				accepted = listener.accept() => { accepted.unwrap().0 },
+			};
 			println!("[CIVKITD] - NET: inbound noise connection !");
-			if stop_listen.load(Ordering::Acquire) {
-				return;
-			}
 			tokio::spawn(async move {
 				lightning_net_tokio::setup_inbound(
 					inbound_peer_mgr,
@@ -531,22 +638,73 @@ async fn main() {
 				.await;
 			});
 		}
-	});
+	}));
+
+	// The NIP-11 relay information document served on the Nostr port.
+	let relay_info = Arc::new(relay_information_document(&config));
+	// The connections are counted by the ClientHandler once handed over, the
+	// ones still waiting for their request head are bounded here.
+	let pending_connections = Arc::new(Semaphore::new(max_pending_connections(&config.connections)));
 
 	// We start the tcp listener for NIP-01 clients.
-	let handle = tokio::spawn(async move {
+	let mut nostr_listener_shutdown = shutdown.subscribe();
+	components.push(tokio::spawn(async move {
 		let try_socket = TcpListener::bind(format!("[::1]:{}", cli.nostr_port)).await;
 		let listener = try_socket.expect("Failed to bind");
 
 		println!("[CIVKITD] - NET: ready to listen tcp connection for clients !");
-		while let Ok((stream, addr)) = listener.accept().await {
+		loop {
+			let (mut stream, addr) = tokio::select! {
+				_ = nostr_listener_shutdown.recv() => { break; },
+				accepted = listener.accept() => {
+					if let Ok(accepted) = accepted { accepted } else { break; }
+				},
+			};
 			println!("[CIVKITD] - NET: receive a tcp connection !");
-			socket_connector.send((stream, addr));
+			let pending_connection = match pending_connections.clone().try_acquire_owned() {
+				Ok(pending_connection) => pending_connection,
+				Err(_) => {
+					println!("[CIVKITD] - NET: dropping connection from {}, too many pending connections", addr);
+					continue;
+				},
+			};
+			let socket_connector = socket_connector.clone();
+			let relay_info = relay_info.clone();
+			let mut connection_shutdown = nostr_listener_shutdown.clone();
+			tokio::spawn(async move {
+				// Plain HTTP requests for the relay information document are answered here.
+				let served = tokio::select! {
+					_ = connection_shutdown.recv() => { return; },
+					served = serve_relay_info(&mut stream, &relay_info) => served,
+				};
+				drop(pending_connection);
+				if served {
+					println!("[CIVKITD] - NET: served relay information document to {}", addr);
+					return;
+				}
+				// The stream is closed if the ClientHandler is overloaded.
+				if socket_connector.try_send((stream, addr)).is_err() {
+					println!("[CIVKITD] - NET: dropping connection from {}, client queue full", addr);
+				}
+			});
 		}
-	});
+		println!("[CIVKITD] - NET: stopped listening for clients");
+	}));
+
+	wait_for_shutdown_request(shutdown.subscribe()).await;
 
+	println!("[CIVKITD] - CONTROL: CivKit node shuting down...");
+	shutdown.trigger();
 
-	handle.await; //TEMPORARY SOLUTION should use ALL handles or block indefinetely TODO
+	// The listeners stop accepting, the clients are sent their CLOSED and NOTICE,
+	// the pending writes and commitments are flushed and the noise peers disconnected.
+	if timeout(SHUTDOWN_TIMEOUT, futures_util::future::join_all(components)).await.is_err() {
+		println!("[CIVKITD] - CONTROL: Some components did not shut down in time");
+	}
+	if let Err(err) = storage.flush().await {
+		println!("[CIVKITD] - CONTROL: storage flush failed: {}", err);
+	}
 
+	println!("[CIVKITD] - CONTROL: CivKit node shut down");
 }
 
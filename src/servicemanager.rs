@@ -27,12 +27,13 @@ use civkit::peerhandler::PeerInfo;
 use civkit::bitcoind_client::BitcoindRequest;
 use civkit::config::Config;
 use civkit::inclusionproof::InclusionProof;
+use civkit::shutdown::ShutdownTrigger;
 
 // use lock from futures::lock
 use std::sync::Mutex;
 use std::sync::Arc;
 
-use tokio::sync::mpsc;
+use civkit::bus::{BusMetrics, BusSender};
 
 pub struct ServiceManager
 {
@@ -43,24 +44,29 @@ pub struct ServiceManager
 	node_signer: Arc<NodeSigner>,
 	anchor_manager: Arc<AnchorManager>,
 
-	pub service_events_send: Mutex<mpsc::UnboundedSender<ClientEvents>>,
-	pub service_peers_send: Mutex<mpsc::UnboundedSender<PeerInfo>>,
+	pub service_events_send: Mutex<BusSender<ClientEvents>>,
+	pub service_peers_send: Mutex<BusSender<PeerInfo>>,
 
-	pub send_db_request: Mutex<mpsc::UnboundedSender<DbRequest>>,
+	pub send_db_request: Mutex<BusSender<DbRequest>>,
 
-	pub send_bitcoind_request: Mutex<mpsc::UnboundedSender<BitcoindRequest>>,
+	pub send_bitcoind_request: Mutex<BusSender<BitcoindRequest>>,
 
-	pub send_events_gateway: Mutex<mpsc::UnboundedSender<ClientEvents>>,
+	pub send_events_gateway: Mutex<BusSender<ClientEvents>>,
 
 	our_service_pubkey: PublicKey,
 	pub inclusion_proof: Arc<InclusionProof>,
+
+	pub shutdown: ShutdownTrigger,
+
+	pub bus_metrics: BusMetrics,
+
 	config: Config,
 	secp_ctx: Secp256k1<secp256k1::All>,
 }
 
 impl ServiceManager
 {
-	pub fn new(node_signer: Arc<NodeSigner>, anchor_manager: Arc<AnchorManager>, board_events_send: mpsc::UnboundedSender<ClientEvents>, board_peers_send: mpsc::UnboundedSender<PeerInfo>, send_db_request: mpsc::UnboundedSender<DbRequest>, send_bitcoind_request: mpsc::UnboundedSender<BitcoindRequest>, send_gateway_events: mpsc::UnboundedSender<ClientEvents>, inclusion_proof: Arc<InclusionProof>, our_config: Config) -> Self {
+	pub fn new(node_signer: Arc<NodeSigner>, anchor_manager: Arc<AnchorManager>, board_events_send: BusSender<ClientEvents>, board_peers_send: BusSender<PeerInfo>, send_db_request: BusSender<DbRequest>, send_bitcoind_request: BusSender<BitcoindRequest>, send_gateway_events: BusSender<ClientEvents>, inclusion_proof: Arc<InclusionProof>, shutdown: ShutdownTrigger, bus_metrics: BusMetrics, our_config: Config) -> Self {
 		let secp_ctx = Secp256k1::new();
 		let pubkey = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42;32]).unwrap());
 		ServiceManager {
@@ -74,6 +80,8 @@ impl ServiceManager
 			send_events_gateway: Mutex::new(send_gateway_events),
 			our_service_pubkey: pubkey,
 			inclusion_proof: inclusion_proof,
+			shutdown,
+			bus_metrics,
 			config: our_config,
 			secp_ctx,
 		}
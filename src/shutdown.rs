@@ -0,0 +1,71 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! The coordination of civkitd shutdown across its components.
+//!
+//! A `ShutdownTrigger` is held by whoever may stop the node (the signal
+//! handlers, the admin RPC) and every component loop listens on its own
+//! `ShutdownSignal` to wind down its work before returning.
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Starts the shutdown of every component listening on a `ShutdownSignal`.
+#[derive(Clone, Debug)]
+pub struct ShutdownTrigger {
+	send: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownTrigger {
+	pub fn new() -> Self {
+		let (send, _) = watch::channel(false);
+		ShutdownTrigger {
+			send: Arc::new(send),
+		}
+	}
+
+	/// Requests the shutdown. Triggering more than once has no further effect.
+	pub fn trigger(&self) {
+		self.send.send_replace(true);
+	}
+
+	pub fn is_triggered(&self) -> bool {
+		*self.send.borrow()
+	}
+
+	pub fn subscribe(&self) -> ShutdownSignal {
+		ShutdownSignal {
+			receive: self.send.subscribe(),
+		}
+	}
+}
+
+/// The component side of the shutdown coordinator.
+#[derive(Clone, Debug)]
+pub struct ShutdownSignal {
+	receive: watch::Receiver<bool>,
+}
+
+impl ShutdownSignal {
+	pub fn is_triggered(&self) -> bool {
+		*self.receive.borrow()
+	}
+
+	/// Completes once the shutdown has been triggered. It is cancel safe and
+	/// can be used as a `tokio::select!` branch.
+	pub async fn recv(&mut self) {
+		while !*self.receive.borrow_and_update() {
+			// All the triggers are gone, nobody can stop us anymore.
+			if self.receive.changed().await.is_err() {
+				std::future::pending::<()>().await;
+			}
+		}
+	}
+}
@@ -0,0 +1,24 @@
+use crate::shutdown::ShutdownTrigger;
+
+use tokio::time::{timeout, Duration};
+
+#[tokio::test]
+async fn test_shutdown_reaches_every_signal() {
+	let trigger = ShutdownTrigger::new();
+	let mut first = trigger.subscribe();
+	let mut second = trigger.subscribe();
+
+	assert!(!first.is_triggered());
+	assert!(timeout(Duration::from_millis(50), first.recv()).await.is_err());
+
+	trigger.clone().trigger();
+	assert!(trigger.is_triggered());
+	assert!(timeout(Duration::from_millis(50), first.recv()).await.is_ok());
+	assert!(timeout(Duration::from_millis(50), second.recv()).await.is_ok());
+
+	// A signal subscribed after the trigger, or polled again, completes at once.
+	let mut late = trigger.subscribe();
+	assert!(late.is_triggered());
+	assert!(timeout(Duration::from_millis(50), late.recv()).await.is_ok());
+	assert!(timeout(Duration::from_millis(50), first.recv()).await.is_ok());
+}
@@ -0,0 +1,32 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! The event and filter factories shared by the tests.
+
+use nostr::{Event, EventBuilder, EventId, Filter, Keys, Kind, Tag, Timestamp};
+
+/// Builds an event signed by `keys`, created now.
+pub fn build_event(keys: &Keys, kind: u64, content: &str, tags: &[Tag]) -> Event {
+	EventBuilder::new(Kind::from(kind), content, tags).to_event(keys).unwrap()
+}
+
+/// Builds an event signed by `keys`, created at `created_at`. The tags are
+/// given as lists of strings.
+pub fn build_event_at(keys: &Keys, kind: u64, content: &str, tags: Vec<Vec<String>>, created_at: u64) -> Event {
+	let tags: Vec<Tag> = tags.into_iter().map(|tag| Tag::parse(tag).unwrap()).collect();
+	let mut unsigned = EventBuilder::new(Kind::from(kind), content, &tags).to_unsigned_event(keys.public_key());
+	// The id commits to the creation time, it is computed again before signing.
+	unsigned.created_at = Timestamp::from(created_at);
+	unsigned.id = EventId::new(&unsigned.pubkey, unsigned.created_at, &unsigned.kind, &unsigned.tags, &unsigned.content);
+	unsigned.sign(keys).unwrap()
+}
+
+pub fn build_filter(value: serde_json::Value) -> Filter {
+	serde_json::from_value(value).unwrap()
+}
@@ -67,12 +67,32 @@ pub fn is_ephemeral(ev: &Event) -> bool {
 	return false;
 }
 
-// Function to assert if an event is a NIP-16 repleceable event
+// Function to assert if an event is a NIP-16 replaceable event, only the last
+// one by (pubkey, kind) is kept
 pub fn is_replaceable(ev: &Event) -> bool {
-	if 10000 <= ev.kind.as_u32() && ev.kind.as_u32() < 20000 {
-		return true;
-	}
-	return false;
+	let kind = ev.kind.as_u32();
+	kind == 0 || kind == 3 || (10000 <= kind && kind < 20000)
+}
+
+// Function to assert if an event is a NIP-33 parameterized replaceable event,
+// only the last one by (pubkey, kind, d tag) is kept
+pub fn is_parameterized_replaceable(ev: &Event) -> bool {
+	30000 <= ev.kind.as_u32() && ev.kind.as_u32() < 40000
+}
+
+// Function to get the value of the first d tag, empty if none
+pub fn d_tag(ev: &Event) -> String {
+	ev.tags.iter().map(|tag| tag.as_vec()).find(|tag| tag.len() >= 2 && tag[0] == "d").map(|tag| tag[1].clone()).unwrap_or_default()
+}
+
+// Function to assert if an event is a NIP-09 deletion
+pub fn is_deletion(ev: &Event) -> bool {
+	ev.kind.as_u32() == 5
+}
+
+// Function to assert if an event is a market order
+pub fn is_order(ev: &Event) -> bool {
+	ev.kind.as_u32() == 32500
 }
 
 // Function to assert if an event is a credential msg